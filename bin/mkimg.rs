@@ -1,9 +1,23 @@
-use clap::Parser;
+use clap::{Args, Parser, ValueEnum};
 use mkimg::{
     error::{MkimgError, MkimgRes},
-    FileMapping,
+    fat::Timestamp,
+    manifest::Manifest,
+    mutation::Registry,
+    options::{
+        parse_number, CreateOptions, DeceptionOptions, EditOptions, ExamineOptions, GptOptions,
+        Layout, Lie, MbrOptions, ScanOptions, SymlinkPolicy, VolumeSize, DEFAULT_ALIGNMENT,
+        DEFAULT_CONTENT_LIMIT,
+    },
+    size::parse_size,
+    EntryMeta, FatType, FileMapping, Source,
+};
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
 };
-use std::{fs::File, path::PathBuf};
+use uuid::Uuid;
 
 #[derive(Parser)]
 struct Cli {
@@ -11,42 +25,389 @@ struct Cli {
     command: Commands,
 }
 
+#[derive(Clone, Copy, ValueEnum)]
+enum FatTypeArg {
+    /// Pick the FAT type from the img size.
+    Auto,
+    #[value(name = "12")]
+    Fat12,
+    #[value(name = "16")]
+    Fat16,
+    #[value(name = "32")]
+    Fat32,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum LayoutArg {
+    /// Bare FAT volume without a partition table.
+    Superfloppy,
+    /// GPT disk with the FAT volume as EFI System Partition.
+    Gpt,
+    /// MBR disk with the FAT volume as first primary partition.
+    Mbr,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum SymlinksArg {
+    /// Map the file or directory a link points to.
+    Follow,
+    /// Leave links out of the img.
+    Skip,
+    /// Fail on the first link found.
+    Error,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum FormatArg {
+    /// Listing with the contents of small files.
+    Text,
+    /// Machine-readable JSON document.
+    Json,
+    /// Directory tree.
+    Tree,
+}
+
+/// Host files to put in an img.
+#[derive(Args)]
+struct TreeArgs {
+    /// If set, only the root dir contents will be included.
+    ///
+    /// If not set, the root of the img will only be the provided
+    /// root dir.
+    #[arg(short, long)]
+    exclude_root: bool,
+    /// The root path of the created img.
+    #[arg(long)]
+    root: Option<PathBuf>,
+    /// A mapping from <EXT PATH> <INT PATH>.
+    #[arg(long, conflicts_with = "root", num_args = 2)]
+    map: Vec<PathBuf>,
+    /// Only map entries under the root dir matching this glob
+    /// (e.g., ""EFI/**"", ""*.efi""). May be repeated.
+    #[arg(long, value_name = "PATTERN", requires = "root")]
+    include: Vec<String>,
+    /// Leave out entries under the root dir matching this glob
+    /// (e.g., ""*.swp"", "".git""). May be repeated.
+    #[arg(long, value_name = "PATTERN", requires = "root")]
+    exclude: Vec<String>,
+    /// Do not read exclude patterns from .mkimgignore in the root
+    /// dir.
+    #[arg(long, requires = "root")]
+    no_ignore_file: bool,
+    /// Skip entries more than this many levels below the root dir.
+    #[arg(long, requires = "root")]
+    max_depth: Option<usize>,
+    /// How to treat symbolic links under the root dir.
+    #[arg(long, value_enum, default_value_t = SymlinksArg::Follow)]
+    symlinks: SymlinksArg,
+}
+
+impl TreeArgs {
+    /// Scans the root dir, or collects the explicit mappings.
+    fn mappings(self) -> MkimgRes<Vec<FileMapping>> {
+        let Some(root) = self.root else {
+            let mut mappings = Vec::new();
+            for pair in self.map.chunks(2) {
+                if pair.len() != 2 {
+                    return Err(MkimgError::validation(
+                        "File mappings must be src dst src dst ..",
+                    ));
+                }
+                mappings.push(FileMapping::new(&pair[0], &pair[1]))
+            }
+            return Ok(mappings);
+        };
+        let scan_options = ScanOptions {
+            include: self.include,
+            exclude: self.exclude,
+            ignore_file: !self.no_ignore_file,
+            max_depth: self.max_depth,
+            symlinks: match self.symlinks {
+                SymlinksArg::Follow => SymlinkPolicy::Follow,
+                SymlinksArg::Skip => SymlinkPolicy::Skip,
+                SymlinksArg::Error => SymlinkPolicy::Error,
+            },
+        };
+        mkimg::create_mappings(&root, self.exclude_root, &scan_options)
+    }
+}
+
+// Parsed once per run, so the size of `Create` does not matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Parser)]
 enum Commands {
     /// Create a disk img (deceptive by default).
     Create {
         /// Output path name for the created img.
         img_path: Option<PathBuf>,
+        /// Build the img described by a TOML manifest instead of
+        /// command line options.
+        #[arg(
+            long,
+            conflicts_with_all = [
+                "root", "map", "exclude_root", "slack", "size", "sectors", "fat_type",
+                "cluster_size", "reserved_sectors", "fats", "root_entries", "layout",
+                "disk_guid", "partition_guid", "align", "partition_type", "active",
+                "disk_signature", "timestamp", "volume_id", "preserve_times",
+                "preserve_attributes", "symlinks",
+            ]
+        )]
+        manifest: Option<PathBuf>,
         /// Create a plain (non-deceptive) img instead of deceptive.
+        /// Manifests build plain imgs unless they set `deceptive = true`.
         #[arg(long)]
         plain: bool,
-        /// If set, only the root dir contents will be included.
-        ///
-        /// If not set, the root of the img will only be the provided
-        /// root dir.
-        #[arg(short, long)]
-        exclude_root: bool,
-        /// The root path of the created img.
+        #[command(flatten)]
+        tree: TreeArgs,
+        /// Free space to leave in the img beyond what its contents
+        /// need (e.g., 512K, 4M).
+        #[arg(long, value_parser = parse_size, default_value_t = mkimg::size::DEFAULT_SLACK)]
+        slack: u64,
+        /// Total img size (e.g., 1440K, 64M). Sized to fit the
+        /// contents if not set.
+        #[arg(long, value_parser = parse_size, conflicts_with = "sectors")]
+        size: Option<u64>,
+        /// Total img size in 512 byte sectors.
+        #[arg(long)]
+        sectors: Option<u32>,
+        /// FAT type. Defaults to FAT16 for plain imgs and FAT32 for
+        /// deceptive imgs.
+        #[arg(long)]
+        fat_type: Option<FatTypeArg>,
+        /// Bytes per cluster (e.g., 512, 4K).
+        #[arg(long, value_parser = parse_size)]
+        cluster_size: Option<u64>,
+        /// Number of reserved sectors before the first FAT.
+        #[arg(long)]
+        reserved_sectors: Option<u16>,
+        /// Number of FAT copies (1 or 2).
+        #[arg(long)]
+        fats: Option<u8>,
+        /// Number of root directory entries (FAT12/FAT16 only).
+        #[arg(long)]
+        root_entries: Option<u16>,
+        /// Partition table to wrap the FAT volume in.
+        #[arg(long, value_enum, default_value_t = LayoutArg::Superfloppy)]
+        layout: LayoutArg,
+        /// GPT disk GUID. Random if not set.
+        #[arg(long)]
+        disk_guid: Option<Uuid>,
+        /// GPT partition GUID. Random if not set.
+        #[arg(long)]
+        partition_guid: Option<Uuid>,
+        /// Partition start alignment (e.g., 4K, 1M).
+        #[arg(long, value_parser = parse_size, default_value_t = DEFAULT_ALIGNMENT)]
+        align: u64,
+        /// MBR partition type byte (e.g., 0xEF). Derived from the FAT
+        /// type if not set.
+        #[arg(long, value_parser = parse_number::<u8>)]
+        partition_type: Option<u8>,
+        /// Mark the MBR partition active.
+        #[arg(long)]
+        active: bool,
+        /// MBR disk signature (e.g., 0x1234ABCD). Random if not set.
+        #[arg(long, value_parser = parse_number::<u32>)]
+        disk_signature: Option<u32>,
+        /// Timestamp for every file and directory, as Unix seconds or
+        /// YYYY-MM-DDTHH:MM:SS (UTC). Makes the build reproducible.
+        /// Defaults to SOURCE_DATE_EPOCH if set.
+        #[arg(long, value_parser = parse_timestamp)]
+        timestamp: Option<Timestamp>,
+        /// Volume serial number (e.g., 0x1234ABCD). Derived from the
+        /// timestamp if not set.
+        #[arg(long, value_parser = parse_number::<u32>)]
+        volume_id: Option<u32>,
+        /// Copy host modification and access times into the img.
         #[arg(long)]
-        root: Option<PathBuf>,
-        /// A mapping from <EXT PATH> <INT PATH>.
-        #[arg(long, conflicts_with = "root", num_args = 2)]
-        map: Vec<PathBuf>,
+        preserve_times: bool,
+        /// Mark non-writable files read-only and dotfiles hidden.
+        #[arg(long)]
+        preserve_attributes: bool,
+        /// Create an empty directory in the img (e.g., /EFI/Vendor).
+        /// May be repeated.
+        #[arg(long, value_name = "INT PATH")]
+        mkdir: Vec<PathBuf>,
+        /// Add a file with inline text as <INT PATH>=<TEXT> (e.g.,
+        /// ""startup.nsh=fs0:\EFI\BOOT\BOOTX64.EFI""). An empty TEXT
+        /// gives a zero-length file.
+        #[arg(long, value_name = "INT PATH=TEXT", value_parser = parse_content)]
+        content: Vec<(PathBuf, Source)>,
+        /// Add a file of SIZE bytes repeating a hex PATTERN as
+        /// <INT PATH>=<SIZE>[:<PATTERN>] (e.g., ""filler.bin=100M:ff"").
+        /// Zero-filled without a PATTERN.
+        #[arg(long, value_name = "INT PATH=SIZE[:PATTERN]", value_parser = parse_fill)]
+        fill: Vec<(PathBuf, Source)>,
+        /// Add a file of SIZE pseudo-random bytes as
+        /// <INT PATH>=<SIZE>[:<SEED>]. The same SEED (0 by default)
+        /// always gives the same bytes.
+        #[arg(long, value_name = "INT PATH=SIZE[:SEED]", value_parser = parse_random)]
+        random: Vec<(PathBuf, Source)>,
+        /// Set FAT attributes on a mapped entry as <INT PATH>=<FLAGS>,
+        /// where FLAGS are r(ead-only), h(idden), s(ystem) and
+        /// a(rchive); flags after a '-' are cleared (e.g.,
+        /// ""EFI/BOOT/BOOTX64.EFI=hs-a"").
+        #[arg(long, value_parser = parse_attr)]
+        attr: Vec<(String, EntryMeta)>,
+        /// Total sectors the boot sector claims: a factor of the real
+        /// count (e.g., 1.5x), an absolute count, or "keep". Defaults to
+        /// 1.5x for deceptive imgs. Any --fake-* option makes a plain
+        /// img lie as well.
+        #[arg(long, value_name = "LIE")]
+        fake_sectors: Option<Lie>,
+        /// Free clusters the FAT32 FSInfo sector claims, in the same
+        /// form. Defaults to 3x for deceptive imgs.
+        #[arg(long, value_name = "LIE")]
+        fake_free_clusters: Option<Lie>,
+        /// Next free cluster hint in the FAT32 FSInfo sector, in the
+        /// same form. Defaults to keep.
+        #[arg(long, value_name = "LIE")]
+        fake_next_free: Option<Lie>,
+        /// Mutation to apply to the finished img, as name[:key=value,...]
+        /// (e.g., deceive:sectors=4x). Repeatable, applied in order.
+        /// See `mkimg mutate --list`.
+        #[arg(long, value_name = "SPEC")]
+        mutate: Vec<String>,
     },
     /// Examine an existing disk img
     Examine {
         /// Path to the disk img to examine
         img_path: PathBuf,
+        /// Deepest directory level to list, with the root at level 0.
+        /// Unlimited if not set.
+        #[arg(long)]
+        depth: Option<usize>,
+        /// Show the contents of files up to this size (e.g., 4K).
+        #[arg(long, value_parser = parse_size, default_value_t = DEFAULT_CONTENT_LIMIT)]
+        content_limit: u64,
+        /// Output format.
+        #[arg(long, value_enum, default_value_t = FormatArg::Text)]
+        format: FormatArg,
     },
-    /// Extract a file from a disk img.
+    /// Extract files from a disk img.
     Extract {
         /// Path to the disk img.
         img_path: PathBuf,
-        /// Path to the file within the img (e.g.,
-        /// ""EFI/boot/bootx64.efi"").
-        file_path: PathBuf,
-        /// Output path for the extracted file/
-        output_path: PathBuf,
+        /// Path to a file or directory within the img, or a glob
+        /// pattern (e.g., ""EFI/boot/bootx64.efi"", ""EFI/**/*.efi"").
+        #[arg(required_unless_present = "all")]
+        file_path: Option<String>,
+        /// Output path for a single extracted file ("-" for stdout),
+        /// otherwise the directory to recreate the matching tree in.
+        #[arg(required_unless_present = "all")]
+        output_path: Option<PathBuf>,
+        /// Extract the whole img into DIR.
+        #[arg(long, value_name = "DIR", conflicts_with_all = ["file_path", "output_path"])]
+        all: Option<PathBuf>,
+    },
+    /// Add or replace a file or directory in an existing img.
+    Add {
+        /// Path to the disk img.
+        img_path: PathBuf,
+        /// File or directory to copy into the img. Directories are
+        /// copied with all of their contents.
+        ext_path: PathBuf,
+        /// Destination within the img (e.g., EFI/BOOT/BOOTX64.EFI).
+        /// Missing parent directories are created.
+        int_path: PathBuf,
+        /// Timestamp for the added entries, as Unix seconds or
+        /// YYYY-MM-DDTHH:MM:SS (UTC). Defaults to SOURCE_DATE_EPOCH if
+        /// set, the current time otherwise.
+        #[arg(long, value_parser = parse_timestamp)]
+        timestamp: Option<Timestamp>,
+        /// Copy host modification and access times into the img.
+        #[arg(long)]
+        preserve_times: bool,
+        /// Mark non-writable files read-only and dotfiles hidden.
+        #[arg(long)]
+        preserve_attributes: bool,
+    },
+    /// Update an existing img to match a host dir, rewriting only
+    /// what changed.
+    Sync {
+        /// Path to the disk img.
+        img_path: PathBuf,
+        #[command(flatten)]
+        tree: TreeArgs,
+        /// Timestamp for every mapped file and directory, as Unix
+        /// seconds or YYYY-MM-DDTHH:MM:SS (UTC). Defaults to
+        /// SOURCE_DATE_EPOCH if set.
+        #[arg(long, value_parser = parse_timestamp)]
+        timestamp: Option<Timestamp>,
+        /// Copy host modification and access times into the img.
+        #[arg(long)]
+        preserve_times: bool,
+        /// Mark non-writable files read-only and dotfiles hidden.
+        #[arg(long)]
+        preserve_attributes: bool,
+    },
+    /// Check that an img holds exactly the given host files.
+    Verify {
+        /// Path to the disk img.
+        img_path: PathBuf,
+        /// Check against the files a TOML manifest maps.
+        #[arg(long, conflicts_with_all = ["root", "map", "exclude_root", "symlinks"])]
+        manifest: Option<PathBuf>,
+        #[command(flatten)]
+        tree: TreeArgs,
+        /// Print the differences as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Delete files or directories from an existing img.
+    Rm {
+        /// Path to the disk img.
+        img_path: PathBuf,
+        /// Entries within the img to delete.
+        #[arg(required = true)]
+        int_paths: Vec<PathBuf>,
+        /// Delete directories along with their contents.
+        #[arg(short, long)]
+        recursive: bool,
+        /// Timestamp for the directories the entries are deleted
+        /// from. Defaults to SOURCE_DATE_EPOCH if set, the current
+        /// time otherwise.
+        #[arg(long, value_parser = parse_timestamp)]
+        timestamp: Option<Timestamp>,
+    },
+    /// Create directories in an existing img.
+    Mkdir {
+        /// Path to the disk img.
+        img_path: PathBuf,
+        /// Directories within the img to create, along with missing
+        /// parents.
+        #[arg(required = true)]
+        int_paths: Vec<PathBuf>,
+        /// Timestamp for the created directories. Defaults to
+        /// SOURCE_DATE_EPOCH if set, the current time otherwise.
+        #[arg(long, value_parser = parse_timestamp)]
+        timestamp: Option<Timestamp>,
+    },
+    /// Apply mutations to an existing img.
+    Mutate {
+        /// Path to the disk img.
+        #[arg(required_unless_present = "list")]
+        img_path: Option<PathBuf>,
+        /// Mutations to apply, in order, as name[:key=value,...].
+        #[arg(required_unless_present = "list")]
+        specs: Vec<String>,
+        /// List the available mutations.
+        #[arg(long, exclusive = true)]
+        list: bool,
+    },
+    /// Rename or move an entry within an existing img.
+    Mv {
+        /// Path to the disk img.
+        img_path: PathBuf,
+        /// Entry to move.
+        from: PathBuf,
+        /// New path. If it is an existing directory the entry is moved
+        /// into it.
+        to: PathBuf,
+        /// Timestamp for the directories the entry is moved between.
+        /// Defaults to SOURCE_DATE_EPOCH if set, the current time
+        /// otherwise.
+        #[arg(long, value_parser = parse_timestamp)]
+        timestamp: Option<Timestamp>,
     },
 }
 
@@ -54,29 +415,150 @@ fn main() -> MkimgRes {
     let cli = Cli::parse();
     match cli.command {
         Commands::Create {
-            root,
             img_path,
+            manifest,
             plain,
-            exclude_root,
-            map,
+            tree,
+            slack,
+            size,
+            sectors,
+            fat_type,
+            cluster_size,
+            reserved_sectors,
+            fats,
+            root_entries,
+            layout,
+            disk_guid,
+            partition_guid,
+            align,
+            partition_type,
+            active,
+            disk_signature,
+            timestamp,
+            volume_id,
+            preserve_times,
+            preserve_attributes,
+            mkdir,
+            content,
+            fill,
+            random,
+            attr,
+            fake_sectors,
+            fake_free_clusters,
+            fake_next_free,
+            mutate,
         } => {
-            let file_mappings = if let Some(root) = root {
-                mkimg::create_mappings(&root, exclude_root)?
-            } else {
-                let mut mappings = Vec::new();
-                for pair in map.chunks(2) {
-                    if pair.len() != 2 {
-                        return Err(MkimgError::validation(
-                            "File mappings must be src dst src dst ..",
-                        ));
+            // Checked before anything is written
+            let registry = Registry::new();
+            let mutations = mutate
+                .iter()
+                .map(|spec| registry.parse(spec))
+                .collect::<MkimgRes<Vec<_>>>()?;
+            let (mut file_mappings, mut options, img_path, plain) = match manifest {
+                Some(manifest) => {
+                    let Manifest {
+                        output,
+                        deceptive,
+                        mappings,
+                        mut options,
+                    } = Manifest::load(&manifest)?;
+                    if options.timestamp.is_none() {
+                        options.timestamp = Timestamp::source_date_epoch()?;
                     }
-                    mappings.push(FileMapping {
-                        ext: pair[0].clone(),
-                        int: pair[1].clone(),
-                    })
+                    (mappings, options, img_path.or(output), plain || !deceptive)
+                }
+                None => {
+                    let file_mappings = tree.mappings()?;
+                    let fat_type = match fat_type {
+                        Some(FatTypeArg::Auto) => None,
+                        Some(FatTypeArg::Fat12) => Some(FatType::Fat12),
+                        Some(FatTypeArg::Fat16) => Some(FatType::Fat16),
+                        Some(FatTypeArg::Fat32) => Some(FatType::Fat32),
+                        None if plain => Some(FatType::Fat16),
+                        None => Some(FatType::Fat32),
+                    };
+                    let bytes_per_cluster = cluster_size
+                        .map(|size| {
+                            u32::try_from(size)
+                                .map_err(|_| MkimgError::validation("cluster size is too large"))
+                        })
+                        .transpose()?;
+                    let options = CreateOptions {
+                        size: size
+                            .map(VolumeSize::Bytes)
+                            .or(sectors.map(VolumeSize::Sectors)),
+                        slack,
+                        fat_type,
+                        bytes_per_cluster,
+                        reserved_sectors,
+                        fats,
+                        root_entries,
+                        layout: match layout {
+                            LayoutArg::Superfloppy => Layout::Superfloppy,
+                            LayoutArg::Gpt => Layout::Gpt(GptOptions {
+                                disk_guid,
+                                partition_guid,
+                                alignment: align,
+                            }),
+                            LayoutArg::Mbr => Layout::Mbr(MbrOptions {
+                                partition_type,
+                                active,
+                                disk_signature,
+                                alignment: align,
+                            }),
+                        },
+                        timestamp: match timestamp {
+                            Some(timestamp) => Some(timestamp),
+                            None => Timestamp::source_date_epoch()?,
+                        },
+                        volume_id,
+                        preserve_times,
+                        preserve_attributes,
+                        deception: None,
+                    };
+                    (file_mappings, options, img_path, plain)
                 }
-                mappings
             };
+            file_mappings.extend(
+                mkdir
+                    .into_iter()
+                    .map(|path| (path, Source::Directory))
+                    .chain(content)
+                    .chain(fill)
+                    .chain(random)
+                    .map(|(path, src)| FileMapping::new(src, path)),
+            );
+            let fakes = [fake_sectors, fake_free_clusters, fake_next_free];
+            if !plain || fakes.iter().any(Option::is_some) {
+                let defaults = match plain {
+                    true => DeceptionOptions {
+                        total_sectors: Lie::Keep,
+                        free_clusters: Lie::Keep,
+                        next_free: Lie::Keep,
+                    },
+                    false => DeceptionOptions::default(),
+                };
+                options.deception = Some(DeceptionOptions {
+                    total_sectors: fake_sectors.unwrap_or(defaults.total_sectors),
+                    free_clusters: fake_free_clusters.unwrap_or(defaults.free_clusters),
+                    next_free: fake_next_free.unwrap_or(defaults.next_free),
+                });
+            }
+            for (path, meta) in attr {
+                let key = path.trim_matches('/');
+                let mapping = file_mappings
+                    .iter_mut()
+                    .find(|mapping| {
+                        mapping
+                            .int
+                            .to_str()
+                            .is_some_and(|int| int.trim_matches('/').eq_ignore_ascii_case(key))
+                    })
+                    .ok_or_else(|| {
+                        MkimgError::validation(format!("--attr: nothing is mapped to '{path}'"))
+                    })?;
+                mapping.meta = mapping.meta.merge(meta);
+            }
             let img_path = img_path.unwrap_or_else(|| {
                 if plain {
                     PathBuf::from("disk.img")
@@ -91,30 +573,324 @@ fn main() -> MkimgRes {
                 .write(true)
                 .open(img_path)?;
             if plain {
-                mkimg::create(&mut img_file, &file_mappings)?;
+                mkimg::create(&mut img_file, &file_mappings, &options)?;
             } else {
-                mkimg::create_deceptive_img(&mut img_file, &file_mappings)?;
+                for change in mkimg::create_deceptive_img(&mut img_file, &file_mappings, &options)?
+                {
+                    println!("{change}");
+                }
+                println!("Deceptive img created successfully!");
+            }
+            if !mutations.is_empty() {
+                for change in mkimg::mutate(&mut img_file, &mutations)? {
+                    println!("{change}");
+                }
             }
         }
-        Commands::Examine { img_path } => {
-            let img_file = std::fs::OpenOptions::new()
-                .create(true)
-                .truncate(true)
-                .read(true)
-                .write(true)
-                .open(img_path)?;
-            mkimg::examine(&img_file)?;
+        Commands::Examine {
+            img_path,
+            depth,
+            content_limit,
+            format,
+        } => {
+            let img_file = File::open(&img_path)
+                .map_err(|e| MkimgError::path_operation("open image", img_path, e))?;
+            let options = ExamineOptions {
+                max_depth: depth,
+                // Only the text listing shows file contents
+                content_limit: matches!(format, FormatArg::Text).then_some(content_limit),
+                free_space: true,
+            };
+            let report = mkimg::examine(&img_file, &options)?;
+            let mut out = std::io::stdout().lock();
+            let written = match format {
+                FormatArg::Text => write!(out, "{report}"),
+                FormatArg::Json => serde_json::to_writer_pretty(&mut out, &report)
+                    .map_err(io::Error::from)
+                    .and_then(|()| writeln!(out)),
+                FormatArg::Tree => report
+                    .volumes
+                    .iter()
+                    .try_for_each(|volume| write!(out, "{}", volume.tree())),
+            };
+            match written.and_then(|()| out.flush()) {
+                // The reader is gone, e.g. `mkimg examine img | head`
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => (),
+                result => result?,
+            }
         }
         Commands::Extract {
             img_path,
             file_path,
             output_path,
+            all,
+        } => {
+            let mut img_file = File::open(&img_path)
+                .map_err(|e| MkimgError::path_operation("open image", img_path, e))?;
+            let (pattern, output_path) = match (all, file_path, output_path) {
+                (Some(dir), _, _) => ("**".to_string(), dir),
+                (None, Some(file_path), Some(output_path)) => (file_path, output_path),
+                _ => unreachable!("clap requires both paths unless --all is set"),
+            };
+            // A single file keeps the classic behaviour of writing it to
+            // the output path itself
+            let report = mkimg::examine(&mut img_file, &ExamineOptions::listing())?;
+            let is_file = report
+                .volumes
+                .first()
+                .and_then(|volume| volume.find(&pattern))
+                .is_some_and(|entry| !entry.is_dir());
+            if is_file && output_path == Path::new("-") {
+                let mut out = std::io::stdout().lock();
+                mkimg::extract_to(&mut img_file, Path::new(&pattern), &mut out)?;
+                out.flush()?;
+            } else if is_file {
+                let file = File::create(&output_path)
+                    .map_err(|e| MkimgError::path_operation("create file", &output_path, e))?;
+                let mut out = BufWriter::new(file);
+                mkimg::extract_to(&mut img_file, Path::new(&pattern), &mut out)?;
+                out.flush()?;
+            } else {
+                for path in mkimg::extract_matching(&mut img_file, &pattern, &output_path)? {
+                    println!("{}", path.display());
+                }
+            }
+        }
+        Commands::Add {
+            img_path,
+            ext_path,
+            int_path,
+            timestamp,
+            preserve_times,
+            preserve_attributes,
+        } => {
+            let mut mappings = vec![FileMapping::new(ext_path.as_path(), int_path.as_path())];
+            if ext_path.is_dir() {
+                let contents = mkimg::create_mappings(&ext_path, true, &ScanOptions::default())?;
+                mappings.extend(contents.into_iter().map(|mut mapping| {
+                    mapping.int = int_path.join(&mapping.int);
+                    mapping
+                }));
+            }
+            let options = EditOptions {
+                timestamp: or_source_date_epoch(timestamp)?,
+                preserve_times,
+                preserve_attributes,
+            };
+            mkimg::edit(&mut open_img(&img_path)?, &options, |editor| {
+                mappings.iter().try_for_each(|mapping| editor.add(mapping))
+            })?;
+        }
+        Commands::Sync {
+            img_path,
+            tree,
+            timestamp,
+            preserve_times,
+            preserve_attributes,
+        } => {
+            let file_mappings = tree.mappings()?;
+            let options = EditOptions {
+                timestamp: or_source_date_epoch(timestamp)?,
+                preserve_times,
+                preserve_attributes,
+            };
+            let report = mkimg::sync(&mut open_img(&img_path)?, &file_mappings, &options)?;
+            for (status, paths) in [
+                ('D', &report.removed),
+                ('A', &report.added),
+                ('M', &report.replaced),
+                ('R', &report.renamed),
+            ] {
+                for path in paths {
+                    println!("{status} {path}");
+                }
+            }
+        }
+        Commands::Verify {
+            img_path,
+            manifest,
+            tree,
+            json,
+        } => {
+            let file_mappings = match manifest {
+                Some(manifest) => Manifest::load(&manifest)?.mappings,
+                None => tree.mappings()?,
+            };
+            let img_file = File::open(&img_path)
+                .map_err(|e| MkimgError::path_operation("open image", img_path, e))?;
+            let report = mkimg::verify(&img_file, &file_mappings)?;
+            if json {
+                serde_json::to_writer_pretty(std::io::stdout().lock(), &report)
+                    .map_err(std::io::Error::from)?;
+                println!();
+            } else {
+                print!("{report}");
+            }
+            if !report.is_match() {
+                return Err(MkimgError::validation(format!(
+                    "img does not match: {} missing, {} extra, {} differing",
+                    report.missing.len(),
+                    report.extra.len(),
+                    report.differing.len()
+                )));
+            }
+        }
+        Commands::Rm {
+            img_path,
+            int_paths,
+            recursive,
+            timestamp,
+        } => {
+            let options = EditOptions {
+                timestamp: or_source_date_epoch(timestamp)?,
+                ..EditOptions::default()
+            };
+            mkimg::edit(&mut open_img(&img_path)?, &options, |editor| {
+                int_paths
+                    .iter()
+                    .try_for_each(|path| editor.remove(path, recursive))
+            })?;
+        }
+        Commands::Mkdir {
+            img_path,
+            int_paths,
+            timestamp,
         } => {
-            let mut img_file = File::open(img_path)?;
-            let mut buf = Vec::new();
-            mkimg::extract(&mut img_file, &file_path, &mut buf)?;
-            std::fs::write(output_path, &buf)?;
+            let options = EditOptions {
+                timestamp: or_source_date_epoch(timestamp)?,
+                ..EditOptions::default()
+            };
+            mkimg::edit(&mut open_img(&img_path)?, &options, |editor| {
+                int_paths.iter().try_for_each(|path| editor.mkdir(path))
+            })?;
+        }
+        Commands::Mutate {
+            img_path,
+            specs,
+            list,
+        } => {
+            let registry = Registry::new();
+            if list {
+                for (name, description) in registry.list() {
+                    println!("{name:<12} {description}");
+                }
+                return Ok(());
+            }
+            let mutations = specs
+                .iter()
+                .map(|spec| registry.parse(spec))
+                .collect::<MkimgRes<Vec<_>>>()?;
+            // Required unless --list is given
+            let img_path = img_path.expect("img_path is required");
+            for change in mkimg::mutate(&mut open_img(&img_path)?, &mutations)? {
+                println!("{change}");
+            }
+        }
+        Commands::Mv {
+            img_path,
+            from,
+            to,
+            timestamp,
+        } => {
+            let options = EditOptions {
+                timestamp: or_source_date_epoch(timestamp)?,
+                ..EditOptions::default()
+            };
+            mkimg::edit(&mut open_img(&img_path)?, &options, |editor| {
+                editor.rename(&from, &to)
+            })?;
         }
     }
     Ok(())
 }
+
+/// Falls back to SOURCE_DATE_EPOCH when no timestamp is given.
+fn or_source_date_epoch(timestamp: Option<Timestamp>) -> MkimgRes<Option<Timestamp>> {
+    match timestamp {
+        Some(timestamp) => Ok(Some(timestamp)),
+        None => Timestamp::source_date_epoch(),
+    }
+}
+
+/// Opens an existing img for modification.
+fn open_img(img_path: &Path) -> MkimgRes<File> {
+    std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(img_path)
+        .map_err(|e| MkimgError::path_operation("open image", img_path, e))
+}
+
+/// Parses a timestamp given as Unix seconds or in ISO 8601 form.
+fn parse_timestamp(s: &str) -> Result<Timestamp, String> {
+    match s.parse::<i64>() {
+        Ok(secs) => Ok(Timestamp::from_unix(secs)),
+        Err(_) => s.parse().map_err(|e: MkimgError| e.to_string()),
+    }
+}
+
+/// Parses an `--attr` value of the form `<INT PATH>=<FLAGS>`.
+fn parse_attr(s: &str) -> Result<(String, EntryMeta), String> {
+    let (path, flags) = s
+        .rsplit_once('=')
+        .ok_or_else(|| format!("expected <INT PATH>=<FLAGS>, got '{s}'"))?;
+    let mut meta = EntryMeta::default();
+    let mut value = true;
+    for flag in flags.chars() {
+        match flag.to_ascii_lowercase() {
+            '+' => value = true,
+            '-' => value = false,
+            'r' => meta.read_only = Some(value),
+            'h' => meta.hidden = Some(value),
+            's' => meta.system = Some(value),
+            'a' => meta.archive = Some(value),
+            _ => return Err(format!("unknown attribute flag '{flag}' in '{s}'")),
+        }
+    }
+    Ok((path.to_string(), meta))
+}
+
+/// Parses a `--content` value of the form `<INT PATH>=<TEXT>`.
+fn parse_content(s: &str) -> Result<(PathBuf, Source), String> {
+    let (path, text) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected <INT PATH>=<TEXT>, got '{s}'"))?;
+    Ok((
+        PathBuf::from(path),
+        Source::Inline(text.as_bytes().to_vec()),
+    ))
+}
+
+/// Parses a `--fill` value of the form `<INT PATH>=<SIZE>[:<PATTERN>]`,
+/// with PATTERN given as hex bytes (e.g., `ff` or `0xDEADBEEF`).
+fn parse_fill(s: &str) -> Result<(PathBuf, Source), String> {
+    let (path, spec) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected <INT PATH>=<SIZE>[:<PATTERN>], got '{s}'"))?;
+    let (size, pattern) = spec.split_once(':').unwrap_or((spec, ""));
+    let len = parse_size(size).map_err(|e| e.to_string())?;
+    let hex = pattern
+        .strip_prefix("0x")
+        .or_else(|| pattern.strip_prefix("0X"))
+        .unwrap_or(pattern);
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return Err(format!("invalid hex pattern '{pattern}'"));
+    }
+    let pattern = (0..hex.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(&hex[idx..idx + 2], 16))
+        .collect::<Result<_, _>>()
+        .map_err(|_| format!("invalid hex pattern '{pattern}'"))?;
+    Ok((PathBuf::from(path), Source::Fill { len, pattern }))
+}
+
+/// Parses a `--random` value of the form `<INT PATH>=<SIZE>[:<SEED>]`.
+fn parse_random(s: &str) -> Result<(PathBuf, Source), String> {
+    let (path, spec) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected <INT PATH>=<SIZE>[:<SEED>], got '{s}'"))?;
+    let (size, seed) = spec.split_once(':').unwrap_or((spec, "0"));
+    let len = parse_size(size).map_err(|e| e.to_string())?;
+    let seed = parse_number(seed).map_err(|e| e.to_string())?;
+    Ok((PathBuf::from(path), Source::Random { len, seed }))
+}
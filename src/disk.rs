@@ -0,0 +1,53 @@
+//! Storage backends images can be built in.
+
+use std::{
+    fs::File,
+    io::{self, Cursor, Read, Seek, Write},
+};
+
+/// Seekable storage holding a disk image.
+///
+/// Implemented for files and in-memory buffers, so images can be
+/// built without touching the filesystem.
+pub trait Disk: Read + Write + Seek {
+    /// Truncates or extends the storage to `len` bytes. Extended
+    /// space reads as zeros.
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+}
+
+impl Disk for File {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+}
+
+impl Disk for Cursor<Vec<u8>> {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        resize(self.get_mut(), len)
+    }
+}
+
+impl Disk for Cursor<&mut Vec<u8>> {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        resize(self.get_mut(), len)
+    }
+}
+
+impl<D: Disk + ?Sized> Disk for &mut D {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        (**self).set_len(len)
+    }
+}
+
+impl<D: Disk + ?Sized> Disk for Box<D> {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        (**self).set_len(len)
+    }
+}
+
+fn resize(buf: &mut Vec<u8>, len: u64) -> io::Result<()> {
+    let len = usize::try_from(len)
+        .map_err(|_| io::Error::new(io::ErrorKind::OutOfMemory, "image too large for memory"))?;
+    buf.resize(len, 0);
+    Ok(())
+}
@@ -0,0 +1,324 @@
+//! In-place modification of existing images.
+//!
+//! An [`Editor`] is handed out by [`edit`](fn@crate::edit) and changes
+//! the first FAT volume of an image without reformatting it: entries
+//! that are not touched keep their clusters, names, timestamps and
+//! attributes.
+
+use crate::{
+    disk::Disk,
+    entry_metadata,
+    error::{path_to_str_with_context, MkimgError, MkimgRes},
+    fat, image_path_key,
+    options::EditOptions,
+    EntryMeta, FileMapping, Source,
+};
+use fatfs::{Dir, FileSystem, FsOptions};
+use fscommon::StreamSlice;
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    path::Path,
+};
+
+/// Makes changes to a mounted FAT volume.
+pub struct Editor<'a, D: Disk> {
+    fs: FileSystem<StreamSlice<&'a mut D>>,
+    options: EditOptions,
+    // Metadata for added and created entries and the directories
+    // holding changed entries, keyed by image path
+    touched: HashMap<String, EntryMeta>,
+    // Set when a directory changes parent, leaving its `..` stale
+    moved_dirs: bool,
+}
+
+impl<'a, D: Disk> Editor<'a, D> {
+    pub(crate) fn mount(
+        disk: &'a mut D,
+        offset: u64,
+        end: u64,
+        options: &EditOptions,
+    ) -> MkimgRes<Self> {
+        let volume = StreamSlice::new(disk, offset, end)?;
+        Ok(Self {
+            fs: FileSystem::new(volume, FsOptions::new())?,
+            options: options.clone(),
+            touched: HashMap::new(),
+            moved_dirs: false,
+        })
+    }
+
+    // Flush the volume, returning what is left to patch in the raw
+    // directory entries
+    pub(crate) fn unmount(self) -> MkimgRes<(HashMap<String, EntryMeta>, bool)> {
+        self.fs.unmount()?;
+        let timestamp = self.options.timestamp;
+        let stamp = EntryMeta {
+            created: timestamp,
+            modified: timestamp,
+            accessed: timestamp,
+            ..EntryMeta::default()
+        };
+        let touched = self
+            .touched
+            .into_iter()
+            .map(|(path, meta)| (path, stamp.merge(meta)))
+            .collect();
+        Ok((touched, self.moved_dirs))
+    }
+
+    /// Adds a file or directory, creating missing parent directories.
+    ///
+    /// An existing file at `mapping.int` is replaced. Its attributes
+    /// are kept unless `mapping.meta` or the host metadata asked for
+    /// by [`EditOptions`] sets them.
+    ///
+    /// # Errors
+    ///
+    /// Returns error if the path is taken by an entry of the other
+    /// kind, the volume is full or the source cannot be read
+    pub fn add(&mut self, mapping: &FileMapping) -> MkimgRes {
+        let parts = components(&mapping.int)?;
+        let Some((_, parents)) = parts.split_last() else {
+            return Err(MkimgError::invalid_path(
+                &mapping.int,
+                "is the root directory",
+            ));
+        };
+        let dirs = match mapping.src.is_dir() {
+            true => &parts[..],
+            false => parents,
+        };
+        // Host metadata is read before the source is, which may update
+        // its access time
+        self.keep(mapping)?;
+
+        let root = self.fs.root_dir();
+        for depth in 1..=dirs.len() {
+            let path = dirs[..depth].join("/");
+            if root.open_dir(&path).is_err() {
+                root.create_dir(&path)
+                    .map_err(|e| MkimgError::path_operation("create directory", &path, e))?;
+                self.touched.entry(image_path_key(&path)).or_default();
+                touch_parent(&mut self.touched, &dirs[..depth]);
+            }
+        }
+        if !mapping.src.is_dir() {
+            let path = parts.join("/");
+            let add_err = |e| MkimgError::path_operation("add", &mapping.int, e);
+            let mut file = root.create_file(&path).map_err(add_err)?;
+            // Opening an existing file keeps its contents
+            file.truncate().map_err(add_err)?;
+            io::copy(&mut mapping.src.open()?, &mut file).map_err(add_err)?;
+            file.flush().map_err(add_err)?;
+            touch_parent(&mut self.touched, &parts);
+        }
+        Ok(())
+    }
+
+    // Give an entry the metadata `mapping` asks for without touching
+    // its contents
+    pub(crate) fn keep(&mut self, mapping: &FileMapping) -> MkimgRes {
+        let metadata = entry_metadata(
+            std::slice::from_ref(mapping),
+            self.options.preserve_times,
+            self.options.preserve_attributes,
+        )?;
+        let key = image_path_key(path_to_str_with_context(&mapping.int)?);
+        let meta = metadata.get(&key).copied().unwrap_or_default();
+        self.touched.insert(key, meta);
+        Ok(())
+    }
+
+    /// Creates a directory and any missing parents. Does nothing if
+    /// it already exists.
+    ///
+    /// # Errors
+    ///
+    /// Returns error if the path is taken by a file
+    pub fn mkdir(&mut self, path: &Path) -> MkimgRes {
+        self.add(&FileMapping::new(Source::Directory, path))
+    }
+
+    /// Deletes a file or directory, freeing its clusters.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Entry to delete
+    /// * `recursive` - Delete directories along with their contents.
+    ///   Without it only empty directories can be deleted
+    ///
+    /// # Errors
+    ///
+    /// Returns error if the entry does not exist or is a non-empty
+    /// directory and `recursive` is not set
+    pub fn remove(&mut self, path: &Path, recursive: bool) -> MkimgRes {
+        let parts = components(path)?;
+        if parts.is_empty() {
+            return Err(MkimgError::invalid_path(path, "is the root directory"));
+        }
+        let path_str = parts.join("/");
+        let root = self.fs.root_dir();
+        match root.open_dir(&path_str) {
+            Ok(dir) if recursive => {
+                remove_contents(&dir).map_err(|e| MkimgError::path_operation("remove", path, e))?
+            }
+            _ => {}
+        }
+        root.remove(&path_str)
+            .map_err(|e| MkimgError::path_operation("remove", path, e))?;
+
+        let key = image_path_key(&path_str);
+        let prefix = format!("{key}/");
+        self.touched
+            .retain(|touched, _| *touched != key && !touched.starts_with(&prefix));
+        touch_parent(&mut self.touched, &parts);
+        Ok(())
+    }
+
+    /// Renames or moves a file or directory. If `to` is an existing
+    /// directory the entry is moved into it, keeping its name.
+    /// Timestamps and attributes are kept.
+    ///
+    /// # Errors
+    ///
+    /// Returns error if `from` does not exist, `to` already exists or
+    /// a directory would be moved into itself
+    pub fn rename(&mut self, from: &Path, to: &Path) -> MkimgRes {
+        let from_parts = components(from)?;
+        let Some((name, from_parent)) = from_parts.split_last() else {
+            return Err(MkimgError::invalid_path(from, "is the root directory"));
+        };
+        let root = self.fs.root_dir();
+        let from_str = from_parts.join("/");
+        let is_dir = root.open_dir(&from_str).is_ok();
+
+        let from_key = image_path_key(&from_str);
+        let mut to_parts = components(to)?;
+        let into_dir = root.open_dir(&to_parts.join("/")).is_ok();
+        // `to` naming `from` itself in another case is a rename, not a
+        // move into it
+        if to_parts.is_empty() || (into_dir && image_path_key(&to_parts.join("/")) != from_key) {
+            to_parts.push(name);
+        }
+        let to_str = to_parts.join("/");
+        let to_key = image_path_key(&to_str);
+        if is_dir && to_key.starts_with(&format!("{from_key}/")) {
+            return Err(MkimgError::invalid_path(
+                to,
+                "is inside the moved directory",
+            ));
+        }
+        let (to_name, to_parent) = to_parts.split_last().unwrap();
+        let dst_dir = match to_parent.is_empty() {
+            true => root.clone(),
+            false => root
+                .open_dir(&to_parent.join("/"))
+                .map_err(|e| MkimgError::path_operation("rename", to, e))?,
+        };
+
+        let rename_err = |e| MkimgError::path_operation("rename", from, e);
+        if from_key == to_key {
+            // fatfs sees a case-only rename as a rename onto itself, so
+            // go through a temporary name
+            if from_str == to_str {
+                return Ok(());
+            }
+            let tmp = temp_name(&dst_dir);
+            root.rename(&from_str, &dst_dir, &tmp).map_err(rename_err)?;
+            dst_dir
+                .rename(&tmp, &dst_dir, to_name)
+                .map_err(rename_err)?;
+        } else {
+            root.rename(&from_str, &dst_dir, to_name)
+                .map_err(rename_err)?;
+        }
+
+        if is_dir && from_parent != to_parent {
+            self.moved_dirs = true;
+        }
+        let prefix = format!("{from_key}/");
+        self.touched = std::mem::take(&mut self.touched)
+            .into_iter()
+            .map(|(key, meta)| match key.strip_prefix(&prefix) {
+                Some(rest) => (format!("{to_key}/{rest}"), meta),
+                None if key == from_key => (to_key.clone(), meta),
+                None => (key, meta),
+            })
+            .collect();
+        touch_parent(&mut self.touched, &from_parts);
+        touch_parent(&mut self.touched, &to_parts);
+        Ok(())
+    }
+}
+
+// Have the timestamp applied to the directory holding `parts`, whose
+// contents changed. The root directory has no entry of its own
+fn touch_parent(touched: &mut HashMap<String, EntryMeta>, parts: &[&str]) {
+    if let Some((_, parent)) = parts.split_last().filter(|(_, parent)| !parent.is_empty()) {
+        touched
+            .entry(image_path_key(&parent.join("/")))
+            .or_default();
+    }
+}
+
+// Non-empty `/` separated components of an image path
+fn components(path: &Path) -> MkimgRes<Vec<&str>> {
+    Ok(path_to_str_with_context(path)?
+        .split('/')
+        .filter(|part| !part.is_empty())
+        .collect())
+}
+
+// Empty a directory so fatfs lets it be removed
+fn remove_contents<T: fatfs::ReadWriteSeek>(dir: &Dir<'_, T>) -> io::Result<()> {
+    let mut names = Vec::new();
+    for entry in dir.iter() {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name != "." && name != ".." {
+            names.push((name, entry.is_dir()));
+        }
+    }
+    for (name, is_dir) in names {
+        if is_dir {
+            remove_contents(&dir.open_dir(&name)?)?;
+        }
+        dir.remove(&name)?;
+    }
+    Ok(())
+}
+
+// A name not yet used in `dir`
+fn temp_name<T: fatfs::ReadWriteSeek>(dir: &Dir<'_, T>) -> String {
+    (0..)
+        .map(|n| format!("~MKIMG{n}.TMP"))
+        .find(|name| dir.open_file(name).is_err() && dir.open_dir(name).is_err())
+        .unwrap()
+}
+
+/// Points the `..` entry of every directory at its current parent.
+/// fatfs leaves them alone when moving a directory.
+pub(crate) fn fix_parent_links<D: Disk>(img_file: &mut D, volume_offset: u64) -> MkimgRes {
+    let mut volume = fat::Volume::open(&mut *img_file, volume_offset)?;
+    let entries = volume.walk()?;
+    let clusters: HashMap<String, u32> = entries
+        .iter()
+        .filter(|(_, entry)| entry.attributes.directory && !entry.is_dot())
+        .map(|(path, entry)| (image_path_key(path), entry.first_cluster))
+        .collect();
+    for (path, entry) in &entries {
+        let Some(dir) = path.strip_suffix("/..") else {
+            continue;
+        };
+        // The root directory is always referred to as cluster 0
+        let parent = match image_path_key(dir).rsplit_once('/') {
+            Some((parent, _)) => clusters.get(parent).copied().unwrap_or(0),
+            None => 0,
+        };
+        if entry.first_cluster != parent {
+            volume.set_first_cluster(entry.offset, parent)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,928 @@
+//! Direct access to on-disk FAT structures.
+//!
+//! `fatfs` hides the boot sector, allocation table and raw directory
+//! entries, so inspecting cluster chains or short names needs this
+//! separate, read-only view of a volume. It is deliberately tolerant:
+//! damaged chains and directories are cut short rather than treated
+//! as errors.
+
+use crate::error::{MkimgError, MkimgRes};
+use fatfs::FatType;
+use serde::{Serialize, Serializer};
+use std::{
+    collections::HashSet,
+    fmt,
+    io::{Read, Seek, SeekFrom, Write},
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Size of a single directory entry.
+pub(crate) const DIR_ENTRY_SIZE: usize = 32;
+
+/// Attribute byte value marking a long file name entry.
+pub(crate) const LFN_ATTR: u8 = 0x0f;
+
+/// First name byte of a deleted entry.
+pub(crate) const DELETED_MARKER: u8 = 0xe5;
+
+/// Sequence number flag marking the last (first stored) LFN entry.
+pub(crate) const LFN_LAST_FLAG: u8 = 0x40;
+
+/// UTF-16 code units stored per LFN entry and their byte offsets.
+pub(crate) const LFN_CHAR_OFFSETS: [usize; 13] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+/// FAT directory entry attributes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Attributes {
+    pub read_only: bool,
+    pub hidden: bool,
+    pub system: bool,
+    pub volume_id: bool,
+    pub directory: bool,
+    pub archive: bool,
+}
+
+impl Attributes {
+    /// Decodes an attribute byte.
+    pub fn from_byte(byte: u8) -> Self {
+        Self {
+            read_only: byte & 0x01 != 0,
+            hidden: byte & 0x02 != 0,
+            system: byte & 0x04 != 0,
+            volume_id: byte & 0x08 != 0,
+            directory: byte & 0x10 != 0,
+            archive: byte & 0x20 != 0,
+        }
+    }
+
+    /// Encodes the attributes as an attribute byte.
+    pub fn to_byte(self) -> u8 {
+        u8::from(self.read_only)
+            | u8::from(self.hidden) << 1
+            | u8::from(self.system) << 2
+            | u8::from(self.volume_id) << 3
+            | u8::from(self.directory) << 4
+            | u8::from(self.archive) << 5
+    }
+}
+
+/// A FAT timestamp. Access dates have no time of day, so their time
+/// fields are always zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub millis: u16,
+}
+
+/// Unix time of the earliest and latest FAT timestamps.
+const UNIX_MIN: i64 = 315_532_800;
+const UNIX_MAX: i64 = 4_354_819_199;
+
+impl Timestamp {
+    /// Converts seconds since the Unix epoch to a UTC timestamp,
+    /// clamped to the 1980-2107 range FAT can store.
+    pub fn from_unix(secs: i64) -> Self {
+        let secs = secs.clamp(UNIX_MIN, UNIX_MAX);
+        let (days, secs) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+        // Civil from days, see https://howardhinnant.github.io/date_algorithms.html
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z.rem_euclid(146_097);
+        let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + i64::from(month <= 2);
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (secs / 3600) as u8,
+            minute: (secs / 60 % 60) as u8,
+            second: (secs % 60) as u8,
+            millis: 0,
+        }
+    }
+
+    /// Converts a host time to a UTC timestamp, clamped to the range
+    /// FAT can store.
+    pub fn from_system_time(time: SystemTime) -> Self {
+        let (secs, millis) = match time.duration_since(UNIX_EPOCH) {
+            Ok(elapsed) => (
+                i64::try_from(elapsed.as_secs()).unwrap_or(i64::MAX),
+                elapsed.subsec_millis() as u16,
+            ),
+            // Before 1970, so clamped to 1980 anyway
+            Err(_) => (0, 0),
+        };
+        let mut timestamp = Self::from_unix(secs);
+        if (UNIX_MIN..UNIX_MAX).contains(&secs) {
+            timestamp.millis = millis;
+        }
+        timestamp
+    }
+
+    /// The current UTC time.
+    pub fn now() -> Self {
+        Self::from_system_time(SystemTime::now())
+    }
+
+    /// Reads the `SOURCE_DATE_EPOCH` environment variable used by
+    /// reproducible build tooling. Returns `None` if it is not set.
+    ///
+    /// # Errors
+    ///
+    /// Returns error if the variable is not a Unix timestamp
+    pub fn source_date_epoch() -> MkimgRes<Option<Self>> {
+        match std::env::var("SOURCE_DATE_EPOCH") {
+            Ok(value) => value
+                .trim()
+                .parse()
+                .map(Self::from_unix)
+                .map(Some)
+                .map_err(|_| {
+                    MkimgError::validation(format!("invalid SOURCE_DATE_EPOCH '{value}'"))
+                }),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Encodes the timestamp as a DOS date, time and 10ms resolution
+    /// field. The timestamp must have passed [`Self::validate`].
+    pub(crate) fn encode(&self) -> (u16, u16, u8) {
+        let date = (self.year - 1980) << 9 | u16::from(self.month) << 5 | u16::from(self.day);
+        let time =
+            u16::from(self.hour) << 11 | u16::from(self.minute) << 5 | u16::from(self.second / 2);
+        let tenths = (self.second % 2) * 100 + (self.millis / 10) as u8;
+        (date, time, tenths)
+    }
+
+    /// Volume serial number derived from the timestamp the way DOS
+    /// `FORMAT` does.
+    pub(crate) fn serial(&self) -> u32 {
+        let month_day = u16::from(self.month) << 8 | u16::from(self.day);
+        let second_hundredths = u16::from(self.second) << 8 | (self.millis / 10);
+        let hour_minute = u16::from(self.hour) << 8 | u16::from(self.minute);
+        u32::from(month_day.wrapping_add(second_hundredths)) << 16
+            | u32::from(hour_minute.wrapping_add(self.year))
+    }
+
+    /// Checks the timestamp is a valid date and time FAT can store.
+    pub(crate) fn validate(&self) -> MkimgRes<Self> {
+        let days_in_month = match self.month {
+            2 if self.year.is_multiple_of(4) && self.year != 2100 => 29,
+            2 => 28,
+            4 | 6 | 9 | 11 => 30,
+            _ => 31,
+        };
+        if !(1980..=2107).contains(&self.year)
+            || !(1..=12).contains(&self.month)
+            || !(1..=days_in_month).contains(&self.day)
+            || self.hour > 23
+            || self.minute > 59
+            || self.second > 59
+            || self.millis > 999
+        {
+            return Err(MkimgError::validation(format!(
+                "{self} is not a valid FAT timestamp (1980-2107)"
+            )));
+        }
+        Ok(*self)
+    }
+
+    /// Decodes a DOS date, time and 10ms resolution field. Returns
+    /// `None` for the all-zero "not set" date.
+    pub(crate) fn decode(date: u16, time: u16, tenths: u8) -> Option<Self> {
+        if date == 0 {
+            return None;
+        }
+        Some(Self {
+            year: 1980 + (date >> 9),
+            month: ((date >> 5) & 0x0f) as u8,
+            day: (date & 0x1f) as u8,
+            hour: (time >> 11) as u8,
+            minute: ((time >> 5) & 0x3f) as u8,
+            second: ((time & 0x1f) * 2) as u8 + tenths / 100,
+            millis: u16::from(tenths % 100) * 10,
+        })
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second, self.millis
+        )
+    }
+}
+
+/// Parses `YYYY-MM-DD`, `YYYY-MM-DDTHH:MM:SS` or
+/// `YYYY-MM-DDTHH:MM:SS.mmm`.
+impl FromStr for Timestamp {
+    type Err = MkimgError;
+
+    fn from_str(s: &str) -> MkimgRes<Self> {
+        let invalid = || MkimgError::validation(format!("invalid timestamp '{s}'"));
+        let (date, time) = s.split_once(['T', ' ']).unwrap_or((s, "00:00:00"));
+        let (time, millis) = time.split_once('.').unwrap_or((time, "0"));
+        let date: Vec<&str> = date.split('-').collect();
+        let time: Vec<&str> = time.split(':').collect();
+        let [year, month, day] = date[..] else {
+            return Err(invalid());
+        };
+        let [hour, minute, second] = time[..] else {
+            return Err(invalid());
+        };
+        let wide = |s: &str| s.parse::<u16>().map_err(|_| invalid());
+        let narrow = |s: &str| s.parse::<u8>().map_err(|_| invalid());
+        Self {
+            year: wide(year)?,
+            month: narrow(month)?,
+            day: narrow(day)?,
+            hour: narrow(hour)?,
+            minute: narrow(minute)?,
+            second: narrow(second)?,
+            millis: wide(millis)?,
+        }
+        .validate()
+    }
+}
+
+/// Serialized as an ISO 8601 local time, e.g. `2024-01-31T12:00:00.000`.
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Boot sector fields needed to navigate a volume.
+#[derive(Debug, Clone)]
+pub(crate) struct Bpb {
+    pub oem_name: [u8; 8],
+    pub bytes_per_sector: u16,
+    pub sectors_per_cluster: u8,
+    pub reserved_sectors: u16,
+    pub fats: u8,
+    pub root_entries: u16,
+    pub total_sectors: u32,
+    pub media: u8,
+    pub sectors_per_fat: u32,
+    pub hidden_sectors: u32,
+    pub root_cluster: u32,
+    pub volume_id: u32,
+    pub volume_label: [u8; 11],
+    pub fat_type: FatType,
+}
+
+impl Bpb {
+    pub fn parse(sector: &[u8]) -> MkimgRes<Self> {
+        let u16_at = |offset: usize| u16::from_le_bytes([sector[offset], sector[offset + 1]]);
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes([
+                sector[offset],
+                sector[offset + 1],
+                sector[offset + 2],
+                sector[offset + 3],
+            ])
+        };
+        if sector.len() < 512 || sector[0x1fe..0x200] != [0x55, 0xaa] {
+            return Err(MkimgError::validation("missing boot sector signature"));
+        }
+        let bytes_per_sector = u16_at(0x0b);
+        let sectors_per_cluster = sector[0x0d];
+        if !matches!(sector[0], 0xeb | 0xe9)
+            || !bytes_per_sector.is_power_of_two()
+            || bytes_per_sector < 512
+            || !sectors_per_cluster.is_power_of_two()
+            || sector[0x10] == 0
+            || u16_at(0x0e) == 0
+        {
+            return Err(MkimgError::validation("invalid BIOS parameter block"));
+        }
+        let sectors_per_fat_16 = u16_at(0x16);
+        let is_fat32 = sectors_per_fat_16 == 0;
+        let total_sectors = match u16_at(0x13) {
+            0 => u32_at(0x20),
+            total_sectors => u32::from(total_sectors),
+        };
+        let (sectors_per_fat, root_cluster, ext) = if is_fat32 {
+            (u32_at(0x24), u32_at(0x2c), 0x40)
+        } else {
+            (u32::from(sectors_per_fat_16), 0, 0x24)
+        };
+        let mut oem_name = [0; 8];
+        oem_name.copy_from_slice(&sector[0x03..0x0b]);
+        let mut volume_label = [0; 11];
+        volume_label.copy_from_slice(&sector[ext + 7..ext + 18]);
+        let mut bpb = Self {
+            oem_name,
+            bytes_per_sector,
+            sectors_per_cluster,
+            reserved_sectors: u16_at(0x0e),
+            fats: sector[0x10],
+            root_entries: u16_at(0x11),
+            total_sectors,
+            media: sector[0x15],
+            sectors_per_fat,
+            hidden_sectors: u32_at(0x1c),
+            root_cluster,
+            volume_id: u32_at(ext + 3),
+            volume_label,
+            fat_type: FatType::Fat32,
+        };
+        if !is_fat32 {
+            bpb.fat_type = if bpb.total_clusters() < 4085 {
+                FatType::Fat12
+            } else {
+                FatType::Fat16
+            };
+        }
+        Ok(bpb)
+    }
+
+    pub fn bytes_per_cluster(&self) -> u32 {
+        u32::from(self.bytes_per_sector) * u32::from(self.sectors_per_cluster)
+    }
+
+    pub fn fat_offset(&self) -> u64 {
+        u64::from(self.reserved_sectors) * u64::from(self.bytes_per_sector)
+    }
+
+    pub fn fat_bytes(&self) -> u64 {
+        u64::from(self.sectors_per_fat) * u64::from(self.bytes_per_sector)
+    }
+
+    pub fn root_dir_offset(&self) -> u64 {
+        self.fat_offset() + u64::from(self.fats) * self.fat_bytes()
+    }
+
+    pub fn root_dir_bytes(&self) -> u64 {
+        u64::from(self.root_entries) * DIR_ENTRY_SIZE as u64
+    }
+
+    pub fn data_offset(&self) -> u64 {
+        let root_dir_sectors = self
+            .root_dir_bytes()
+            .div_ceil(u64::from(self.bytes_per_sector));
+        self.root_dir_offset() + root_dir_sectors * u64::from(self.bytes_per_sector)
+    }
+
+    pub fn total_clusters(&self) -> u32 {
+        let data_sectors = u64::from(self.total_sectors)
+            .saturating_sub(self.data_offset() / u64::from(self.bytes_per_sector));
+        (data_sectors / u64::from(self.sectors_per_cluster)) as u32
+    }
+
+    /// Highest cluster number both the BPB and the FAT size allow.
+    pub fn max_cluster(&self) -> u32 {
+        let bits = match self.fat_type {
+            FatType::Fat12 => 12,
+            FatType::Fat16 => 16,
+            FatType::Fat32 => 32,
+        };
+        let fat_entries = (self.fat_bytes() * 8 / bits).min(u64::from(u32::MAX)) as u32;
+        (self.total_clusters() + 1).min(fat_entries.saturating_sub(1))
+    }
+
+    pub fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_offset() + u64::from(cluster - 2) * u64::from(self.bytes_per_cluster())
+    }
+
+    /// Smallest FAT entry value marking the end of a chain.
+    pub fn end_of_chain(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat12 => 0x0ff8,
+            FatType::Fat16 => 0xfff8,
+            FatType::Fat32 => 0x0fff_fff8,
+        }
+    }
+
+    /// FAT entry value marking a bad cluster.
+    pub fn bad_cluster(&self) -> u32 {
+        self.end_of_chain() - 1
+    }
+}
+
+/// Returns true if `sector` looks like the boot sector of a FAT
+/// volume.
+pub(crate) fn is_boot_sector(sector: &[u8]) -> bool {
+    Bpb::parse(sector).is_ok()
+}
+
+/// A FAT volume read directly from disk. All offsets are relative to
+/// the start of the volume.
+pub(crate) struct Volume<T> {
+    disk: T,
+    /// Byte offset of the volume within `disk`.
+    base: u64,
+    pub bpb: Bpb,
+    /// Last chunk read from the first FAT, and its offset within the
+    /// FAT.
+    fat_chunk: Option<(u64, Vec<u8>)>,
+}
+
+/// Bytes of the first FAT read at a time.
+const FAT_CHUNK: u64 = 64 * 1024;
+
+impl<T: Read + Seek> Volume<T> {
+    /// Opens the volume starting `base` bytes into `disk`.
+    pub fn open(mut disk: T, base: u64) -> MkimgRes<Self> {
+        let mut sector = [0u8; 512];
+        disk.seek(SeekFrom::Start(base))?;
+        disk.read_exact(&mut sector)?;
+        let bpb = Bpb::parse(&sector)?;
+        Ok(Self {
+            disk,
+            base,
+            bpb,
+            fat_chunk: None,
+        })
+    }
+
+    pub fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> MkimgRes {
+        self.disk.seek(SeekFrom::Start(self.base + offset))?;
+        self.disk.read_exact(buf)?;
+        Ok(())
+    }
+
+    /// Reads the entry for `cluster` from the first FAT.
+    pub fn fat_entry(&mut self, cluster: u32) -> MkimgRes<u32> {
+        match self.bpb.fat_type {
+            FatType::Fat12 => {
+                let mut buf = [0u8; 2];
+                self.read_fat(u64::from(cluster) + u64::from(cluster / 2), &mut buf)?;
+                let value = u16::from_le_bytes(buf);
+                Ok(u32::from(if cluster.is_multiple_of(2) {
+                    value & 0x0fff
+                } else {
+                    value >> 4
+                }))
+            }
+            FatType::Fat16 => {
+                let mut buf = [0u8; 2];
+                self.read_fat(u64::from(cluster) * 2, &mut buf)?;
+                Ok(u32::from(u16::from_le_bytes(buf)))
+            }
+            FatType::Fat32 => {
+                let mut buf = [0u8; 4];
+                self.read_fat(u64::from(cluster) * 4, &mut buf)?;
+                Ok(u32::from_le_bytes(buf) & 0x0fff_ffff)
+            }
+        }
+    }
+
+    // Reads `buf` from `offset` bytes into the first FAT, going
+    // through a cached chunk of it
+    fn read_fat(&mut self, offset: u64, buf: &mut [u8]) -> MkimgRes {
+        let end = offset + buf.len() as u64;
+        let covers =
+            |(start, data): &(u64, Vec<u8>)| offset >= *start && end <= start + data.len() as u64;
+        if !self.fat_chunk.as_ref().is_some_and(covers) && end <= self.bpb.fat_bytes() {
+            // Chunks overlap by a few bytes, so no entry is split
+            // between two of them
+            let start = offset - offset % FAT_CHUNK;
+            let len = (FAT_CHUNK + 3).min(self.bpb.fat_bytes() - start);
+            let mut data = Vec::new();
+            self.disk
+                .seek(SeekFrom::Start(self.base + self.bpb.fat_offset() + start))?;
+            (&mut self.disk).take(len).read_to_end(&mut data)?;
+            self.fat_chunk = Some((start, data));
+        }
+        match &self.fat_chunk {
+            Some(chunk @ (start, data)) if covers(chunk) => {
+                let idx = (offset - start) as usize;
+                buf.copy_from_slice(&data[idx..idx + buf.len()]);
+                Ok(())
+            }
+            // Past the end of the FAT or of the image
+            _ => self.read_at(self.bpb.fat_offset() + offset, buf),
+        }
+    }
+
+    /// Number of unallocated clusters according to the first FAT.
+    pub fn free_clusters(&mut self) -> MkimgRes<u32> {
+        let mut free = 0;
+        for cluster in 2..=self.bpb.max_cluster() {
+            if self.fat_entry(cluster)? == 0 {
+                free += 1;
+            }
+        }
+        Ok(free)
+    }
+
+    /// Follows the chain starting at `first`. The chain is cut short
+    /// at the first free, reserved, bad or out of range entry, and
+    /// when it loops back on itself.
+    pub fn chain(&mut self, first: u32) -> MkimgRes<Vec<u32>> {
+        let mut chain = Vec::new();
+        let mut seen = HashSet::new();
+        let mut cluster = first;
+        while (2..=self.bpb.max_cluster()).contains(&cluster) && seen.insert(cluster) {
+            chain.push(cluster);
+            let next = self.fat_entry(cluster)?;
+            if next >= self.bpb.end_of_chain() {
+                break;
+            }
+            cluster = next;
+        }
+        Ok(chain)
+    }
+
+    /// Reads up to `len` bytes of the data stored in `chain`.
+    pub fn read_chain(&mut self, chain: &[u32], len: u64) -> MkimgRes<Vec<u8>> {
+        let mut data = Vec::new();
+        self.copy_chain(chain, len, &mut data)?;
+        Ok(data)
+    }
+
+    /// Copies up to `len` bytes of the data stored in `chain` to `out`,
+    /// one cluster at a time. Returns the number of bytes copied.
+    pub fn copy_chain<W: Write + ?Sized>(
+        &mut self,
+        chain: &[u32],
+        len: u64,
+        out: &mut W,
+    ) -> MkimgRes<u64> {
+        let bytes_per_cluster = u64::from(self.bpb.bytes_per_cluster());
+        let mut buf = vec![0u8; len.min(bytes_per_cluster) as usize];
+        let mut copied = 0;
+        for &cluster in chain {
+            let remaining = len - copied;
+            if remaining == 0 {
+                break;
+            }
+            let chunk = &mut buf[..remaining.min(bytes_per_cluster) as usize];
+            self.read_at(self.bpb.cluster_offset(cluster), chunk)?;
+            out.write_all(chunk)?;
+            copied += chunk.len() as u64;
+        }
+        Ok(copied)
+    }
+
+    /// Reads the raw entries of the root directory.
+    pub fn root_dir(&mut self) -> MkimgRes<Vec<RawEntry>> {
+        if self.bpb.fat_type == FatType::Fat32 {
+            return self.dir(self.bpb.root_cluster);
+        }
+        let offset = self.bpb.root_dir_offset();
+        let mut buf = vec![0u8; self.bpb.root_dir_bytes() as usize];
+        self.read_at(offset, &mut buf)?;
+        Ok(RawEntry::split(&buf, offset))
+    }
+
+    /// Reads the raw entries of the directory starting at `first`.
+    pub fn dir(&mut self, first: u32) -> MkimgRes<Vec<RawEntry>> {
+        let chain = self.chain(first)?;
+        let bytes_per_cluster = self.bpb.bytes_per_cluster() as usize;
+        let mut entries = Vec::new();
+        for cluster in chain {
+            let offset = self.bpb.cluster_offset(cluster);
+            let mut buf = vec![0u8; bytes_per_cluster];
+            self.read_at(offset, &mut buf)?;
+            entries.extend(RawEntry::split(&buf, offset));
+        }
+        Ok(entries)
+    }
+
+    /// Lists every entry in the volume with its `/` separated path,
+    /// parents before their children. The `.` and `..` entries of
+    /// each subdirectory are included, with paths such as `EFI/.`.
+    pub fn walk(&mut self) -> MkimgRes<Vec<(String, DirEntry)>> {
+        let mut entries = Vec::new();
+        let root = parse_dir(&self.root_dir()?);
+        let mut visited = HashSet::new();
+        self.walk_dir(root, "", &mut visited, &mut entries)?;
+        Ok(entries)
+    }
+
+    fn walk_dir(
+        &mut self,
+        dir: Vec<DirEntry>,
+        parent: &str,
+        visited: &mut HashSet<u32>,
+        entries: &mut Vec<(String, DirEntry)>,
+    ) -> MkimgRes {
+        for entry in dir {
+            let path = if parent.is_empty() {
+                entry.name().to_string()
+            } else {
+                format!("{parent}/{}", entry.name())
+            };
+            let descend = entry.attributes.directory
+                && !entry.is_dot()
+                && visited.insert(entry.first_cluster);
+            let first_cluster = entry.first_cluster;
+            entries.push((path.clone(), entry));
+            if descend {
+                let dir = parse_dir(&self.dir(first_cluster)?);
+                self.walk_dir(dir, &path, visited, entries)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T: Read + Write + Seek> Volume<T> {
+    pub fn write_at(&mut self, offset: u64, data: &[u8]) -> MkimgRes {
+        let fat = self.bpb.fat_offset()..self.bpb.fat_offset() + self.bpb.fat_bytes();
+        if offset < fat.end && offset + data.len() as u64 > fat.start {
+            self.fat_chunk = None;
+        }
+        self.disk.seek(SeekFrom::Start(self.base + offset))?;
+        self.disk.write_all(data)?;
+        Ok(())
+    }
+
+    /// Writes the attributes and timestamps of `entry` back to its
+    /// short entry. Names, size and first cluster are left untouched.
+    pub fn update_entry(&mut self, entry: &DirEntry) -> MkimgRes {
+        let mut raw = [0u8; DIR_ENTRY_SIZE];
+        self.read_at(entry.offset, &mut raw)?;
+        raw[0x0b] = entry.attributes.to_byte();
+        let encode = |timestamp: Option<Timestamp>| timestamp.map_or((0, 0, 0), |t| t.encode());
+        let (date, time, tenths) = encode(entry.created);
+        raw[0x0d] = tenths;
+        raw[0x0e..0x10].copy_from_slice(&time.to_le_bytes());
+        raw[0x10..0x12].copy_from_slice(&date.to_le_bytes());
+        let (date, _, _) = encode(entry.accessed);
+        raw[0x12..0x14].copy_from_slice(&date.to_le_bytes());
+        let (date, time, _) = encode(entry.modified);
+        raw[0x16..0x18].copy_from_slice(&time.to_le_bytes());
+        raw[0x18..0x1a].copy_from_slice(&date.to_le_bytes());
+        self.write_at(entry.offset, &raw)
+    }
+
+    /// Sets the entry for `cluster` in FAT copy `copy`, counting from
+    /// 0. The reserved top bits of FAT32 entries are kept.
+    pub fn set_fat_entry(&mut self, copy: u8, cluster: u32, value: u32) -> MkimgRes {
+        if copy >= self.bpb.fats {
+            return Err(MkimgError::validation(format!(
+                "volume has no FAT copy {copy}"
+            )));
+        }
+        let fat_offset = self.bpb.fat_offset() + u64::from(copy) * self.bpb.fat_bytes();
+        match self.bpb.fat_type {
+            FatType::Fat12 => {
+                let offset = fat_offset + u64::from(cluster) + u64::from(cluster / 2);
+                let mut buf = [0u8; 2];
+                self.read_at(offset, &mut buf)?;
+                let old = u16::from_le_bytes(buf);
+                let value = (value & 0x0fff) as u16;
+                let new = if cluster.is_multiple_of(2) {
+                    (old & 0xf000) | value
+                } else {
+                    (old & 0x000f) | (value << 4)
+                };
+                self.write_at(offset, &new.to_le_bytes())
+            }
+            FatType::Fat16 => self.write_at(
+                fat_offset + u64::from(cluster) * 2,
+                &(value as u16).to_le_bytes(),
+            ),
+            FatType::Fat32 => {
+                let offset = fat_offset + u64::from(cluster) * 4;
+                let mut buf = [0u8; 4];
+                self.read_at(offset, &mut buf)?;
+                let new = (u32::from_le_bytes(buf) & 0xf000_0000) | (value & 0x0fff_ffff);
+                self.write_at(offset, &new.to_le_bytes())
+            }
+        }
+    }
+
+    /// Sets the entry for `cluster` in every FAT copy.
+    pub fn set_fat_entries(&mut self, cluster: u32, value: u32) -> MkimgRes {
+        for copy in 0..self.bpb.fats {
+            self.set_fat_entry(copy, cluster, value)?;
+        }
+        Ok(())
+    }
+
+    /// Points the short entry at `offset` to `cluster`.
+    pub fn set_first_cluster(&mut self, offset: u64, cluster: u32) -> MkimgRes {
+        let mut raw = [0u8; DIR_ENTRY_SIZE];
+        self.read_at(offset, &mut raw)?;
+        raw[0x14..0x16].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+        raw[0x1a..0x1c].copy_from_slice(&(cluster as u16).to_le_bytes());
+        self.write_at(offset, &raw)
+    }
+}
+
+/// A 32 byte directory slot and its location in the volume.
+#[derive(Debug, Clone)]
+pub(crate) struct RawEntry {
+    pub offset: u64,
+    pub bytes: [u8; DIR_ENTRY_SIZE],
+}
+
+impl RawEntry {
+    /// Splits directory data read from `offset` into slots.
+    fn split(buf: &[u8], offset: u64) -> Vec<Self> {
+        buf.chunks_exact(DIR_ENTRY_SIZE)
+            .enumerate()
+            .map(|(idx, chunk)| {
+                let mut bytes = [0; DIR_ENTRY_SIZE];
+                bytes.copy_from_slice(chunk);
+                Self {
+                    offset: offset + (idx * DIR_ENTRY_SIZE) as u64,
+                    bytes,
+                }
+            })
+            .collect()
+    }
+
+    fn u16_at(&self, offset: usize) -> u16 {
+        u16::from_le_bytes([self.bytes[offset], self.bytes[offset + 1]])
+    }
+
+    pub fn is_lfn(&self) -> bool {
+        self.bytes[0x0b] & 0x3f == LFN_ATTR
+    }
+}
+
+/// A directory entry with its long name assembled.
+#[derive(Debug, Clone)]
+pub(crate) struct DirEntry {
+    pub short_name: String,
+    pub long_name: Option<String>,
+    pub attributes: Attributes,
+    pub created: Option<Timestamp>,
+    pub modified: Option<Timestamp>,
+    pub accessed: Option<Timestamp>,
+    pub first_cluster: u32,
+    pub size: u32,
+    /// Offset of the short entry in the volume.
+    pub offset: u64,
+}
+
+impl DirEntry {
+    pub fn name(&self) -> &str {
+        self.long_name.as_deref().unwrap_or(&self.short_name)
+    }
+
+    pub fn is_dot(&self) -> bool {
+        self.short_name == "." || self.short_name == ".."
+    }
+}
+
+/// Assembles the live entries of a directory from its raw slots,
+/// skipping deleted entries and the volume label, and stopping at the
+/// end of directory marker.
+pub(crate) fn parse_dir(raw: &[RawEntry]) -> Vec<DirEntry> {
+    let mut entries = Vec::new();
+    let mut lfn: Vec<&RawEntry> = Vec::new();
+    for entry in raw {
+        match entry.bytes[0] {
+            0x00 => break,
+            DELETED_MARKER => {
+                lfn.clear();
+                continue;
+            }
+            _ => {}
+        }
+        if entry.is_lfn() {
+            if entry.bytes[0] & LFN_LAST_FLAG != 0 {
+                lfn.clear();
+            }
+            lfn.push(entry);
+            continue;
+        }
+        let attributes = Attributes::from_byte(entry.bytes[0x0b]);
+        if attributes.volume_id {
+            lfn.clear();
+            continue;
+        }
+        let mut name = [0u8; 11];
+        name.copy_from_slice(&entry.bytes[..11]);
+        let long_name = long_name(&lfn, &name);
+        lfn.clear();
+        if name[0] == 0x05 {
+            name[0] = DELETED_MARKER;
+        }
+        entries.push(DirEntry {
+            short_name: short_name(&name),
+            long_name,
+            attributes,
+            created: Timestamp::decode(entry.u16_at(0x10), entry.u16_at(0x0e), entry.bytes[0x0d]),
+            modified: Timestamp::decode(entry.u16_at(0x18), entry.u16_at(0x16), 0),
+            accessed: Timestamp::decode(entry.u16_at(0x12), 0, 0),
+            first_cluster: u32::from(entry.u16_at(0x14)) << 16 | u32::from(entry.u16_at(0x1a)),
+            size: u32::from_le_bytes([
+                entry.bytes[0x1c],
+                entry.bytes[0x1d],
+                entry.bytes[0x1e],
+                entry.bytes[0x1f],
+            ]),
+            offset: entry.offset,
+        });
+    }
+    entries
+}
+
+/// Formats an 11 byte short name as `NAME.EXT`.
+pub(crate) fn short_name(raw: &[u8; 11]) -> String {
+    let base = String::from_utf8_lossy(&raw[..8]).trim_end().to_string();
+    let ext = String::from_utf8_lossy(&raw[8..]).trim_end().to_string();
+    if ext.is_empty() {
+        base
+    } else {
+        format!("{base}.{ext}")
+    }
+}
+
+/// Checksum of a short name stored in each of its LFN entries.
+pub(crate) fn lfn_checksum(name: &[u8; 11]) -> u8 {
+    name.iter()
+        .fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+/// Decodes the long name stored in `lfn`, which holds the LFN entries
+/// in on-disk order (last part first). Returns `None` if the sequence
+/// is incomplete or does not belong to the short name.
+fn long_name(lfn: &[&RawEntry], short_name: &[u8; 11]) -> Option<String> {
+    let checksum = lfn_checksum(short_name);
+    let count = lfn.len();
+    let first = lfn.first()?;
+    if first.bytes[0] & LFN_LAST_FLAG == 0 || usize::from(first.bytes[0] & 0x1f) != count {
+        return None;
+    }
+    let mut units = Vec::with_capacity(count * LFN_CHAR_OFFSETS.len());
+    for (idx, entry) in lfn.iter().rev().enumerate() {
+        if usize::from(entry.bytes[0] & 0x1f) != idx + 1 || entry.bytes[0x0d] != checksum {
+            return None;
+        }
+        units.extend(LFN_CHAR_OFFSETS.iter().map(|&offset| entry.u16_at(offset)));
+    }
+    let len = units
+        .iter()
+        .position(|&unit| unit == 0)
+        .unwrap_or(units.len());
+    Some(String::from_utf16_lossy(&units[..len]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn last_moment(year: u16) -> Timestamp {
+        Timestamp {
+            year,
+            month: 12,
+            day: 31,
+            hour: 23,
+            minute: 59,
+            second: 59,
+            millis: 990,
+        }
+    }
+
+    #[test]
+    fn validate_accepts_fat_year_bounds() {
+        let first = Timestamp {
+            month: 1,
+            day: 1,
+            hour: 0,
+            minute: 0,
+            second: 0,
+            millis: 0,
+            ..last_moment(1980)
+        };
+        assert!(first.validate().is_ok());
+        assert!(last_moment(2107).validate().is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_years_outside_fat_range() {
+        assert!(last_moment(1979).validate().is_err());
+        assert!(last_moment(2108).validate().is_err());
+        assert!("1979-12-31T23:59:59".parse::<Timestamp>().is_err());
+        assert!("2108-01-01".parse::<Timestamp>().is_err());
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        for timestamp in [
+            "1980-01-01T00:00:00",
+            "2024-02-29T12:34:57.450",
+            "2107-12-31T23:59:59.990",
+        ] {
+            let timestamp: Timestamp = timestamp.parse().unwrap();
+            let (date, time, tenths) = timestamp.encode();
+            assert_eq!(Timestamp::decode(date, time, tenths), Some(timestamp));
+        }
+        assert_eq!(last_moment(2107).encode().0 >> 9, 127);
+    }
+
+    #[test]
+    fn decode_treats_zero_date_as_unset() {
+        assert_eq!(Timestamp::decode(0, 0, 0), None);
+    }
+}
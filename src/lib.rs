@@ -1,24 +1,110 @@
 #![doc = include_str!("../README.md")]
 
+pub mod disk;
+pub mod edit;
 pub mod error;
-use crate::error::{
-    canonicalize_with_context, path_to_str_with_context, strip_prefix_with_context, MkimgError,
-    MkimgRes,
+pub mod fat;
+pub mod manifest;
+pub mod mutation;
+pub mod options;
+pub mod partition;
+pub mod report;
+pub mod size;
+pub mod source;
+use crate::{
+    disk::Disk,
+    error::{
+        canonicalize_with_context, path_to_str_with_context, strip_prefix_with_context, MkimgError,
+        MkimgRes,
+    },
+    fat::Timestamp,
+    mutation::{Mutation, Shrink, SizeDeception},
+    options::{
+        CreateOptions, EditOptions, ExamineOptions, Layout, ScanOptions, SymlinkPolicy, IGNORE_FILE,
+    },
+    partition::Placement,
+    report::{Difference, Differing, DiskReport, EntryReport, ImageReport, VerifyReport},
+    size::{Geometry, BYTES_PER_SECTOR},
 };
+pub use edit::Editor;
+pub use fatfs::FatType;
 use fatfs::{FileSystem, FormatVolumeOptions, FsOptions};
+use fscommon::StreamSlice;
+use globset::{GlobBuilder, GlobMatcher, GlobSet, GlobSetBuilder};
+pub use source::Source;
 use std::{
+    collections::{HashMap, HashSet},
     fs::File,
-    io::{Read, Seek, SeekFrom, Write},
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
     path::{Path, PathBuf},
 };
 use walkdir::WalkDir;
 
-/// Mapping from external to image file.
+/// Mapping from a content source to an image file.
 pub struct FileMapping {
-    /// Path to source file in external filesystem.
-    pub ext: PathBuf,
+    /// Where the file contents come from.
+    pub src: Source,
     /// Where to place the file in the image filesystem.
     pub int: PathBuf,
+    /// Attributes and timestamps for the image entry. These take
+    /// precedence over [`CreateOptions::timestamp`] and host metadata.
+    pub meta: EntryMeta,
+}
+
+impl FileMapping {
+    /// Maps `src` to `int` with default metadata.
+    pub fn new(src: impl Into<Source>, int: impl Into<PathBuf>) -> Self {
+        Self {
+            src: src.into(),
+            int: int.into(),
+            meta: EntryMeta::default(),
+        }
+    }
+}
+
+/// Explicit FAT metadata for an image entry. Fields left as `None`
+/// keep the value the entry would otherwise get. Timestamps must lie
+/// within the years 1980 to 2107 FAT can store.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EntryMeta {
+    pub read_only: Option<bool>,
+    pub hidden: Option<bool>,
+    pub system: Option<bool>,
+    pub archive: Option<bool>,
+    pub created: Option<Timestamp>,
+    pub modified: Option<Timestamp>,
+    /// Only the date is stored.
+    pub accessed: Option<Timestamp>,
+}
+
+impl EntryMeta {
+    /// Layers `other` on top of `self`; fields set in `other` win.
+    pub fn merge(self, other: EntryMeta) -> Self {
+        Self {
+            read_only: other.read_only.or(self.read_only),
+            hidden: other.hidden.or(self.hidden),
+            system: other.system.or(self.system),
+            archive: other.archive.or(self.archive),
+            created: other.created.or(self.created),
+            modified: other.modified.or(self.modified),
+            accessed: other.accessed.or(self.accessed),
+        }
+    }
+
+    // Apply to a raw entry. `.` entries only take timestamps
+    fn apply(&self, entry: &mut fat::DirEntry, times_only: bool) {
+        entry.created = self.created.or(entry.created);
+        entry.modified = self.modified.or(entry.modified);
+        entry.accessed = self.accessed.or(entry.accessed);
+        if times_only {
+            return;
+        }
+        let attributes = &mut entry.attributes;
+        attributes.read_only = self.read_only.unwrap_or(attributes.read_only);
+        attributes.hidden = self.hidden.unwrap_or(attributes.hidden);
+        attributes.system = self.system.unwrap_or(attributes.system);
+        attributes.archive = self.archive.unwrap_or(attributes.archive);
+    }
 }
 
 /// Scans a directory tree and creates file mappings for image
@@ -29,6 +115,8 @@ pub struct FileMapping {
 /// * `exclude_root` - If true, only directory contents are
 ///   included. If false, the root directory itself becomes the image
 ///   root
+/// * `options` - Patterns selecting the entries to map, the maximum
+///   depth and how to treat symbolic links
 ///
 /// # Returns
 ///
@@ -37,145 +125,721 @@ pub struct FileMapping {
 ///
 /// # Errors
 ///
-/// Returns error if root is not a directory or filesystem operations
-/// fail
-pub fn create_mappings(root: &Path, exclude_root: bool) -> MkimgRes<Vec<FileMapping>> {
+/// Returns error if root is not a directory, a pattern is invalid, a
+/// symbolic link is found with [`SymlinkPolicy::Error`] or filesystem
+/// operations fail
+pub fn create_mappings(
+    root: &Path,
+    exclude_root: bool,
+    options: &ScanOptions,
+) -> MkimgRes<Vec<FileMapping>> {
     if !root.is_dir() {
         return Err(MkimgError::validation("root must be a directory"));
     };
-    let canon_root = {
-        let mut canon = canonicalize_with_context(root)?;
-        if !exclude_root {
-            canon.pop();
-        }
-        canon
+    let root_name = match exclude_root {
+        true => None,
+        false => canonicalize_with_context(root)?
+            .file_name()
+            .map(PathBuf::from),
     };
-    let tree = WalkDir::new(root);
-    let rerooted_mappings = reroot_tree(&canon_root, tree)?;
-    Ok(rerooted_mappings)
+    let filter = ScanFilter::new(root, options)?;
+    // Sorted so that the same tree always produces the same image
+    let mut walker = WalkDir::new(root)
+        .sort_by_file_name()
+        .follow_links(options.symlinks == SymlinkPolicy::Follow);
+    if let Some(max_depth) = options.max_depth {
+        walker = walker.max_depth(max_depth);
+    }
+    let mut scanned = Vec::new();
+    let mut entries = walker.into_iter();
+    while let Some(entry) = entries.next() {
+        let entry = entry?;
+        let rel_path = relative_path(strip_prefix_with_context(entry.path(), root)?)?;
+        if entry.depth() > 0 && entry.path_is_symlink() {
+            match options.symlinks {
+                SymlinkPolicy::Follow => (),
+                SymlinkPolicy::Skip => continue,
+                SymlinkPolicy::Error => {
+                    return Err(MkimgError::invalid_path(entry.path(), "is a symbolic link"))
+                }
+            }
+        }
+        if entry.depth() > 0 && filter.is_excluded(&rel_path) {
+            if entry.file_type().is_dir() {
+                entries.skip_current_dir();
+            }
+            continue;
+        }
+        scanned.push((rel_path, entry));
+    }
+
+    let included = filter.included(scanned.iter().map(|(rel_path, _)| rel_path.as_str()));
+    let mut out = Vec::new();
+    for ((rel_path, entry), included) in scanned.iter().zip(included) {
+        let int = match &root_name {
+            Some(name) => name.join(rel_path),
+            None => PathBuf::from(rel_path),
+        };
+        if included && int != Path::new("") {
+            out.push(FileMapping::new(entry.path(), int));
+        }
+    }
+    Ok(out)
+}
+
+// `/` separated form of a path relative to the scanned root
+fn relative_path(path: &Path) -> MkimgRes<String> {
+    let mut parts = Vec::new();
+    for part in path.iter() {
+        parts.push(path_to_str_with_context(Path::new(part))?);
+    }
+    Ok(parts.join("/"))
+}
+
+/// Compiled include and exclude patterns of a [`ScanOptions`].
+struct ScanFilter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
 }
 
-/// Creates a standard FAT16 disk image (6MB fixed size).
+impl ScanFilter {
+    fn new(root: &Path, options: &ScanOptions) -> MkimgRes<Self> {
+        let mut exclude = options.exclude.clone();
+        let ignore_file = root.join(IGNORE_FILE);
+        if options.ignore_file && ignore_file.is_file() {
+            let text = std::fs::read_to_string(&ignore_file)
+                .map_err(|e| MkimgError::path_operation("read", &ignore_file, e))?;
+            exclude.extend(
+                text.lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(String::from),
+            );
+            exclude.push(format!("/{IGNORE_FILE}"));
+        }
+        let include = match options.include.is_empty() {
+            true => None,
+            false => Some(compile_patterns(&options.include)?),
+        };
+        Ok(Self {
+            include,
+            exclude: compile_patterns(&exclude)?,
+        })
+    }
+
+    fn is_excluded(&self, rel_path: &str) -> bool {
+        self.exclude.is_match(rel_path)
+    }
+
+    /// Whether each of `rel_paths`, given in walk order, passes the
+    /// include patterns: either it or a parent directory matches, or it
+    /// is a directory holding an entry that does.
+    fn included<'a>(&self, rel_paths: impl Iterator<Item = &'a str>) -> Vec<bool> {
+        let rel_paths: Vec<_> = rel_paths.collect();
+        let Some(include) = &self.include else {
+            return vec![true; rel_paths.len()];
+        };
+        // Matching entries and all of their parent directories
+        let mut kept = HashSet::new();
+        for rel_path in &rel_paths {
+            let mut ancestor = Path::new(rel_path);
+            let matched = ancestor.ancestors().any(|path| include.is_match(path));
+            while matched && kept.insert(ancestor) {
+                ancestor = ancestor.parent().unwrap_or(Path::new(""));
+            }
+        }
+        rel_paths
+            .iter()
+            .map(|rel_path| rel_path.is_empty() || kept.contains(Path::new(rel_path)))
+            .collect()
+    }
+}
+
+// Compile patterns with gitignore-like anchoring: patterns without a
+// `/` match at any depth, a leading `/` anchors to the root
+fn compile_patterns(patterns: &[String]) -> MkimgRes<GlobSet> {
+    let mut set = GlobSetBuilder::new();
+    for pattern in patterns {
+        let trimmed = pattern.trim_end_matches('/');
+        let anchored = match trimmed.strip_prefix('/') {
+            Some(anchored) => anchored.to_string(),
+            None if !trimmed.contains('/') => format!("**/{trimmed}"),
+            None => trimmed.to_string(),
+        };
+        set.add(
+            GlobBuilder::new(&anchored)
+                .literal_separator(true)
+                .build()
+                .map_err(|e| MkimgError::validation(format!("invalid pattern '{pattern}': {e}")))?,
+        );
+    }
+    set.build()
+        .map_err(|e| MkimgError::validation(format!("invalid patterns: {e}")))
+}
+
+/// Creates a standard FAT disk image, optionally wrapped in a
+/// partition table.
 ///
 /// # Arguments
-/// * `img_file` - Output file handle for the image
+/// * `img_file` - Output storage for the image, e.g. a `File` or a
+///   `Cursor<Vec<u8>>`
 /// * `file_mappings` - Vector of files to include in the image
+/// * `options` - Volume size and geometry; unset options are chosen
+///   automatically to fit the contents
 ///
 /// # Errors
-/// Returns error if filesystem operations fail
-pub fn create(img_file: &mut File, file_mappings: &[FileMapping]) -> MkimgRes {
-    img_file.set_len(6 * 1024 * 1024)?;
-    write_fs(img_file, file_mappings, fatfs::FatType::Fat16)?;
+/// Returns error if the options are invalid, the contents do not fit
+/// or filesystem operations fail
+pub fn create<D: Disk>(
+    img_file: &mut D,
+    file_mappings: &[FileMapping],
+    options: &CreateOptions,
+) -> MkimgRes {
+    let geometry = size::compute_geometry(file_mappings, options)?;
+    let placement = partition::place(&options.layout, geometry.total_sectors)?;
+    img_file.set_len(placement.disk_bytes())?;
+    write_fs(img_file, file_mappings, &geometry, &placement, options)?;
+    if let Some(deception) = options.deception {
+        SizeDeception(deception).apply(img_file, placement.volume_offset())?;
+    }
+    let layout = match options.timestamp {
+        Some(_) => partition::derive_ids(img_file, &options.layout, &placement)?,
+        None => options.layout.clone(),
+    };
+    partition::write_table(img_file, &layout, &geometry, &placement)?;
     Ok(())
 }
 
-/// Prints detailed contents of a disk image including directory
-/// structure and file contents for small files.
+/// Modifies the first FAT volume of an existing image in place.
+///
+/// The volume is mounted and handed to `f`, which adds, replaces,
+/// removes and renames entries through the [`Editor`]. Everything it
+/// does not touch is left as it was, so swapping a single file in a
+/// large image only rewrites that file. Like [`extract`], this works
+/// on bare volumes as well as MBR and GPT disks.
 ///
 /// # Arguments
 ///
-/// * `img_file` - Image file to examine
+/// * `img_file` - Image to modify, e.g. a `File` opened for reading
+///   and writing
+/// * `options` - Timestamp and host metadata for added entries
+/// * `f` - Changes to make
+///
+/// # Returns
+///
+/// Whatever `f` returns
 ///
 /// # Errors
 ///
-/// Returns error if image cannot be read or is not a valid FAT
-/// filesystem
-pub fn examine(img_file: &File) -> MkimgRes {
-    let fs = FileSystem::new(img_file, FsOptions::new())?;
-    let fs_root = fs.root_dir();
-    for entry in fs_root.iter() {
-        let entry = entry?;
-        let name = entry.file_name();
-        let is_dir = entry.is_dir();
-        let size = if is_dir { 0 } else { entry.len() };
-        let is_dir_tag = if is_dir { "(DIR)" } else { "(FILE)" };
-        println!("{name} {size} bytes {is_dir_tag}");
-        if is_dir && name != "." && name != ".." {
-            examine_directory(&fs_root, &name, 1)?;
+/// Returns error if the image holds no FAT volume, `f` fails or
+/// filesystem operations fail. Changes made before `f` failed are
+/// kept.
+pub fn edit<D: Disk, R>(
+    img_file: &mut D,
+    options: &EditOptions,
+    f: impl FnOnce(&mut Editor<'_, D>) -> MkimgRes<R>,
+) -> MkimgRes<R> {
+    let (offset, end) = volume_bounds(&mut *img_file)?;
+    let mut editor = Editor::mount(&mut *img_file, offset, end, options)?;
+    let result = f(&mut editor)?;
+    let (touched, moved_dirs) = editor.unmount()?;
+    if moved_dirs {
+        edit::fix_parent_links(img_file, offset)?;
+    }
+    apply_metadata(img_file, offset, &touched, None)?;
+    Ok(result)
+}
+
+/// Applies mutations, in order, to the first FAT volume of a finished
+/// image.
+///
+/// # Arguments
+///
+/// * `img_file` - Image to change, e.g. a `File` opened for reading
+///   and writing
+/// * `mutations` - Changes to apply, e.g. built by a
+///   [`Registry`](mutation::Registry) from `name[:params]` specs
+///
+/// # Returns
+///
+/// A description of what each mutation changed, in order
+///
+/// # Errors
+///
+/// Returns error if the image holds no FAT volume or a mutation fails
+pub fn mutate<D: Disk>(img_file: &mut D, mutations: &[Box<dyn Mutation>]) -> MkimgRes<Vec<String>> {
+    // Located once, as mutations may leave the volume unreadable
+    let (offset, _) = volume_bounds(&mut *img_file)?;
+    mutations
+        .iter()
+        .map(|mutation| mutation.apply(img_file, offset))
+        .collect()
+}
+
+/// Changes made to an image by [`sync`], as `/` separated image paths.
+#[derive(Debug, Clone, Default)]
+pub struct SyncReport {
+    /// Entries that were not in the image.
+    pub added: Vec<String>,
+    /// Files whose size or contents differed.
+    pub replaced: Vec<String>,
+    /// Entries whose name differed only in case.
+    pub renamed: Vec<String>,
+    /// Entries no mapping covers, or of the wrong kind. Contents of
+    /// removed directories are not listed.
+    pub removed: Vec<String>,
+    /// Number of mapped entries that were already up to date.
+    pub unchanged: usize,
+}
+
+/// Brings the first FAT volume of an existing image in line with a
+/// set of file mappings, changing only what differs.
+///
+/// Mapped files missing from the image are added and files whose size
+/// or contents differ are replaced; unchanged files keep their
+/// clusters. Entries no mapping covers are removed, as are entries of
+/// the wrong kind, e.g. a file where a directory is mapped. Names that
+/// differ only in case are corrected. Timestamps and attributes of
+/// every mapped entry are then set from `options` and the mappings,
+/// as [`create`] would set them.
+///
+/// # Arguments
+///
+/// * `img_file` - Image to update, e.g. a `File` opened for reading
+///   and writing
+/// * `file_mappings` - What the image should hold, as given to
+///   [`create`]
+/// * `options` - Timestamp and host metadata for the mapped entries
+///
+/// # Returns
+///
+/// A [`SyncReport`] listing the changes
+///
+/// # Errors
+///
+/// Returns error if the image holds no FAT volume, a source cannot be
+/// read, the volume is full or filesystem operations fail
+pub fn sync<D: Disk>(
+    img_file: &mut D,
+    file_mappings: &[FileMapping],
+    options: &EditOptions,
+) -> MkimgRes<SyncReport> {
+    let disk_report = examine(&mut *img_file, &ExamineOptions::listing())?;
+    let volume = first_volume(&disk_report)?;
+    let existing: HashMap<_, _> = volume
+        .iter()
+        .map(|entry| (image_path_key(&entry.path), entry))
+        .collect();
+
+    let wanted = wanted_entries(file_mappings)?;
+
+    let mut report = SyncReport::default();
+    let mut removed = HashSet::new();
+    for entry in volume.iter() {
+        let key = image_path_key(&entry.path);
+        let parent_removed = key
+            .rsplit_once('/')
+            .is_some_and(|(parent, _)| removed.contains(parent));
+        if parent_removed || wanted.get(&key) != Some(&entry.is_dir()) {
+            if !parent_removed {
+                report.removed.push(entry.path.clone());
+            }
+            removed.insert(key);
         }
     }
-    Ok(())
+
+    // Compare contents before the volume is mounted for writing
+    let mut plan = Vec::new();
+    for mapping in file_mappings {
+        let path = image_path(path_to_str_with_context(&mapping.int)?);
+        let key = path.to_lowercase();
+        let entry = existing.get(&key).filter(|_| !removed.contains(&key));
+        let Some(entry) = entry else {
+            report.added.push(path);
+            plan.push((mapping, None, true));
+            continue;
+        };
+        let changed = !mapping.src.is_dir()
+            && compare_contents(&mut *img_file, volume, entry, &mapping.src)?.is_some();
+        let rename = path.rsplit('/').next() != Some(entry.name.as_str());
+        match (changed, rename) {
+            (true, _) => report.replaced.push(path.clone()),
+            (false, false) => report.unchanged += 1,
+            (false, true) => (),
+        }
+        if rename {
+            report.renamed.push(path);
+        }
+        plan.push((mapping, rename.then_some(entry.path.as_str()), changed));
+    }
+
+    edit(img_file, options, |editor| {
+        for path in &report.removed {
+            editor.remove(Path::new(path), true)?;
+        }
+        for (mapping, rename_from, write) in plan {
+            if let Some(from) = rename_from {
+                editor.rename(Path::new(from), &mapping.int)?;
+            }
+            match write {
+                true => editor.add(mapping)?,
+                false => editor.keep(mapping)?,
+            }
+        }
+        Ok(())
+    })?;
+    Ok(report)
+}
+
+/// Checks that the first FAT volume of an image holds exactly what a
+/// set of file mappings describes.
+///
+/// Every mapped file must be present with identical contents and every
+/// mapped directory must exist. Image entries no mapping covers, other
+/// than the directories holding mapped entries, are reported as extra.
+/// Timestamps and attributes are not compared. The image is only ever
+/// read.
+///
+/// # Arguments
+///
+/// * `img_file` - Image to check, e.g. a `&File` or an in-memory
+///   `Cursor`
+/// * `file_mappings` - What the image should hold, as given to
+///   [`create`]
+///
+/// # Returns
+///
+/// A [`VerifyReport`] listing missing, extra and differing entries;
+/// [`VerifyReport::is_match`] tells whether there were any
+///
+/// # Errors
+///
+/// Returns error if the image holds no FAT volume, or the image or a
+/// source cannot be read
+pub fn verify<T: Read + Seek>(
+    mut img_file: T,
+    file_mappings: &[FileMapping],
+) -> MkimgRes<VerifyReport> {
+    let disk_report = examine(&mut img_file, &ExamineOptions::listing())?;
+    let volume = first_volume(&disk_report)?;
+    let wanted = wanted_entries(file_mappings)?;
+
+    // Entries of the wrong kind or not mapped at all are reported
+    // once, without their contents
+    let mut report = VerifyReport::default();
+    let mut existing = HashMap::new();
+    let mut skipped = HashSet::new();
+    for entry in volume.iter() {
+        let key = image_path_key(&entry.path);
+        let parent_skipped = key
+            .rsplit_once('/')
+            .is_some_and(|(parent, _)| skipped.contains(parent));
+        match wanted.get(&key) {
+            _ if parent_skipped => (),
+            None => report.extra.push(entry.path.clone()),
+            Some(&is_dir) if is_dir != entry.is_dir() => report.differing.push(Differing {
+                path: entry.path.clone(),
+                difference: Difference::Kind,
+            }),
+            Some(_) => {
+                existing.insert(key, entry);
+                continue;
+            }
+        }
+        skipped.insert(key);
+    }
+
+    for mapping in file_mappings {
+        let path = image_path(path_to_str_with_context(&mapping.int)?);
+        let key = path.to_lowercase();
+        match existing.get(&key) {
+            None if !skipped.contains(&key) => report.missing.push(path),
+            Some(entry) if !mapping.src.is_dir() => {
+                if let Some(difference) =
+                    compare_contents(&mut img_file, volume, entry, &mapping.src)?
+                {
+                    report.differing.push(Differing { path, difference });
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok(report)
+}
+
+/// Reads the volume parameters and full directory tree of every FAT
+/// volume in a disk image.
+///
+/// The image is only ever read. Bare FAT volumes, MBR disks and GPT
+/// disks are told apart automatically.
+///
+/// # Arguments
+///
+/// * `img_file` - Image to examine, e.g. a `&File` or an in-memory
+///   `Cursor`
+/// * `options` - How deep to descend and which file contents to
+///   capture
+///
+/// # Returns
+///
+/// A [`DiskReport`] with the partition table and one [`ImageReport`]
+/// per FAT volume; its `Display` implementation renders the classic
+/// text listing
+///
+/// # Errors
+///
+/// Returns error if image cannot be read or holds neither a FAT
+/// volume nor a partition table
+pub fn examine<T: Read + Seek>(img_file: T, options: &ExamineOptions) -> MkimgRes<DiskReport> {
+    report::build(img_file, options)
 }
 
 /// Extracts a single file from a disk image.
 ///
+/// The file is looked up in the first FAT volume of the image, which
+/// may be a bare volume or a partition of an MBR or GPT disk.
+///
 /// # Arguments
 ///
-/// * `img_file` - Source image file
+/// * `img_file` - Source image, e.g. a `&mut File` or an in-memory
+///   `Cursor`
 /// * `target_path` - Path to file within the image filesystem
 /// * `buf` - Buffer to store extracted file contents
 ///
 /// # Errors
 ///
 /// Returns error if file not found or filesystem operations fail
-pub fn extract(img_file: &mut File, target_path: &Path, buf: &mut Vec<u8>) -> MkimgRes {
-    let fs = FileSystem::new(img_file, FsOptions::new())?;
-    let root_dir = fs.root_dir();
-    let target_parts = target_path.iter().collect::<Vec<_>>();
+pub fn extract<T: Read + Seek>(img_file: T, target_path: &Path, buf: &mut Vec<u8>) -> MkimgRes {
+    extract_to(img_file, target_path, buf)?;
+    Ok(())
+}
 
-    // Navigate through directories to find the file
-    let mut current_path = String::new();
-    for (i, part) in target_parts.iter().enumerate() {
-        let part = part.to_str().ok_or_else(|| {
+/// Streams a single file from a disk image into any writer.
+///
+/// Like [`extract`], but the file is copied one cluster at a time, so
+/// only a bounded buffer is held in memory regardless of its size.
+///
+/// # Arguments
+///
+/// * `img_file` - Source image, e.g. a `&mut File` or an in-memory
+///   `Cursor`
+/// * `target_path` - Path to file within the image filesystem
+/// * `out` - Destination of the file contents, e.g. a `File` or
+///   `io::stdout()`
+///
+/// # Returns
+///
+/// The number of bytes written
+///
+/// # Errors
+///
+/// Returns error if file not found, filesystem operations fail or
+/// `out` cannot be written. `out` may have received part of the file
+/// when reading fails partway
+pub fn extract_to<T: Read + Seek, W: Write + ?Sized>(
+    mut img_file: T,
+    target_path: &Path,
+    out: &mut W,
+) -> MkimgRes<u64> {
+    let mut target = Vec::new();
+    for part in target_path.iter() {
+        target.push(part.to_str().ok_or_else(|| {
             MkimgError::invalid_path(
                 PathBuf::from(part),
                 "invalid UTF-8 characters in path component",
             )
-        })?;
-
-        if i == target_parts.len() - 1 {
-            // This is the filename, open the file
-            let dir = if current_path.is_empty() {
-                root_dir.clone()
-            } else {
-                root_dir.open_dir(&current_path)?
-            };
-            let mut file = dir.open_file(part)?;
-            file.read_to_end(buf)?;
-            break;
-        } else {
-            // This is a directory, add to path
-            if !current_path.is_empty() {
-                current_path.push('/');
-            }
-            current_path.push_str(part);
-        }
+        })?);
+    }
+    let report = examine(&mut img_file, &ExamineOptions::listing())?;
+    let volume = first_volume(&report)?;
+    match volume.find(&target.join("/")) {
+        Some(entry) if !entry.is_dir() => volume.copy_file(&mut img_file, entry, out),
+        Some(_) => Err(MkimgError::invalid_path(target_path, "is a directory")),
+        None => Err(MkimgError::invalid_path(target_path, "not found in image")),
     }
-
-    Ok(())
 }
 
-// Create filesystem with FAT32 and copy files
-fn write_fs(img_file: &mut File, tree: &[FileMapping], fat_type: fatfs::FatType) -> MkimgRes {
-    {
-        fatfs::format_volume(
-            &mut *img_file,
-            FormatVolumeOptions::new().fat_type(fat_type),
+/// Extracts every file and directory matching a glob pattern,
+/// recreating their directory structure under `dest`.
+///
+/// Patterns are matched case-insensitively against `/` separated
+/// paths from the image root: `*` does not cross directories, `**`
+/// does. A matching directory is extracted with all of its contents,
+/// so `**` extracts the whole image. Like [`extract`], this reads the
+/// first FAT volume of the image.
+///
+/// # Arguments
+///
+/// * `img_file` - Source image, e.g. a `&mut File` or an in-memory
+///   `Cursor`
+/// * `pattern` - Glob pattern or plain path, e.g. `EFI/**/*.efi`
+/// * `dest` - Host directory to extract into
+///
+/// # Returns
+///
+/// Host paths of the extracted files and directories
+///
+/// # Errors
+///
+/// Returns error if the pattern is invalid or matches nothing, or if
+/// reading the image or writing the host files fails
+pub fn extract_matching<T: Read + Seek>(
+    mut img_file: T,
+    pattern: &str,
+    dest: &Path,
+) -> MkimgRes<Vec<PathBuf>> {
+    let matcher = GlobBuilder::new(pattern.trim_matches('/'))
+        .case_insensitive(true)
+        .literal_separator(true)
+        .build()
+        .map_err(|e| MkimgError::validation(format!("invalid pattern '{pattern}': {e}")))?
+        .compile_matcher();
+    let report = examine(&mut img_file, &ExamineOptions::listing())?;
+    let volume = first_volume(&report)?;
+    let mut extracted = Vec::new();
+    for entry in &volume.entries {
+        extract_entry(
+            &mut img_file,
+            volume,
+            entry,
+            &matcher,
+            false,
+            dest,
+            &mut extracted,
         )?;
     }
-    let fs = FileSystem::new(img_file, FsOptions::new())?;
+    if extracted.is_empty() {
+        return Err(MkimgError::validation(format!(
+            "nothing in the image matches '{pattern}'"
+        )));
+    }
+    Ok(extracted)
+}
+
+// Start and end, in bytes, of the first FAT volume of an image
+fn volume_bounds<T: Read + Seek>(img_file: T) -> MkimgRes<(u64, u64)> {
+    let report = examine(
+        img_file,
+        &ExamineOptions {
+            max_depth: Some(0),
+            ..ExamineOptions::listing()
+        },
+    )?;
+    let volume = first_volume(&report)?;
+    Ok(match &volume.partition {
+        Some(partition) => (
+            partition.offset(),
+            partition.offset() + partition.sectors * u64::from(BYTES_PER_SECTOR),
+        ),
+        None => (
+            0,
+            u64::from(volume.volume.total_sectors) * u64::from(volume.volume.bytes_per_sector),
+        ),
+    })
+}
+
+fn first_volume(report: &DiskReport) -> MkimgRes<&ImageReport> {
+    report
+        .volumes
+        .first()
+        .ok_or_else(|| MkimgError::validation("image contains no FAT volume"))
+}
+
+// Extract `entry` if it or one of its parents matched, then recurse
+fn extract_entry<T: Read + Seek>(
+    img_file: &mut T,
+    volume: &ImageReport,
+    entry: &EntryReport,
+    matcher: &GlobMatcher,
+    parent_matched: bool,
+    dest: &Path,
+    extracted: &mut Vec<PathBuf>,
+) -> MkimgRes {
+    let matched = parent_matched || matcher.is_match(&entry.path);
+    // Names come from the image and may have been crafted to escape
+    // `dest`
+    let mut host_path = dest.to_path_buf();
+    for part in entry.path.split('/') {
+        if matches!(part, "" | "." | "..") || part.contains('\\') {
+            return Err(MkimgError::invalid_path(
+                &entry.path,
+                "unsafe path component in image",
+            ));
+        }
+        host_path.push(part);
+    }
+    if entry.is_dir() {
+        if matched {
+            std::fs::create_dir_all(&host_path)
+                .map_err(|e| MkimgError::path_operation("create directory", &host_path, e))?;
+            extracted.push(host_path);
+        }
+        for child in &entry.children {
+            extract_entry(img_file, volume, child, matcher, matched, dest, extracted)?;
+        }
+    } else if matched {
+        if let Some(parent) = host_path.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| MkimgError::path_operation("create directory", parent, e))?;
+        }
+        let file = File::create(&host_path)
+            .map_err(|e| MkimgError::path_operation("create file", &host_path, e))?;
+        let mut out = BufWriter::new(file);
+        volume.copy_file(&mut *img_file, entry, &mut out)?;
+        out.flush()
+            .map_err(|e| MkimgError::path_operation("write file", &host_path, e))?;
+        extracted.push(host_path);
+    }
+    Ok(())
+}
+
+// Create filesystem with the given geometry and copy files
+fn write_fs<D: Disk>(
+    img_file: &mut D,
+    tree: &[FileMapping],
+    geometry: &Geometry,
+    placement: &Placement,
+    options: &CreateOptions,
+) -> MkimgRes {
+    let volume_id = options
+        .volume_id
+        .unwrap_or_else(|| options.timestamp.unwrap_or_else(Timestamp::now).serial());
+    let entry_metadata = entry_metadata(tree, options.preserve_times, options.preserve_attributes)?;
+    format_volume(img_file, geometry, placement, volume_id)?;
+    let volume = StreamSlice::new(
+        &mut *img_file,
+        placement.volume_offset(),
+        placement.volume_end(),
+    )?;
+    let fs = FileSystem::new(volume, FsOptions::new())?;
     let root_dir = fs.root_dir();
 
     // Copy files from the source directory
     for FileMapping {
-        ext: external_path,
+        src,
         int: internal_path,
+        ..
     } in tree
     {
-        // Skip directories - only process files
-        if external_path.is_dir() {
-            continue;
-        }
-
         let path_parts: Vec<_> = path_to_str_with_context(internal_path)?
             .split('/')
             .collect();
 
+        // Directories are created along with their parents, files
+        // only need their parents
+        let (dirs, filename) = match src.is_dir() {
+            true => (&path_parts[..], None),
+            false => (
+                &path_parts[..path_parts.len() - 1],
+                path_parts.last().filter(|last| !last.is_empty()),
+            ),
+        };
+
         // Create parent directories as needed
         let mut current_dir = &root_dir;
         let mut owned_dirs = Vec::new();
 
-        for part in &path_parts[..path_parts.len() - 1] {
+        for part in dirs {
             if !part.is_empty() {
                 match current_dir.open_dir(part) {
                     Ok(dir) => {
@@ -192,193 +856,336 @@ fn write_fs(img_file: &mut File, tree: &[FileMapping], fat_type: fatfs::FatType)
             }
         }
 
-        if let Some(filename) = path_parts.last().filter(|last| !last.is_empty()) {
-            let file_content = std::fs::read(external_path)?;
+        if let Some(filename) = filename {
             let mut file = current_dir.create_file(filename)?;
-            file.write_all(&file_content)?;
+            // Copied in bounded chunks, so large files are never held
+            // in memory
+            io::copy(&mut src.open()?, &mut file)?;
             file.flush()?;
         }
     }
 
     drop(root_dir);
     fs.unmount()?;
-    Ok(())
+    apply_metadata(
+        img_file,
+        placement.volume_offset(),
+        &entry_metadata,
+        options.timestamp,
+    )
 }
 
-fn examine_directory(parent_dir: &fatfs::Dir<'_, &File>, dir_name: &str, depth: usize) -> MkimgRes {
-    let indent = "  ".repeat(depth + 1);
-    if let Ok(subdir) = parent_dir.open_dir(dir_name) {
-        println!("{}Contents of {}:", indent, dir_name);
-        for entry in subdir.iter() {
-            let entry = entry?;
-            let name = entry.file_name();
-            let is_dir = entry.is_dir();
-            let size = if is_dir { 0 } else { entry.len() };
-            println!(
-                "{}  {} {} bytes {}",
-                indent,
-                name,
-                size,
-                if is_dir { "(DIR)" } else { "(FILE)" }
-            );
-
-            // Read file contents if it's a small file
-            if let Ok(mut file) = subdir.open_file(&name) {
-                if !is_dir && size <= 200000 {
-                    let mut contents = Vec::new();
-                    if file.read_to_end(&mut contents).is_ok() {
-                        if contents.iter().all(|&b| {
-                            b.is_ascii() && !b.is_ascii_control()
-                                || b == b'\n'
-                                || b == b'\r'
-                                || b == b'\t'
-                        }) {
-                            println!(
-                                "{}    Content: {:?}",
-                                indent,
-                                String::from_utf8_lossy(&contents)
-                            );
-                        } else {
-                            println!(
-                                "{}    Content: {} bytes of binary data",
-                                indent,
-                                contents.len()
-                            );
-                        }
-                    }
-                }
+// Collect the metadata for every mapping, keyed by image path. Host
+// metadata has to be read before the files are copied, as reading
+// them may update their access times
+fn entry_metadata(
+    tree: &[FileMapping],
+    preserve_times: bool,
+    preserve_attributes: bool,
+) -> MkimgRes<HashMap<String, EntryMeta>> {
+    let mut entries = HashMap::new();
+    for mapping in tree {
+        let mut meta = EntryMeta::default();
+        // Inline contents have no host metadata to preserve
+        let host_path = mapping
+            .src
+            .path()
+            .filter(|_| preserve_times || preserve_attributes);
+        if let Some(host_path) = host_path {
+            let metadata = std::fs::metadata(host_path)
+                .map_err(|e| MkimgError::path_operation("read metadata", host_path, e))?;
+            if preserve_times {
+                meta.modified = metadata.modified().ok().map(Timestamp::from_system_time);
+                meta.accessed = metadata.accessed().ok().map(Timestamp::from_system_time);
+                meta.created = metadata
+                    .created()
+                    .ok()
+                    .map(Timestamp::from_system_time)
+                    .or(meta.modified);
             }
-
-            // Recursively explore subdirectories
-            if is_dir && name != "." && name != ".." && depth < 5 {
-                examine_directory(&subdir, &name, depth + 1)?;
+            if preserve_attributes {
+                let read_only = metadata.is_file() && metadata.permissions().readonly();
+                let hidden = host_path
+                    .file_name()
+                    .is_some_and(|name| name.as_encoded_bytes().starts_with(b"."));
+                meta.read_only = read_only.then_some(true);
+                meta.hidden = hidden.then_some(true);
             }
         }
+        let meta = meta.merge(mapping.meta);
+        if meta != EntryMeta::default() {
+            entries.insert(
+                image_path_key(path_to_str_with_context(&mapping.int)?),
+                meta,
+            );
+        }
     }
-    Ok(())
+    Ok(entries)
 }
 
-/// Creates a deceptive FAT32 disk image that reports false size
-/// information.
-///
-/// Creates a 32MB FAT32 image, applies size deception to boot sector
-/// and FSInfo, then shrinks the file to actual content size while
-/// maintaining the deception.  The resulting image will report 1.5x
-/// its actual size to basic filesystem queries.
-///
-/// # Arguments
-///
-/// * `img_file` - Output file handle for the image
-/// * `file_mappings` - Vector of files to include in the image
-///
-/// # Errors
-///
-/// Returns error if filesystem operations fail
-pub fn create_deceptive_img(img_file: &mut File, file_mappings: &[FileMapping]) -> MkimgRes {
-    // 32MB real size to ensure FAT32
-    img_file.set_len(32 * 1024 * 1024)?;
-    write_fs(img_file, file_mappings, fatfs::FatType::Fat32)?;
-    apply_size_deception(img_file)?;
-    shrink_file_after_deception(img_file)?;
-    println!("Deceptive img created successfully!");
+// Rewrite the timestamps and attributes fatfs has no API for. fatfs
+// stamps entries, including the `.` and `..` entries of every
+// directory, with the current time and only sets DIRECTORY/ARCHIVE
+fn apply_metadata<D: Disk>(
+    img_file: &mut D,
+    volume_offset: u64,
+    entries: &HashMap<String, EntryMeta>,
+    timestamp: Option<Timestamp>,
+) -> MkimgRes {
+    if timestamp.is_none() && entries.is_empty() {
+        return Ok(());
+    }
+    // Checked before anything is written, as FAT only stores years
+    // 1980 to 2107
+    if let Some(timestamp) = timestamp {
+        timestamp.validate()?;
+    }
+    for (path, meta) in entries {
+        for time in [meta.created, meta.modified, meta.accessed]
+            .into_iter()
+            .flatten()
+        {
+            time.validate().map_err(|e| match e {
+                MkimgError::Validation(msg) => MkimgError::validation(format!("'{path}': {msg}")),
+                e => e,
+            })?;
+        }
+    }
+    let mut volume = fat::Volume::open(&mut *img_file, volume_offset)?;
+    for (path, mut entry) in volume.walk()? {
+        if let Some(timestamp) = timestamp {
+            entry.created = Some(timestamp);
+            entry.modified = Some(timestamp);
+            entry.accessed = Some(timestamp);
+        }
+        // A directory's `.` and `..` entries take its timestamps
+        let dir = path.strip_suffix("/.").or_else(|| path.strip_suffix("/.."));
+        let key = image_path_key(dir.unwrap_or(&path));
+        if let Some(meta) = entries.get(&key) {
+            meta.apply(&mut entry, dir.is_some());
+        }
+        volume.update_entry(&entry)?;
+    }
     Ok(())
 }
 
-fn apply_size_deception(img_file: &mut File) -> MkimgRes {
-    // Read the current boot sector
-    let mut boot_sector = [0u8; 512];
-    img_file.read_exact(&mut boot_sector)?;
-
-    // Modify the total sectors field at offset 0x20 (32-bit value)
-    // Use a more moderate deception: claim 4x the actual size
-    let current_sectors = u32::from_le_bytes([
-        boot_sector[0x20],
-        boot_sector[0x21],
-        boot_sector[0x22],
-        boot_sector[0x23],
-    ]);
-    let fake_sectors: u32 = current_sectors + (current_sectors / 2); // 1.5x deception for better compatibility
-    boot_sector[0x20..0x24].copy_from_slice(&fake_sectors.to_le_bytes());
-
-    // Write back the modified boot sector
-    img_file.seek(SeekFrom::Start(0))?;
-    img_file.write_all(&boot_sector)?;
-
-    // Also modify the FSInfo sector (usually at sector 1)
-    img_file.seek(SeekFrom::Start(512))?;
-    let mut fsinfo_sector = [0u8; 512];
-    img_file.read_exact(&mut fsinfo_sector)?;
-
-    // Check if this is actually an FSInfo sector (signature "RRaA" at offset 0)
-    if &fsinfo_sector[0x00..0x04] == b"RRaA" {
-        // Modify free cluster count at offset 0x1e8 to match our moderate deception
-        let current_free = u32::from_le_bytes([
-            fsinfo_sector[0x1e8],
-            fsinfo_sector[0x1e9],
-            fsinfo_sector[0x1ea],
-            fsinfo_sector[0x1eb],
-        ]);
-        let fake_free_clusters: u32 = if current_free != 0xFFFFFFFF {
-            current_free * 3
-        } else {
-            current_free
-        };
-        fsinfo_sector[0x1e8..0x1ec].copy_from_slice(&fake_free_clusters.to_le_bytes());
+// Image path without empty components
+fn image_path(path: &str) -> String {
+    path.split('/')
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("/")
+}
 
-        // Write back the modified FSInfo sector
-        img_file.seek(SeekFrom::Start(512))?;
-        img_file.write_all(&fsinfo_sector)?;
+// Normalized image path used to match mappings against volume entries
+fn image_path_key(path: &str) -> String {
+    image_path(path).to_lowercase()
+}
+
+// Whether each mapped path, and each directory holding one, is a
+// directory, keyed by image path
+fn wanted_entries(file_mappings: &[FileMapping]) -> MkimgRes<HashMap<String, bool>> {
+    let mut wanted = HashMap::new();
+    for mapping in file_mappings {
+        let key = image_path_key(path_to_str_with_context(&mapping.int)?);
+        let mut parent = key.as_str();
+        while let Some((dir, _)) = parent.rsplit_once('/') {
+            wanted.insert(dir.to_string(), true);
+            parent = dir;
+        }
+        wanted.entry(key).or_insert(mapping.src.is_dir());
     }
+    Ok(wanted)
+}
 
-    img_file.flush()?;
-    println!("Applied size deception - img now claims to be 1.5x actual size");
-    Ok(())
+// How the file `entry` differs from what `src` provides, if at all
+fn compare_contents<T: Read + Seek>(
+    img_file: T,
+    volume: &ImageReport,
+    entry: &EntryReport,
+    src: &Source,
+) -> MkimgRes<Option<Difference>> {
+    let expected = src.size()?;
+    let size = u64::from(entry.size);
+    if expected != size {
+        return Ok(Some(Difference::Size {
+            expected,
+            found: size,
+        }));
+    }
+    let mut compare = Compare {
+        src: src.open()?,
+        buf: Vec::new(),
+        equal: true,
+    };
+    let base = volume
+        .partition
+        .as_ref()
+        .map_or(0, partition::Partition::offset);
+    let mut fat_volume = fat::Volume::open(img_file, base)?;
+    let copied = match fat_volume.copy_chain(&entry.clusters, size, &mut compare) {
+        Ok(copied) => copied,
+        // Clusters past the end of a shrunk image
+        Err(MkimgError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => 0,
+        Err(e) => return Err(e),
+    };
+    Ok(match (copied == size, compare.equal) {
+        (false, _) => Some(Difference::Truncated),
+        (true, false) => Some(Difference::Contents),
+        (true, true) => None,
+    })
 }
 
-fn shrink_file_after_deception(img_file: &mut File) -> MkimgRes {
-    // Find the last non-zero byte to determine minimum file size
-    // Start from a reasonable minimum (like 512KB) and extend as needed
-    let min_size = 512 * 1024; // 512KB minimum
-    let mut actual_size = min_size;
-    let mut content = Vec::with_capacity(img_file.metadata()?.len() as usize);
-    img_file.read_to_end(&mut content)?;
-    // Look for actual data beyond the minimum
-    for i in (min_size..content.len()).rev() {
-        if content[i] != 0 {
-            actual_size = ((i / 512) + 1) * 512; // Round up to next sector
-            break;
+/// Compares everything written to it against a reader.
+struct Compare<R> {
+    src: R,
+    buf: Vec<u8>,
+    equal: bool,
+}
+
+impl<R: Read> Write for Compare<R> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.equal {
+            self.buf.resize(data.len(), 0);
+            match self.src.read_exact(&mut self.buf) {
+                Ok(()) => self.equal = self.buf == data,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => self.equal = false,
+                Err(e) => return Err(e),
+            }
         }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
     }
-    img_file.set_len(actual_size as u64)?;
-    img_file.flush()?;
-    println!(
-        "Shrunk file to {} bytes while maintaining deception",
-        actual_size
-    );
-    Ok(())
 }
 
-/// Returns `(total size, [(external src, internal path), ..])`
-fn reroot_tree(canon_root: &Path, walkdir: WalkDir) -> MkimgRes<Vec<FileMapping>> {
-    let mut out = Vec::new();
-    for entry in walkdir {
-        let entry = entry?;
-        let len = entry.metadata().map(|m| m.len()).unwrap_or(0);
-        let entry_path_buf = entry.path().to_path_buf();
-        let rerooted_path = reroot_path(canon_root, &entry_path_buf)?;
-        println!("{rerooted_path:?} {entry_path_buf:?} {len}");
-        if rerooted_path != Path::new("") {
-            out.push(FileMapping {
-                ext: entry_path_buf,
-                int: rerooted_path,
-            });
+// Format the volume, placing any reserved sectors beyond the fixed
+// amount fatfs uses in front of the volume fatfs formats
+fn format_volume<D: Disk>(
+    img_file: &mut D,
+    geometry: &Geometry,
+    placement: &Placement,
+    volume_id: u32,
+) -> MkimgRes {
+    let sector = u64::from(BYTES_PER_SECTOR);
+    let extra = u64::from(geometry.extra_reserved_sectors());
+    let fatfs_sectors = geometry.total_sectors - extra as u32;
+    let volume_offset = placement.volume_offset();
+    {
+        let volume = StreamSlice::new(
+            &mut *img_file,
+            volume_offset + extra * sector,
+            placement.volume_end(),
+        )?;
+        fatfs::format_volume(
+            volume,
+            FormatVolumeOptions::new()
+                .fat_type(geometry.fat_type)
+                .bytes_per_cluster(geometry.bytes_per_cluster)
+                .total_sectors(fatfs_sectors)
+                .fats(geometry.fats)
+                .max_root_dir_entries(geometry.root_entries)
+                .volume_id(volume_id),
+        )?;
+    }
+    if extra == 0 && placement.volume_start == 0 {
+        return Ok(());
+    }
+
+    // fatfs wrote its reserved sectors (boot sector, plus FSInfo and
+    // backup boot sector for FAT32) at the start of its slice
+    let fatfs_reserved = u64::from(geometry.reserved_sectors) - extra;
+    let mut reserved = vec![0u8; (fatfs_reserved * sector) as usize];
+    img_file.seek(SeekFrom::Start(volume_offset + extra * sector))?;
+    img_file.read_exact(&mut reserved)?;
+    img_file.seek(SeekFrom::Start(volume_offset))?;
+    img_file.write_all(&vec![
+        0u8;
+        (u64::from(geometry.reserved_sectors) * sector)
+            as usize
+    ])?;
+
+    let boot_sector = &mut reserved[..BYTES_PER_SECTOR as usize];
+    boot_sector[0x0e..0x10].copy_from_slice(&geometry.reserved_sectors.to_le_bytes());
+    match u16::try_from(geometry.total_sectors) {
+        Ok(total_sectors) if boot_sector[0x13..0x15] != [0, 0] => {
+            boot_sector[0x13..0x15].copy_from_slice(&total_sectors.to_le_bytes())
+        }
+        _ => {
+            boot_sector[0x13..0x15].fill(0);
+            boot_sector[0x20..0x24].copy_from_slice(&geometry.total_sectors.to_le_bytes());
         }
     }
-    Ok(out)
+    let hidden_sectors = u32::try_from(placement.volume_start)
+        .map_err(|_| MkimgError::validation("partition starts beyond 2TiB"))?;
+    boot_sector[0x1c..0x20].copy_from_slice(&hidden_sectors.to_le_bytes());
+    let boot_sector = boot_sector.to_vec();
+    if geometry.fat_type == FatType::Fat32 {
+        // The backup boot sector must match the patched primary one
+        let backup = 6 * BYTES_PER_SECTOR as usize;
+        reserved[backup..backup + boot_sector.len()].copy_from_slice(&boot_sector);
+    }
+    img_file.seek(SeekFrom::Start(volume_offset))?;
+    img_file.write_all(&reserved)?;
+    img_file.flush()?;
+    Ok(())
 }
 
-fn reroot_path(canon_root: &Path, target: &Path) -> MkimgRes<PathBuf> {
-    let canon_target = canonicalize_with_context(target)?;
-    let rerooted_target = strip_prefix_with_context(&canon_target, canon_root)?.to_path_buf();
-    Ok(rerooted_target)
+/// Creates a deceptive FAT32 disk image that reports false size
+/// information.
+///
+/// Creates a FAT32 image sized to fit its contents (at least ~32MB,
+/// the FAT32 minimum), applies size deception to boot sector and
+/// FSInfo, then shrinks the file to actual content size while
+/// maintaining the deception.  By default the resulting image will
+/// report 1.5x its actual size to basic filesystem queries; see
+/// [`CreateOptions::deception`].
+///
+/// # Arguments
+///
+/// * `img_file` - Output storage for the image, e.g. a `File` or a
+///   `Cursor<Vec<u8>>`
+/// * `file_mappings` - Vector of files to include in the image
+/// * `options` - Volume size and geometry; the FAT type must be FAT32
+///   or unset
+///
+/// # Returns
+///
+/// A description of the deception and of the shrink step
+///
+/// # Errors
+///
+/// Returns error if the options are invalid, the contents do not fit
+/// or filesystem operations fail
+pub fn create_deceptive_img<D: Disk>(
+    img_file: &mut D,
+    file_mappings: &[FileMapping],
+    options: &CreateOptions,
+) -> MkimgRes<Vec<String>> {
+    if options
+        .fat_type
+        .is_some_and(|fat_type| fat_type != FatType::Fat32)
+    {
+        return Err(MkimgError::validation("deceptive imgs must be FAT32"));
+    }
+    if !matches!(options.layout, Layout::Superfloppy) {
+        return Err(MkimgError::validation(
+            "deceptive imgs cannot have a partition table",
+        ));
+    }
+    let options = CreateOptions {
+        fat_type: Some(FatType::Fat32),
+        ..options.clone()
+    };
+    let geometry = size::compute_geometry(file_mappings, &options)?;
+    let placement = partition::place(&options.layout, geometry.total_sectors)?;
+    img_file.set_len(placement.disk_bytes())?;
+    write_fs(img_file, file_mappings, &geometry, &placement, &options)?;
+    let deception = SizeDeception(options.deception.unwrap_or_default());
+    Ok(vec![
+        deception.apply(img_file, placement.volume_offset())?,
+        Shrink.apply(img_file, placement.volume_offset())?,
+    ])
 }
@@ -0,0 +1,607 @@
+//! Declarative image recipes.
+//!
+//! A manifest is a TOML file describing the files, directories,
+//! attributes, volume options and disk layout of an image, so that
+//! recipes can live in version control instead of long command lines:
+//!
+//! ```toml
+//! output = "esp.img"
+//!
+//! [volume]
+//! fat_type = 32
+//! cluster_size = "4K"
+//! timestamp = 2024-01-01T00:00:00
+//!
+//! [layout]
+//! type = "gpt"
+//! align = "1M"
+//!
+//! [[file]]
+//! path = "EFI/BOOT/BOOTX64.EFI"
+//! source = "build/loader.efi"
+//! system = true
+//!
+//! [[file]]
+//! path = "startup.nsh"
+//! content = "fs0:\\EFI\\BOOT\\BOOTX64.EFI\n"
+//!
+//! [[file]]
+//! path = "filler.bin"
+//! size = "100M"
+//! fill = 0xff
+//!
+//! [[dir]]
+//! path = "EFI/vendor"
+//! source = "build/vendor"
+//! exclude = ["*.o", "*.d"]
+//! hidden = true
+//!
+//! [[dir]]
+//! path = "EFI/Vendor"
+//! ```
+//!
+//! A `[[file]]` takes its contents from a host `source`, inline
+//! `content`, or `size` bytes of a repeated `fill` pattern (zeros by
+//! default) or of random data generated from a `seed`. A `[[dir]]`
+//! with a `source` maps a host directory tree, one without
+//! creates an empty directory. Relative paths are resolved against the
+//! directory holding the manifest.
+//!
+//! The image is a plain one unless the manifest sets `deceptive = true`
+//! at the top level, which needs a FAT32 volume without a partition
+//! table.
+
+use crate::{
+    create_mappings,
+    error::{MkimgError, MkimgRes},
+    fat::Timestamp,
+    options::{
+        CreateOptions, GptOptions, Layout, MbrOptions, ScanOptions, SymlinkPolicy, VolumeSize,
+        DEFAULT_ALIGNMENT,
+    },
+    size::{parse_size, DEFAULT_SLACK},
+    EntryMeta, FatType, FileMapping, Source,
+};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use toml::value::{Datetime, Offset};
+use uuid::Uuid;
+
+/// An image recipe parsed from a manifest.
+pub struct Manifest {
+    /// Where to write the image, if the manifest names a path.
+    pub output: Option<PathBuf>,
+    /// Build a deceptive image with
+    /// [`create_deceptive_img`](crate::create_deceptive_img) instead of
+    /// a plain one.
+    pub deceptive: bool,
+    /// Files and directories to place in the image.
+    pub mappings: Vec<FileMapping>,
+    /// Volume and layout options.
+    pub options: CreateOptions,
+}
+
+impl Manifest {
+    /// Reads and parses the manifest at `path`.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Manifest file; relative paths inside it are resolved
+    ///   against its directory
+    ///
+    /// # Errors
+    ///
+    /// Returns error if the file cannot be read, is not a valid
+    /// manifest, or a source directory cannot be scanned
+    pub fn load(path: &Path) -> MkimgRes<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| MkimgError::path_operation("read manifest", path, e))?;
+        let base = path.parent().unwrap_or(Path::new(""));
+        Self::parse(&text, base)
+    }
+
+    /// Parses a manifest.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - Manifest contents in TOML
+    /// * `base` - Directory relative paths are resolved against
+    ///
+    /// # Errors
+    ///
+    /// Returns error if `text` is not a valid manifest or a source
+    /// directory cannot be scanned
+    pub fn parse(text: &str, base: &Path) -> MkimgRes<Self> {
+        let raw: RawManifest = toml::from_str(text)
+            .map_err(|e| MkimgError::validation(format!("invalid manifest: {e}")))?;
+        let mut mappings = Vec::new();
+        for file in &raw.files {
+            let src = match (&file.source, &file.content, &file.size) {
+                (Some(source), None, None) => Source::Path(base.join(source)),
+                (None, Some(content), None) => Source::Inline(content.clone().into_bytes()),
+                (None, None, Some(size)) => file.generated(size.bytes()?)?,
+                _ => {
+                    return Err(MkimgError::validation(format!(
+                        "file '{}' needs exactly one of 'source', 'content' and 'size'",
+                        file.path
+                    )))
+                }
+            };
+            if file.size.is_none() && (file.fill.is_some() || file.seed.is_some()) {
+                return Err(MkimgError::validation(format!(
+                    "file '{}' needs a 'size' for 'fill' or 'seed'",
+                    file.path
+                )));
+            }
+            if file.scan_options().is_some() {
+                return Err(MkimgError::validation(format!(
+                    "file '{}' has options that only apply to directories",
+                    file.path
+                )));
+            }
+            mappings.push(FileMapping {
+                meta: file.meta()?,
+                ..FileMapping::new(src, image_path(&file.path))
+            });
+        }
+        for dir in &raw.dirs {
+            if dir.content.is_some()
+                || dir.size.is_some()
+                || dir.fill.is_some()
+                || dir.seed.is_some()
+            {
+                return Err(MkimgError::validation(format!(
+                    "directory '{}' cannot have 'content', 'size', 'fill' or 'seed'",
+                    dir.path
+                )));
+            }
+            let prefix = image_path(&dir.path);
+            let Some(source) = &dir.source else {
+                if dir.scan_options().is_some() {
+                    return Err(MkimgError::validation(format!(
+                        "directory '{}' has scan options but no 'source'",
+                        dir.path
+                    )));
+                }
+                mappings.push(FileMapping {
+                    meta: dir.meta()?,
+                    ..FileMapping::new(Source::Directory, prefix)
+                });
+                continue;
+            };
+            let source = base.join(source);
+            if prefix != Path::new("") {
+                mappings.push(FileMapping {
+                    meta: dir.meta()?,
+                    ..FileMapping::new(&source, &prefix)
+                });
+            }
+            let scan_options = dir.scan_options().unwrap_or_default();
+            for mapping in create_mappings(&source, true, &scan_options)? {
+                mappings.push(FileMapping {
+                    int: prefix.join(&mapping.int),
+                    ..mapping
+                });
+            }
+        }
+        Ok(Self {
+            output: raw.output.map(|output| base.join(output)),
+            deceptive: raw.deceptive,
+            mappings,
+            options: CreateOptions {
+                layout: raw.layout.layout()?,
+                ..raw.volume.options()?
+            },
+        })
+    }
+}
+
+fn image_path(path: &str) -> PathBuf {
+    PathBuf::from(path.trim_matches('/'))
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawManifest {
+    output: Option<PathBuf>,
+    #[serde(default)]
+    deceptive: bool,
+    #[serde(default)]
+    volume: RawVolume,
+    #[serde(default)]
+    layout: RawLayout,
+    #[serde(default, rename = "file")]
+    files: Vec<RawEntry>,
+    #[serde(default, rename = "dir")]
+    dirs: Vec<RawEntry>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawVolume {
+    size: Option<RawSize>,
+    sectors: Option<u32>,
+    slack: Option<RawSize>,
+    fat_type: Option<RawFatType>,
+    cluster_size: Option<RawSize>,
+    reserved_sectors: Option<u16>,
+    fats: Option<u8>,
+    root_entries: Option<u16>,
+    timestamp: Option<RawTimestamp>,
+    volume_id: Option<u32>,
+    #[serde(default)]
+    preserve_times: bool,
+    #[serde(default)]
+    preserve_attributes: bool,
+}
+
+impl RawVolume {
+    fn options(&self) -> MkimgRes<CreateOptions> {
+        let size = match (&self.size, self.sectors) {
+            (Some(_), Some(_)) => {
+                return Err(MkimgError::validation(
+                    "volume 'size' and 'sectors' are mutually exclusive",
+                ))
+            }
+            (Some(size), None) => Some(VolumeSize::Bytes(size.bytes()?)),
+            (None, sectors) => sectors.map(VolumeSize::Sectors),
+        };
+        let bytes_per_cluster = self
+            .cluster_size
+            .as_ref()
+            .map(|size| {
+                u32::try_from(size.bytes()?)
+                    .map_err(|_| MkimgError::validation("cluster size is too large"))
+            })
+            .transpose()?;
+        Ok(CreateOptions {
+            size,
+            slack: match &self.slack {
+                Some(slack) => slack.bytes()?,
+                None => DEFAULT_SLACK,
+            },
+            fat_type: match &self.fat_type {
+                Some(fat_type) => fat_type.fat_type()?,
+                None => None,
+            },
+            bytes_per_cluster,
+            reserved_sectors: self.reserved_sectors,
+            fats: self.fats,
+            root_entries: self.root_entries,
+            layout: Layout::Superfloppy,
+            timestamp: self
+                .timestamp
+                .as_ref()
+                .map(RawTimestamp::timestamp)
+                .transpose()?,
+            volume_id: self.volume_id,
+            preserve_times: self.preserve_times,
+            preserve_attributes: self.preserve_attributes,
+            deception: None,
+        })
+    }
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawLayout {
+    #[serde(default, rename = "type")]
+    kind: LayoutKind,
+    disk_guid: Option<Uuid>,
+    partition_guid: Option<Uuid>,
+    align: Option<RawSize>,
+    partition_type: Option<u8>,
+    #[serde(default)]
+    active: bool,
+    disk_signature: Option<u32>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum LayoutKind {
+    #[default]
+    Superfloppy,
+    Gpt,
+    Mbr,
+}
+
+impl RawLayout {
+    fn layout(&self) -> MkimgRes<Layout> {
+        let gpt_only = self.disk_guid.is_some() || self.partition_guid.is_some();
+        let mbr_only =
+            self.partition_type.is_some() || self.active || self.disk_signature.is_some();
+        let alignment = match &self.align {
+            Some(align) => align.bytes()?,
+            None => DEFAULT_ALIGNMENT,
+        };
+        match self.kind {
+            LayoutKind::Superfloppy if gpt_only || mbr_only || self.align.is_some() => Err(
+                MkimgError::validation("superfloppy layouts have no partition options"),
+            ),
+            LayoutKind::Gpt if mbr_only => Err(MkimgError::validation(
+                "'partition_type', 'active' and 'disk_signature' only apply to MBR layouts",
+            )),
+            LayoutKind::Mbr if gpt_only => Err(MkimgError::validation(
+                "'disk_guid' and 'partition_guid' only apply to GPT layouts",
+            )),
+            LayoutKind::Superfloppy => Ok(Layout::Superfloppy),
+            LayoutKind::Gpt => Ok(Layout::Gpt(GptOptions {
+                disk_guid: self.disk_guid,
+                partition_guid: self.partition_guid,
+                alignment,
+            })),
+            LayoutKind::Mbr => Ok(Layout::Mbr(MbrOptions {
+                partition_type: self.partition_type,
+                active: self.active,
+                disk_signature: self.disk_signature,
+                alignment,
+            })),
+        }
+    }
+}
+
+/// A `[[file]]` or `[[dir]]` table.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawEntry {
+    path: String,
+    source: Option<PathBuf>,
+    content: Option<String>,
+    size: Option<RawSize>,
+    fill: Option<RawPattern>,
+    seed: Option<u64>,
+    read_only: Option<bool>,
+    hidden: Option<bool>,
+    system: Option<bool>,
+    archive: Option<bool>,
+    created: Option<RawTimestamp>,
+    modified: Option<RawTimestamp>,
+    accessed: Option<RawTimestamp>,
+    include: Option<Vec<String>>,
+    exclude: Option<Vec<String>>,
+    ignore_file: Option<bool>,
+    max_depth: Option<usize>,
+    symlinks: Option<RawSymlinks>,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum RawSymlinks {
+    Follow,
+    Skip,
+    Error,
+}
+
+impl RawEntry {
+    /// Generated contents of a `[[file]]` with a `size`.
+    fn generated(&self, len: u64) -> MkimgRes<Source> {
+        match (&self.fill, self.seed) {
+            (Some(_), Some(_)) => Err(MkimgError::validation(format!(
+                "file '{}' cannot have both 'fill' and 'seed'",
+                self.path
+            ))),
+            (None, Some(seed)) => Ok(Source::Random { len, seed }),
+            (fill, None) => Ok(Source::Fill {
+                len,
+                pattern: fill.as_ref().map(RawPattern::bytes).unwrap_or_default(),
+            }),
+        }
+    }
+
+    /// Scan options of a `[[dir]]`, or `None` if none are given.
+    fn scan_options(&self) -> Option<ScanOptions> {
+        if self.include.is_none()
+            && self.exclude.is_none()
+            && self.ignore_file.is_none()
+            && self.max_depth.is_none()
+            && self.symlinks.is_none()
+        {
+            return None;
+        }
+        let defaults = ScanOptions::default();
+        Some(ScanOptions {
+            include: self.include.clone().unwrap_or_default(),
+            exclude: self.exclude.clone().unwrap_or_default(),
+            ignore_file: self.ignore_file.unwrap_or(defaults.ignore_file),
+            max_depth: self.max_depth,
+            symlinks: match self.symlinks {
+                Some(RawSymlinks::Follow) => SymlinkPolicy::Follow,
+                Some(RawSymlinks::Skip) => SymlinkPolicy::Skip,
+                Some(RawSymlinks::Error) => SymlinkPolicy::Error,
+                None => defaults.symlinks,
+            },
+        })
+    }
+
+    fn meta(&self) -> MkimgRes<EntryMeta> {
+        let timestamp =
+            |raw: &Option<RawTimestamp>| raw.as_ref().map(RawTimestamp::timestamp).transpose();
+        Ok(EntryMeta {
+            read_only: self.read_only,
+            hidden: self.hidden,
+            system: self.system,
+            archive: self.archive,
+            created: timestamp(&self.created)?,
+            modified: timestamp(&self.modified)?,
+            accessed: timestamp(&self.accessed)?,
+        })
+    }
+}
+
+/// A byte count, either as an integer or a string with a K, M or G
+/// suffix.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawSize {
+    Bytes(u64),
+    Text(String),
+}
+
+impl RawSize {
+    fn bytes(&self) -> MkimgRes<u64> {
+        match self {
+            RawSize::Bytes(bytes) => Ok(*bytes),
+            RawSize::Text(text) => parse_size(text),
+        }
+    }
+}
+
+/// A fill pattern, either a single byte or an array of bytes.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawPattern {
+    Byte(u8),
+    Bytes(Vec<u8>),
+}
+
+impl RawPattern {
+    fn bytes(&self) -> Vec<u8> {
+        match self {
+            RawPattern::Byte(byte) => vec![*byte],
+            RawPattern::Bytes(bytes) => bytes.clone(),
+        }
+    }
+}
+
+/// A FAT type as `12`, `16`, `32` or `"auto"`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawFatType {
+    Bits(u8),
+    Text(String),
+}
+
+impl RawFatType {
+    fn fat_type(&self) -> MkimgRes<Option<FatType>> {
+        let bits = match self {
+            RawFatType::Bits(bits) => bits.to_string(),
+            RawFatType::Text(text) => text.to_lowercase(),
+        };
+        match bits.trim_start_matches("fat") {
+            "auto" => Ok(None),
+            "12" => Ok(Some(FatType::Fat12)),
+            "16" => Ok(Some(FatType::Fat16)),
+            "32" => Ok(Some(FatType::Fat32)),
+            _ => Err(MkimgError::validation(format!(
+                "invalid FAT type '{bits}', expected 12, 16, 32 or \"auto\""
+            ))),
+        }
+    }
+}
+
+/// A timestamp as Unix seconds, a TOML date-time or an ISO 8601
+/// string. Times are UTC; a TOML date-time may only carry a zero
+/// offset such as `Z`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawTimestamp {
+    Unix(i64),
+    Datetime(Datetime),
+    Text(String),
+}
+
+impl RawTimestamp {
+    fn timestamp(&self) -> MkimgRes<Timestamp> {
+        match self {
+            RawTimestamp::Unix(secs) => Ok(Timestamp::from_unix(*secs)),
+            RawTimestamp::Datetime(datetime) => datetime_timestamp(datetime),
+            RawTimestamp::Text(text) => text.parse(),
+        }
+    }
+}
+
+// Fractions beyond milliseconds are dropped
+fn datetime_timestamp(datetime: &Datetime) -> MkimgRes<Timestamp> {
+    let Some(date) = datetime.date else {
+        return Err(MkimgError::validation(format!(
+            "timestamp {datetime} has no date"
+        )));
+    };
+    if let Some(Offset::Custom { minutes }) = datetime.offset {
+        if minutes != 0 {
+            return Err(MkimgError::validation(format!(
+                "timestamp {datetime} is not UTC, use a time without an offset or with Z"
+            )));
+        }
+    }
+    let time = datetime.time;
+    Timestamp {
+        year: date.year,
+        month: date.month,
+        day: date.day,
+        hour: time.map_or(0, |time| time.hour),
+        minute: time.map_or(0, |time| time.minute),
+        second: time.and_then(|time| time.second).unwrap_or(0),
+        millis: time
+            .and_then(|time| time.nanosecond)
+            .map_or(0, |nanos| (nanos / 1_000_000) as u16),
+    }
+    .validate()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> MkimgRes<Manifest> {
+        Manifest::parse(text, Path::new(""))
+    }
+
+    fn timestamp(value: &str) -> MkimgRes<Option<Timestamp>> {
+        parse(&format!("[volume]\ntimestamp = {value}\n"))
+            .map(|manifest| manifest.options.timestamp)
+    }
+
+    fn utc(text: &str) -> Option<Timestamp> {
+        Some(text.parse().unwrap())
+    }
+
+    #[test]
+    fn images_are_plain_unless_deceptive_is_set() {
+        assert!(!parse("").unwrap().deceptive);
+        assert!(!parse("deceptive = false").unwrap().deceptive);
+        assert!(parse("deceptive = true").unwrap().deceptive);
+    }
+
+    #[test]
+    fn timestamp_forms() {
+        assert_eq!(timestamp("1704067200").unwrap(), utc("2024-01-01"));
+        assert_eq!(
+            timestamp("2024-01-01T12:34:56.789").unwrap(),
+            utc("2024-01-01T12:34:56.789")
+        );
+        assert_eq!(timestamp("2024-01-01").unwrap(), utc("2024-01-01"));
+        assert_eq!(
+            timestamp("\"2024-01-01T12:34:56\"").unwrap(),
+            utc("2024-01-01T12:34:56")
+        );
+    }
+
+    #[test]
+    fn datetime_fractions_are_cut_to_millis() {
+        assert_eq!(
+            timestamp("2024-01-01T12:34:56.123456789").unwrap(),
+            utc("2024-01-01T12:34:56.123")
+        );
+    }
+
+    #[test]
+    fn datetime_accepts_only_zero_offsets() {
+        assert_eq!(
+            timestamp("2024-01-01T00:00:00Z").unwrap(),
+            utc("2024-01-01")
+        );
+        assert_eq!(
+            timestamp("2024-01-01T00:00:00+00:00").unwrap(),
+            utc("2024-01-01")
+        );
+        assert!(timestamp("2024-01-01T00:00:00+02:00").is_err());
+        assert!(timestamp("2024-01-01T00:00:00-05:30").is_err());
+    }
+
+    #[test]
+    fn datetime_needs_a_date_in_fat_range() {
+        assert!(timestamp("12:00:00").is_err());
+        assert!(timestamp("1979-12-31T23:59:59").is_err());
+    }
+}
@@ -0,0 +1,783 @@
+//! Changes applied to finished images, mostly to make them malformed.
+//!
+//! A [`Mutation`] rewrites part of an image after it has been built,
+//! e.g. to test how FAT drivers cope with volumes that lie about
+//! their size. Mutations are looked up by name in a [`Registry`], so
+//! they can be selected with `--mutate name[:params]` on the command
+//! line; library users can register their own next to the built-in
+//! ones.
+
+use crate::{
+    disk::Disk,
+    error::{MkimgError, MkimgRes},
+    fat::{DirEntry, RawEntry, Volume, DELETED_MARKER},
+    image_path_key,
+    options::{parse_number, DeceptionOptions, Lie},
+};
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    io::{Read, Seek, SeekFrom},
+    ops::Range,
+    str::FromStr,
+};
+
+mod chain;
+mod entry;
+mod lfn;
+
+pub use chain::{BadLink, CrossLink, Cycle, DivergeFat, Link, TruncateChain};
+pub use entry::{BadShortName, DeleteEntry, DirLoop, DotLink, NoEndMarker, Oversize};
+pub use lfn::{LfnChecksum, LfnGap, LfnOrphan, LfnSurrogate, LfnSwap, LfnTooLong, MAX_LFN_UNITS};
+
+/// A change to the FAT volume of a finished image.
+pub trait Mutation {
+    /// Applies the change to the volume starting `volume_offset` bytes
+    /// into `img`.
+    ///
+    /// # Returns
+    ///
+    /// A one line description of what was changed
+    ///
+    /// # Errors
+    ///
+    /// Returns error if the image cannot be read or written, or does
+    /// not have the structure the mutation needs
+    fn apply(&self, img: &mut dyn Disk, volume_offset: u64) -> MkimgRes<String>;
+}
+
+/// Builds a mutation from the parameters following the name in a
+/// `name:params` spec, or an empty string if there are none.
+pub type Factory = Box<dyn Fn(&str) -> MkimgRes<Box<dyn Mutation>>>;
+
+/// Named mutations, selectable with `name[:params]` specs.
+pub struct Registry {
+    mutations: BTreeMap<String, (String, Factory)>,
+}
+
+impl Registry {
+    /// A registry holding the built-in mutations.
+    pub fn new() -> Self {
+        let mut registry = Self {
+            mutations: BTreeMap::new(),
+        };
+        registry.register(
+            "deceive",
+            "Misreport the volume size. Params: sectors, free and next, each \
+             a factor (1.5x), a count or keep (default sectors=1.5x,free=3x)",
+            |params| {
+                let mut params = Params::parse(params)?;
+                let defaults = DeceptionOptions::default();
+                let deception = DeceptionOptions {
+                    total_sectors: params.take("sectors")?.unwrap_or(defaults.total_sectors),
+                    free_clusters: params.take("free")?.unwrap_or(defaults.free_clusters),
+                    next_free: params.take("next")?.unwrap_or(defaults.next_free),
+                };
+                params.finish()?;
+                Ok(Box::new(SizeDeception(deception)))
+            },
+        );
+        registry.register(
+            "shrink",
+            "Cut the image file after its last sector holding data",
+            |params| {
+                Params::parse(params)?.finish()?;
+                Ok(Box::new(Shrink))
+            },
+        );
+        registry.register(
+            "crosslink",
+            "Make the chain of one file run into another's. Params: file and \
+             target, image paths (default the first two files)",
+            |params| {
+                let mut params = Params::parse(params)?;
+                let mutation = CrossLink {
+                    target: params.take("target")?,
+                    file: params.take("file")?,
+                };
+                params.finish()?;
+                Ok(Box::new(mutation))
+            },
+        );
+        registry.register(
+            "cycle",
+            "Point the last cluster of a file back into its chain. Params: \
+             file, to (chain index, default 0)",
+            |params| {
+                let mut params = Params::parse(params)?;
+                let mutation = Cycle {
+                    file: params.take("file")?,
+                    to: params.take("to")?.unwrap_or_default(),
+                };
+                params.finish()?;
+                Ok(Box::new(mutation))
+            },
+        );
+        registry.register(
+            "badlink",
+            "Point a cluster of a file at an invalid one. Params: file, at \
+             (chain index, default 0), to (free, reserved, bad, range or a \
+             value; default range)",
+            |params| {
+                let mut params = Params::parse(params)?;
+                let defaults = BadLink::default();
+                let mutation = BadLink {
+                    file: params.take("file")?,
+                    at: params.take("at")?.unwrap_or(defaults.at),
+                    to: params.take("to")?.unwrap_or(defaults.to),
+                };
+                params.finish()?;
+                Ok(Box::new(mutation))
+            },
+        );
+        registry.register(
+            "truncate",
+            "End the chain of a file before its size is reached. Params: \
+             file, clusters (to keep, default one less)",
+            |params| {
+                let mut params = Params::parse(params)?;
+                let mutation = TruncateChain {
+                    file: params.take("file")?,
+                    clusters: params.take("clusters")?,
+                };
+                params.finish()?;
+                Ok(Box::new(mutation))
+            },
+        );
+        registry.register(
+            "diverge",
+            "Change a cluster of a file in one FAT copy only. Params: file, \
+             at (default 0), copy (default 1), to (as for badlink, default \
+             free)",
+            |params| {
+                let mut params = Params::parse(params)?;
+                let defaults = DivergeFat::default();
+                let mutation = DivergeFat {
+                    file: params.take("file")?,
+                    at: params.take("at")?.unwrap_or(defaults.at),
+                    copy: params.take("copy")?.unwrap_or(defaults.copy),
+                    to: params.take("to")?.unwrap_or(defaults.to),
+                };
+                params.finish()?;
+                Ok(Box::new(mutation))
+            },
+        );
+        registry.register(
+            "oversize",
+            "Make the size of a file exceed its chain. Params: file, size \
+             (default one cluster more than the chain holds)",
+            |params| {
+                let mut params = Params::parse(params)?;
+                let mutation = Oversize {
+                    file: params.take("file")?,
+                    size: params.take::<Number<u32>>("size")?.map(|n| n.0),
+                };
+                params.finish()?;
+                Ok(Box::new(mutation))
+            },
+        );
+        registry.register(
+            "dotlink",
+            "Point the . or .. entry of a directory at the wrong cluster. \
+             Params: dir, entry (. or .., default ..), to (default the \
+             directory itself for .., its parent for .)",
+            |params| {
+                let mut params = Params::parse(params)?;
+                let dir = params.take("dir")?;
+                let parent = match params.take::<String>("entry")?.as_deref() {
+                    None | Some("..") => true,
+                    Some(".") => false,
+                    Some(entry) => {
+                        return Err(MkimgError::validation(format!(
+                            "entry: expected . or .., got '{entry}'"
+                        )))
+                    }
+                };
+                let mutation = DotLink {
+                    dir,
+                    parent,
+                    to: params.take::<Number<u32>>("to")?.map(|n| n.0),
+                };
+                params.finish()?;
+                Ok(Box::new(mutation))
+            },
+        );
+        registry.register(
+            "dirloop",
+            "Point a subdirectory at an ancestor. Params: dir, up (levels, \
+             default 1)",
+            |params| {
+                let mut params = Params::parse(params)?;
+                let defaults = DirLoop::default();
+                let mutation = DirLoop {
+                    dir: params.take("dir")?,
+                    up: params.take("up")?.unwrap_or(defaults.up),
+                };
+                params.finish()?;
+                Ok(Box::new(mutation))
+            },
+        );
+        registry.register(
+            "badname",
+            "Put an illegal character in a short name. Params: entry, at \
+             (0-10, default 0), byte (default 0x2a, '*')",
+            |params| {
+                let mut params = Params::parse(params)?;
+                let defaults = BadShortName::default();
+                let mutation = BadShortName {
+                    entry: params.take("entry")?,
+                    at: params.take("at")?.unwrap_or(defaults.at),
+                    byte: params
+                        .take::<Number<u8>>("byte")?
+                        .map_or(defaults.byte, |n| n.0),
+                };
+                params.finish()?;
+                Ok(Box::new(mutation))
+            },
+        );
+        registry.register(
+            "delete",
+            "Mark an entry deleted (0xe5) with live entries after it. \
+             Params: entry",
+            |params| {
+                let mut params = Params::parse(params)?;
+                let mutation = DeleteEntry {
+                    entry: params.take("entry")?,
+                };
+                params.finish()?;
+                Ok(Box::new(mutation))
+            },
+        );
+        registry.register(
+            "noend",
+            "Fill the unused slots of a directory so it has no end marker. \
+             Params: dir (default the root), byte (default 0xe5)",
+            |params| {
+                let mut params = Params::parse(params)?;
+                let defaults = NoEndMarker::default();
+                let mutation = NoEndMarker {
+                    dir: params.take("dir")?,
+                    byte: params
+                        .take::<Number<u8>>("byte")?
+                        .map_or(defaults.byte, |n| n.0),
+                };
+                params.finish()?;
+                Ok(Box::new(mutation))
+            },
+        );
+        registry.register(
+            "lfnsum",
+            "Break the checksum tying LFN entries to their short name. \
+             Params: entry, seq (the only LFN entry to change, default all)",
+            |params| {
+                let mut params = Params::parse(params)?;
+                let mutation = LfnChecksum {
+                    entry: params.take("entry")?,
+                    seq: params.take("seq")?,
+                };
+                params.finish()?;
+                Ok(Box::new(mutation))
+            },
+        );
+        registry.register(
+            "lfngap",
+            "Leave a sequence number out of a long name. Params: entry, seq \
+             (default 1)",
+            |params| {
+                let mut params = Params::parse(params)?;
+                let defaults = LfnGap::default();
+                let mutation = LfnGap {
+                    entry: params.take("entry")?,
+                    seq: params.take("seq")?.unwrap_or(defaults.seq),
+                };
+                params.finish()?;
+                Ok(Box::new(mutation))
+            },
+        );
+        registry.register(
+            "lfnswap",
+            "Store two LFN entries out of order. Params: entry, seq (swapped \
+             with the next, default 1)",
+            |params| {
+                let mut params = Params::parse(params)?;
+                let defaults = LfnSwap::default();
+                let mutation = LfnSwap {
+                    entry: params.take("entry")?,
+                    seq: params.take("seq")?.unwrap_or(defaults.seq),
+                };
+                params.finish()?;
+                Ok(Box::new(mutation))
+            },
+        );
+        registry.register(
+            "lfnorphan",
+            "Delete the short entry of a long name, orphaning its LFN \
+             entries. Params: entry",
+            |params| {
+                let mut params = Params::parse(params)?;
+                let mutation = LfnOrphan {
+                    entry: params.take("entry")?,
+                };
+                params.finish()?;
+                Ok(Box::new(mutation))
+            },
+        );
+        registry.register(
+            "lfnlong",
+            "Store a long name over 255 UTF-16 units. Params: entry, len \
+             (default 256)",
+            |params| {
+                let mut params = Params::parse(params)?;
+                let defaults = LfnTooLong::default();
+                let mutation = LfnTooLong {
+                    entry: params.take("entry")?,
+                    len: params.take("len")?.unwrap_or(defaults.len),
+                };
+                params.finish()?;
+                Ok(Box::new(mutation))
+            },
+        );
+        registry.register(
+            "lfnsurrogate",
+            "Put an unpaired UTF-16 surrogate in a long name. Params: entry, \
+             at (default 0), unit (default 0xd800)",
+            |params| {
+                let mut params = Params::parse(params)?;
+                let defaults = LfnSurrogate::default();
+                let mutation = LfnSurrogate {
+                    entry: params.take("entry")?,
+                    at: params.take("at")?.unwrap_or(defaults.at),
+                    unit: params
+                        .take::<Number<u16>>("unit")?
+                        .map_or(defaults.unit, |n| n.0),
+                };
+                params.finish()?;
+                Ok(Box::new(mutation))
+            },
+        );
+        registry
+    }
+
+    /// Adds a mutation, replacing any registered under the same name.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name used in specs
+    /// * `description` - One line of help, including the parameters
+    /// * `factory` - Builds the mutation from its parameters
+    pub fn register(
+        &mut self,
+        name: impl Into<String>,
+        description: impl Into<String>,
+        factory: impl Fn(&str) -> MkimgRes<Box<dyn Mutation>> + 'static,
+    ) {
+        self.mutations
+            .insert(name.into(), (description.into(), Box::new(factory)));
+    }
+
+    /// Builds the mutation a `name[:params]` spec selects.
+    ///
+    /// # Errors
+    ///
+    /// Returns error if no mutation has that name or its parameters
+    /// are invalid
+    pub fn parse(&self, spec: &str) -> MkimgRes<Box<dyn Mutation>> {
+        let (name, params) = spec.split_once(':').unwrap_or((spec, ""));
+        let (_, factory) = self
+            .mutations
+            .get(name)
+            .ok_or_else(|| MkimgError::validation(format!("unknown mutation '{name}'")))?;
+        factory(params).map_err(|e| match e {
+            MkimgError::Validation(msg) => {
+                MkimgError::validation(format!("mutation '{spec}': {msg}"))
+            }
+            e => e,
+        })
+    }
+
+    /// Names and descriptions of the registered mutations, sorted by
+    /// name.
+    pub fn list(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.mutations
+            .iter()
+            .map(|(name, (description, _))| (name.as_str(), description.as_str()))
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The comma separated `key=value` parameters of a mutation spec.
+pub struct Params<'a> {
+    values: Vec<(&'a str, &'a str)>,
+}
+
+impl<'a> Params<'a> {
+    /// Splits `params` into its `key=value` pairs.
+    ///
+    /// # Errors
+    ///
+    /// Returns error if a pair has no `=`
+    pub fn parse(params: &'a str) -> MkimgRes<Self> {
+        let values = params
+            .split(',')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                pair.split_once('=').ok_or_else(|| {
+                    MkimgError::validation(format!("expected key=value, got '{pair}'"))
+                })
+            })
+            .collect::<MkimgRes<_>>()?;
+        Ok(Self { values })
+    }
+
+    /// Removes the parameter `key` and parses its value, if it was
+    /// given.
+    ///
+    /// # Errors
+    ///
+    /// Returns error if the value cannot be parsed
+    pub fn take<T: FromStr>(&mut self, key: &str) -> MkimgRes<Option<T>>
+    where
+        T::Err: Display,
+    {
+        let Some(idx) = self.values.iter().position(|(name, _)| *name == key) else {
+            return Ok(None);
+        };
+        let (_, value) = self.values.remove(idx);
+        value
+            .parse()
+            .map(Some)
+            .map_err(|e| MkimgError::validation(format!("{key}: {e}")))
+    }
+
+    /// Checks that every parameter was taken.
+    ///
+    /// # Errors
+    ///
+    /// Returns error naming the first parameter left
+    pub fn finish(self) -> MkimgRes {
+        match self.values.first() {
+            Some((name, _)) => Err(MkimgError::validation(format!(
+                "unknown parameter '{name}'"
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+// A numeric parameter, decimal or hexadecimal
+struct Number<T>(T);
+
+impl<T: TryFrom<u64>> FromStr for Number<T> {
+    type Err = MkimgError;
+
+    fn from_str(s: &str) -> MkimgRes<Self> {
+        parse_number(s).map(Number)
+    }
+}
+
+// The entry at `path`, or the first one in directory order `accept`
+// allows. `what` names the accepted entries in errors
+fn find_entry<'a>(
+    entries: &'a [(String, DirEntry)],
+    path: Option<&str>,
+    what: &str,
+    accept: impl Fn(&str, &DirEntry) -> bool,
+) -> MkimgRes<&'a (String, DirEntry)> {
+    match path {
+        Some(path) => {
+            let key = image_path_key(path);
+            let found = entries
+                .iter()
+                .find(|(entry_path, _)| image_path_key(entry_path) == key)
+                .ok_or_else(|| MkimgError::validation(format!("nothing found at '{path}'")))?;
+            match accept(&found.0, &found.1) {
+                true => Ok(found),
+                false => Err(MkimgError::validation(format!("'{path}' is not {what}"))),
+            }
+        }
+        None => entries
+            .iter()
+            .find(|(path, entry)| accept(path, entry))
+            .ok_or_else(|| MkimgError::validation(format!("nothing in the image is {what}"))),
+    }
+}
+
+// Raw slots of the directory at `path`, empty for the root directory
+fn dir_slots<T: Read + Seek>(
+    volume: &mut Volume<T>,
+    entries: &[(String, DirEntry)],
+    path: &str,
+) -> MkimgRes<Vec<RawEntry>> {
+    let key = image_path_key(path);
+    if key.is_empty() {
+        return volume.root_dir();
+    }
+    let (_, dir) = find_entry(entries, Some(path), "a directory", |_, entry| {
+        entry.attributes.directory
+    })?;
+    volume.dir(dir.first_cluster)
+}
+
+// Slots of the entry whose short entry is at `offset`, its LFN entries
+// first
+fn entry_slots(slots: &[RawEntry], offset: u64) -> MkimgRes<Range<usize>> {
+    let idx = slots
+        .iter()
+        .position(|slot| slot.offset == offset)
+        .ok_or_else(|| MkimgError::validation("entry is not in its directory"))?;
+    let lfn = slots[..idx]
+        .iter()
+        .rev()
+        .take_while(|slot| slot.is_lfn() && slot.bytes[0] != DELETED_MARKER)
+        .count();
+    Ok(idx - lfn..idx + 1)
+}
+
+// Image path of the directory holding `path`
+fn parent_path(path: &str) -> &str {
+    path.rsplit_once('/').map_or("", |(parent, _)| parent)
+}
+
+/// Rewrites the size fields of the boot sector and FSInfo sector as
+/// [`DeceptionOptions`] asks.
+#[derive(Debug, Clone, Copy)]
+pub struct SizeDeception(pub DeceptionOptions);
+
+impl Mutation for SizeDeception {
+    fn apply(&self, img: &mut dyn Disk, volume_offset: u64) -> MkimgRes<String> {
+        let u16_at =
+            |sector: &[u8], offset: usize| u16::from_le_bytes([sector[offset], sector[offset + 1]]);
+        let u32_at = |sector: &[u8], offset: usize| {
+            u32::from_le_bytes([
+                sector[offset],
+                sector[offset + 1],
+                sector[offset + 2],
+                sector[offset + 3],
+            ])
+        };
+
+        // Read the current boot sector
+        let mut boot_sector = [0u8; 512];
+        img.seek(SeekFrom::Start(volume_offset))?;
+        img.read_exact(&mut boot_sector)?;
+        let is_fat32 = u16_at(&boot_sector, 0x16) == 0;
+
+        // FAT32 only has the 32-bit total sectors field at 0x20. FAT12 and
+        // FAT16 use the 16-bit field at 0x13 unless the count needs more
+        // bits
+        let current_sectors = match u16_at(&boot_sector, 0x13) {
+            0 => u32_at(&boot_sector, 0x20),
+            sectors => u32::from(sectors),
+        };
+        let fake_sectors = self.0.total_sectors.apply(current_sectors);
+        let (sectors_16, sectors_32) = match u16::try_from(fake_sectors) {
+            Ok(sectors) if !is_fat32 && sectors != 0 => (sectors, 0),
+            _ => (0, fake_sectors),
+        };
+        boot_sector[0x13..0x15].copy_from_slice(&sectors_16.to_le_bytes());
+        boot_sector[0x20..0x24].copy_from_slice(&sectors_32.to_le_bytes());
+
+        // Write back the modified boot sector
+        img.seek(SeekFrom::Start(volume_offset))?;
+        img.write_all(&boot_sector)?;
+
+        // FAT32 keeps free space hints in the FSInfo sector
+        if is_fat32 {
+            let fsinfo_offset = volume_offset
+                + u64::from(u16_at(&boot_sector, 0x30)) * u64::from(u16_at(&boot_sector, 0x0b));
+            img.seek(SeekFrom::Start(fsinfo_offset))?;
+            let mut fsinfo_sector = [0u8; 512];
+            img.read_exact(&mut fsinfo_sector)?;
+
+            // Check if this is actually an FSInfo sector (signature "RRaA" at offset 0)
+            if &fsinfo_sector[0x00..0x04] == b"RRaA" {
+                // Free cluster count at 0x1e8, where 0xFFFFFFFF means
+                // unknown, and next free cluster hint at 0x1ec
+                let current_free = u32_at(&fsinfo_sector, 0x1e8);
+                let fake_free = match self.0.free_clusters {
+                    Lie::Scale(_) if current_free == 0xFFFFFFFF => current_free,
+                    lie => lie.apply(current_free),
+                };
+                fsinfo_sector[0x1e8..0x1ec].copy_from_slice(&fake_free.to_le_bytes());
+                let next_free = self.0.next_free.apply(u32_at(&fsinfo_sector, 0x1ec));
+                fsinfo_sector[0x1ec..0x1f0].copy_from_slice(&next_free.to_le_bytes());
+
+                // Write back the modified FSInfo sector
+                img.seek(SeekFrom::Start(fsinfo_offset))?;
+                img.write_all(&fsinfo_sector)?;
+            }
+        }
+
+        img.flush()?;
+        Ok(format!(
+            "Applied size deception - img now claims {fake_sectors} sectors instead of {current_sectors}"
+        ))
+    }
+}
+
+/// Bytes read at a time when looking for the end of the data.
+const SHRINK_SCAN_CHUNK: usize = 1 << 20;
+
+/// Cuts the image file after the last sector holding a non-zero byte,
+/// keeping at least 512KB. Reading the free space of a shrunk volume
+/// fails, while its size fields still claim the full size.
+///
+/// Only images without a partition table can be shrunk, as cutting a
+/// partitioned image would remove the structures behind its volume,
+/// such as the GPT backup. The file never grows.
+#[derive(Debug, Clone, Copy)]
+pub struct Shrink;
+
+impl Mutation for Shrink {
+    fn apply(&self, img: &mut dyn Disk, volume_offset: u64) -> MkimgRes<String> {
+        if volume_offset != 0 {
+            return Err(MkimgError::validation(
+                "only images without a partition table can be shrunk",
+            ));
+        }
+        let len = img.seek(SeekFrom::End(0))?;
+        // Find the last non-zero byte to determine minimum file size
+        // Start from a reasonable minimum (like 512KB) and extend as needed
+        let min_size = (512 * 1024).min(len); // 512KB minimum
+        let mut actual_size = min_size;
+        // Look for actual data beyond the minimum, scanning backwards in
+        // bounded chunks
+        let mut chunk = vec![0u8; SHRINK_SCAN_CHUNK];
+        let mut end = len;
+        while end > min_size {
+            let start = end.saturating_sub(SHRINK_SCAN_CHUNK as u64).max(min_size);
+            let chunk = &mut chunk[..(end - start) as usize];
+            img.seek(SeekFrom::Start(start))?;
+            img.read_exact(chunk)?;
+            if let Some(i) = chunk.iter().rposition(|&byte| byte != 0) {
+                // Round up to next sector
+                actual_size = (((start + i as u64) / 512 + 1) * 512).min(len);
+                break;
+            }
+            end = start;
+        }
+        img.set_len(actual_size)?;
+        img.flush()?;
+        Ok(format!("Shrunk file to {actual_size} bytes"))
+    }
+}
+
+// Images and readers shared by the tests of the mutations
+#[cfg(test)]
+mod test_util {
+    use super::{dir_slots, entry_slots, parent_path};
+    use crate::{
+        fat::{DirEntry, RawEntry, Volume},
+        image_path_key,
+        mutation::Mutation,
+        options::CreateOptions,
+        FatType, FileMapping, Source,
+    };
+    use std::io::Cursor;
+
+    pub type Img = Cursor<Vec<u8>>;
+
+    // An image with 512 byte clusters holding, in directory order,
+    // a.bin (20 clusters), b.bin (10), "Long Name File.txt" and the
+    // directory d with d/c.bin and d/sub/x.txt
+    pub fn image(fat_type: FatType) -> Img {
+        let fill = |len| Source::Fill {
+            len,
+            pattern: vec![0xa5],
+        };
+        let mappings = [
+            FileMapping::new(fill(10000), "a.bin"),
+            FileMapping::new(fill(5000), "b.bin"),
+            FileMapping::new(fill(100), "Long Name File.txt"),
+            FileMapping::new(fill(3000), "d/c.bin"),
+            FileMapping::new(fill(100), "d/sub/x.txt"),
+        ];
+        let options = CreateOptions {
+            fat_type: Some(fat_type),
+            bytes_per_cluster: Some(512),
+            ..CreateOptions::default()
+        };
+        let mut img = Cursor::new(Vec::new());
+        crate::create(&mut img, &mappings, &options).unwrap();
+        img
+    }
+
+    pub fn apply(img: &mut Img, mutation: impl Mutation) {
+        mutation.apply(img, 0).unwrap();
+    }
+
+    pub fn volume(img: &mut Img) -> Volume<&mut Img> {
+        Volume::open(img, 0).unwrap()
+    }
+
+    pub fn entry(img: &mut Img, path: &str) -> DirEntry {
+        volume(img)
+            .walk()
+            .unwrap()
+            .into_iter()
+            .find(|(other, _)| image_path_key(other) == image_path_key(path))
+            .map(|(_, entry)| entry)
+            .unwrap()
+    }
+
+    pub fn chain(img: &mut Img, path: &str) -> Vec<u32> {
+        let first = entry(img, path).first_cluster;
+        volume(img).chain(first).unwrap()
+    }
+
+    // Raw entry for `cluster` in FAT copy `copy`
+    pub fn fat_entry(img: &mut Img, copy: u8, cluster: u32) -> u32 {
+        let mut volume = volume(img);
+        let bpb = volume.bpb.clone();
+        let fat_offset = bpb.fat_offset() + u64::from(copy) * bpb.fat_bytes();
+        let mut buf = [0u8; 4];
+        match bpb.fat_type {
+            FatType::Fat12 => {
+                let offset = fat_offset + u64::from(cluster) + u64::from(cluster / 2);
+                volume.read_at(offset, &mut buf[..2]).unwrap();
+                let value = u32::from(u16::from_le_bytes([buf[0], buf[1]]));
+                match cluster % 2 {
+                    0 => value & 0x0fff,
+                    _ => value >> 4,
+                }
+            }
+            FatType::Fat16 => {
+                volume
+                    .read_at(fat_offset + u64::from(cluster) * 2, &mut buf[..2])
+                    .unwrap();
+                u32::from(u16::from_le_bytes([buf[0], buf[1]]))
+            }
+            FatType::Fat32 => {
+                volume
+                    .read_at(fat_offset + u64::from(cluster) * 4, &mut buf)
+                    .unwrap();
+                u32::from_le_bytes(buf)
+            }
+        }
+    }
+
+    // Raw directory slot at `offset`
+    pub fn slot(img: &mut Img, offset: u64) -> [u8; 32] {
+        let mut buf = [0u8; 32];
+        volume(img).read_at(offset, &mut buf).unwrap();
+        buf
+    }
+
+    // Raw LFN slots of the entry at `path`, followed by its short slot
+    pub fn raw_slots(img: &mut Img, path: &str) -> Vec<RawEntry> {
+        let mut volume = volume(img);
+        let entries = volume.walk().unwrap();
+        let (_, entry) = entries
+            .iter()
+            .find(|(other, _)| image_path_key(other) == image_path_key(path))
+            .unwrap();
+        let slots = dir_slots(&mut volume, &entries, parent_path(path)).unwrap();
+        let range = entry_slots(&slots, entry.offset).unwrap();
+        slots[range].to_vec()
+    }
+}
@@ -0,0 +1,453 @@
+//! Mutations damaging the cluster chains of files.
+//!
+//! Each one picks a file by its image path, or the first file in
+//! directory order with enough clusters, and changes the allocation
+//! table so its chain no longer matches its directory entry. Unless
+//! noted otherwise every FAT copy gets the same change.
+
+use super::Mutation;
+use crate::{
+    disk::Disk,
+    error::{MkimgError, MkimgRes},
+    fat::{Bpb, Volume},
+    image_path_key,
+    options::parse_number,
+};
+use std::{
+    io::{Read, Seek},
+    str::FromStr,
+};
+
+/// What a damaged FAT entry points to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Link {
+    /// A free cluster, as if the chain had been freed under the file.
+    Free,
+    /// Cluster 1, which holds no data.
+    Reserved,
+    /// The bad cluster marker.
+    Bad,
+    /// The first cluster past the end of the volume.
+    OutOfRange,
+    /// Any FAT entry value.
+    Value(u32),
+}
+
+impl Link {
+    fn value(self, bpb: &Bpb) -> u32 {
+        match self {
+            Link::Free => 0,
+            Link::Reserved => 1,
+            Link::Bad => bpb.bad_cluster(),
+            Link::OutOfRange => bpb.max_cluster() + 1,
+            Link::Value(value) => value,
+        }
+    }
+}
+
+impl FromStr for Link {
+    type Err = MkimgError;
+
+    fn from_str(s: &str) -> MkimgRes<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "free" => Ok(Link::Free),
+            "reserved" => Ok(Link::Reserved),
+            "bad" => Ok(Link::Bad),
+            "range" => Ok(Link::OutOfRange),
+            _ => parse_number(s)
+                .map(Link::Value)
+                .map_err(|_| MkimgError::validation(format!("invalid link '{s}'"))),
+        }
+    }
+}
+
+/// Makes the chain of one file run into the chain of another after
+/// its first cluster. The rest of its own chain is left allocated but
+/// unreachable.
+#[derive(Debug, Clone, Default)]
+pub struct CrossLink {
+    /// File whose chain is shared.
+    pub target: Option<String>,
+    /// File whose chain is redirected. Must differ from `target`.
+    pub file: Option<String>,
+}
+
+impl Mutation for CrossLink {
+    fn apply(&self, img: &mut dyn Disk, volume_offset: u64) -> MkimgRes<String> {
+        let mut volume = Volume::open(img, volume_offset)?;
+        let files = files(&mut volume)?;
+        let (target, target_chain) = pick(&files, self.target.as_deref(), 1, self.file.as_deref())?;
+        let (file, chain) = pick(&files, self.file.as_deref(), 1, Some(target))?;
+        volume.set_fat_entries(chain[0], target_chain[0])?;
+        Ok(format!("Cross-linked {file} into {target}"))
+    }
+}
+
+/// Points the last cluster of a file back into its own chain.
+#[derive(Debug, Clone, Default)]
+pub struct Cycle {
+    /// File to change.
+    pub file: Option<String>,
+    /// Index within the chain the last cluster points to. 0 loops back
+    /// to the first cluster; a single cluster chain points to itself.
+    pub to: usize,
+}
+
+impl Mutation for Cycle {
+    fn apply(&self, img: &mut dyn Disk, volume_offset: u64) -> MkimgRes<String> {
+        let mut volume = Volume::open(img, volume_offset)?;
+        let files = files(&mut volume)?;
+        let (file, chain) = pick(&files, self.file.as_deref(), self.to + 1, None)?;
+        volume.set_fat_entries(chain[chain.len() - 1], chain[self.to])?;
+        Ok(format!(
+            "Looped the chain of {file} back to cluster {}",
+            chain[self.to]
+        ))
+    }
+}
+
+/// Points one cluster of a file at a cluster no chain may continue
+/// into.
+#[derive(Debug, Clone)]
+pub struct BadLink {
+    /// File to change.
+    pub file: Option<String>,
+    /// Index within the chain of the cluster whose entry changes.
+    pub at: usize,
+    /// What the entry points to.
+    pub to: Link,
+}
+
+impl Default for BadLink {
+    fn default() -> Self {
+        Self {
+            file: None,
+            at: 0,
+            to: Link::OutOfRange,
+        }
+    }
+}
+
+impl Mutation for BadLink {
+    fn apply(&self, img: &mut dyn Disk, volume_offset: u64) -> MkimgRes<String> {
+        let mut volume = Volume::open(img, volume_offset)?;
+        let files = files(&mut volume)?;
+        let (file, chain) = pick(&files, self.file.as_deref(), self.at + 1, None)?;
+        let value = self.to.value(&volume.bpb);
+        volume.set_fat_entries(chain[self.at], value)?;
+        Ok(format!(
+            "Pointed cluster {} of {file} at {value:#x}",
+            chain[self.at]
+        ))
+    }
+}
+
+/// Ends the chain of a file before its size is reached, freeing the
+/// clusters cut off.
+#[derive(Debug, Clone, Default)]
+pub struct TruncateChain {
+    /// File to change.
+    pub file: Option<String>,
+    /// Clusters to keep, at least 1. Defaults to one less than the
+    /// chain holds.
+    pub clusters: Option<usize>,
+}
+
+impl Mutation for TruncateChain {
+    fn apply(&self, img: &mut dyn Disk, volume_offset: u64) -> MkimgRes<String> {
+        if self.clusters == Some(0) {
+            return Err(MkimgError::validation("a chain keeps at least 1 cluster"));
+        }
+        let mut volume = Volume::open(img, volume_offset)?;
+        let files = files(&mut volume)?;
+        let min_clusters = self.clusters.unwrap_or(1) + 1;
+        let (file, chain) = pick(&files, self.file.as_deref(), min_clusters, None)?;
+        let keep = self.clusters.unwrap_or(chain.len() - 1);
+        // The marker fatfs writes, the highest end of chain value
+        let end_of_chain = volume.bpb.end_of_chain() | 0x7;
+        volume.set_fat_entries(chain[keep - 1], end_of_chain)?;
+        for &cluster in &chain[keep..] {
+            volume.set_fat_entries(cluster, 0)?;
+        }
+        Ok(format!(
+            "Truncated the chain of {file} from {} to {keep} clusters",
+            chain.len()
+        ))
+    }
+}
+
+/// Changes one cluster of a file in a single FAT copy, so the copies
+/// disagree.
+#[derive(Debug, Clone)]
+pub struct DivergeFat {
+    /// File to change.
+    pub file: Option<String>,
+    /// Index within the chain of the cluster whose entry changes.
+    pub at: usize,
+    /// FAT copy to change, counting from 0.
+    pub copy: u8,
+    /// What the entry points to in that copy.
+    pub to: Link,
+}
+
+impl Default for DivergeFat {
+    fn default() -> Self {
+        Self {
+            file: None,
+            at: 0,
+            copy: 1,
+            to: Link::Free,
+        }
+    }
+}
+
+impl Mutation for DivergeFat {
+    fn apply(&self, img: &mut dyn Disk, volume_offset: u64) -> MkimgRes<String> {
+        let mut volume = Volume::open(img, volume_offset)?;
+        let files = files(&mut volume)?;
+        let (file, chain) = pick(&files, self.file.as_deref(), self.at + 1, None)?;
+        let value = self.to.value(&volume.bpb);
+        volume.set_fat_entry(self.copy, chain[self.at], value)?;
+        Ok(format!(
+            "Pointed cluster {} of {file} at {value:#x} in FAT {}",
+            chain[self.at], self.copy
+        ))
+    }
+}
+
+// Paths and chains of the files holding data, in directory order
+fn files<T: Read + Seek>(volume: &mut Volume<T>) -> MkimgRes<Vec<(String, Vec<u32>)>> {
+    let mut files = Vec::new();
+    for (path, entry) in volume.walk()? {
+        if !entry.attributes.directory && entry.first_cluster != 0 {
+            let chain = volume.chain(entry.first_cluster)?;
+            files.push((path, chain));
+        }
+    }
+    Ok(files)
+}
+
+// The file at `path`, or the first one other than `skip` whose chain
+// has at least `min_clusters` clusters
+fn pick<'a>(
+    files: &'a [(String, Vec<u32>)],
+    path: Option<&str>,
+    min_clusters: usize,
+    skip: Option<&str>,
+) -> MkimgRes<(&'a str, &'a [u32])> {
+    let (file, chain) = match path {
+        Some(path) => {
+            let key = image_path_key(path);
+            let (file, chain) = files
+                .iter()
+                .find(|(file, _)| image_path_key(file) == key)
+                .ok_or_else(|| {
+                    MkimgError::validation(format!("no file holding data at '{path}'"))
+                })?;
+            if skip.is_some_and(|skip| image_path_key(skip) == key) {
+                return Err(MkimgError::validation(format!(
+                    "'{path}' cannot be linked to itself"
+                )));
+            }
+            if chain.len() < min_clusters {
+                return Err(MkimgError::validation(format!(
+                    "'{path}' has {} clusters, {min_clusters} needed",
+                    chain.len()
+                )));
+            }
+            (file, chain)
+        }
+        None => files
+            .iter()
+            .find(|(file, chain)| {
+                chain.len() >= min_clusters
+                    && skip.is_none_or(|skip| image_path_key(skip) != image_path_key(file))
+            })
+            .map(|(file, chain)| (file, chain))
+            .ok_or_else(|| {
+                MkimgError::validation(format!("no file has {min_clusters} or more clusters"))
+            })?,
+    };
+    Ok((file, chain))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mutation::test_util::{apply, chain, fat_entry, image, volume, Img},
+        FatType,
+    };
+
+    // Entry for `cluster` in every FAT copy
+    fn fat_entries(img: &mut Img, cluster: u32) -> [u32; 2] {
+        [fat_entry(img, 0, cluster), fat_entry(img, 1, cluster)]
+    }
+
+    #[test]
+    fn crosslink_links_second_file_into_first_by_default() {
+        let mut img = image(FatType::Fat16);
+        let a = chain(&mut img, "a.bin");
+        let b = chain(&mut img, "b.bin");
+        apply(&mut img, CrossLink::default());
+        assert_eq!(fat_entries(&mut img, b[0]), [a[0], a[0]]);
+        assert_eq!(fat_entries(&mut img, a[0]), [a[1], a[1]]);
+    }
+
+    #[test]
+    fn crosslink_named_files() {
+        let mut img = image(FatType::Fat16);
+        let b = chain(&mut img, "b.bin");
+        let c = chain(&mut img, "d/c.bin");
+        apply(
+            &mut img,
+            CrossLink {
+                target: Some("d/c.bin".into()),
+                file: Some("B.BIN".into()),
+            },
+        );
+        assert_eq!(fat_entries(&mut img, b[0]), [c[0], c[0]]);
+    }
+
+    #[test]
+    fn crosslink_refuses_same_file() {
+        let mut img = image(FatType::Fat16);
+        let mutation = CrossLink {
+            target: Some("a.bin".into()),
+            file: Some("a.bin".into()),
+        };
+        assert!(mutation.apply(&mut img, 0).is_err());
+    }
+
+    #[test]
+    fn cycle_loops_first_file_to_its_start_by_default() {
+        let mut img = image(FatType::Fat16);
+        let a = chain(&mut img, "a.bin");
+        apply(&mut img, Cycle::default());
+        assert_eq!(fat_entries(&mut img, a[a.len() - 1]), [a[0], a[0]]);
+        assert_eq!(chain(&mut img, "a.bin"), a);
+    }
+
+    #[test]
+    fn cycle_named_file_to_index() {
+        let mut img = image(FatType::Fat32);
+        let b = chain(&mut img, "b.bin");
+        apply(
+            &mut img,
+            Cycle {
+                file: Some("b.bin".into()),
+                to: 3,
+            },
+        );
+        assert_eq!(fat_entries(&mut img, b[b.len() - 1]), [b[3], b[3]]);
+    }
+
+    #[test]
+    fn badlink_points_past_the_volume_by_default() {
+        let mut img = image(FatType::Fat16);
+        let a = chain(&mut img, "a.bin");
+        let past_end = volume(&mut img).bpb.max_cluster() + 1;
+        apply(&mut img, BadLink::default());
+        assert_eq!(fat_entries(&mut img, a[0]), [past_end, past_end]);
+    }
+
+    #[test]
+    fn badlink_named_file_to_bad_cluster() {
+        let mut img = image(FatType::Fat12);
+        let b = chain(&mut img, "b.bin");
+        apply(
+            &mut img,
+            BadLink {
+                file: Some("b.bin".into()),
+                at: 4,
+                to: Link::Bad,
+            },
+        );
+        assert_eq!(fat_entries(&mut img, b[4]), [0xff7, 0xff7]);
+        assert_eq!(fat_entries(&mut img, b[3]), [b[4], b[4]]);
+    }
+
+    #[test]
+    fn truncate_drops_last_cluster_by_default() {
+        let mut img = image(FatType::Fat16);
+        let a = chain(&mut img, "a.bin");
+        let n = a.len();
+        apply(&mut img, TruncateChain::default());
+        assert_eq!(fat_entries(&mut img, a[n - 2]), [0xffff, 0xffff]);
+        assert_eq!(fat_entries(&mut img, a[n - 1]), [0, 0]);
+    }
+
+    #[test]
+    fn truncate_named_file_on_fat12() {
+        let mut img = image(FatType::Fat12);
+        let b = chain(&mut img, "b.bin");
+        apply(
+            &mut img,
+            TruncateChain {
+                file: Some("b.bin".into()),
+                clusters: Some(2),
+            },
+        );
+        assert_eq!(fat_entries(&mut img, b[1]), [0xfff, 0xfff]);
+        for &cluster in &b[2..] {
+            assert_eq!(fat_entries(&mut img, cluster), [0, 0]);
+        }
+        assert_eq!(chain(&mut img, "b.bin"), b[..2]);
+    }
+
+    #[test]
+    fn truncate_keeps_fat32_reserved_bits() {
+        let mut img = image(FatType::Fat32);
+        let a = chain(&mut img, "a.bin");
+        let n = a.len();
+        apply(&mut img, TruncateChain::default());
+        assert_eq!(fat_entries(&mut img, a[n - 2]), [0x0fff_ffff, 0x0fff_ffff]);
+    }
+
+    #[test]
+    fn truncate_refuses_zero_clusters() {
+        let mut img = image(FatType::Fat16);
+        let mutation = TruncateChain {
+            file: None,
+            clusters: Some(0),
+        };
+        assert!(mutation.apply(&mut img, 0).is_err());
+    }
+
+    #[test]
+    fn diverge_frees_entry_in_second_copy_by_default() {
+        let mut img = image(FatType::Fat16);
+        let a = chain(&mut img, "a.bin");
+        apply(&mut img, DivergeFat::default());
+        assert_eq!(fat_entries(&mut img, a[0]), [a[1], 0]);
+    }
+
+    #[test]
+    fn diverge_named_file_in_first_copy() {
+        let mut img = image(FatType::Fat12);
+        let b = chain(&mut img, "b.bin");
+        let before = fat_entries(&mut img, b[3]);
+        apply(
+            &mut img,
+            DivergeFat {
+                file: Some("b.bin".into()),
+                at: 2,
+                copy: 0,
+                to: Link::Value(0x123),
+            },
+        );
+        assert_eq!(fat_entries(&mut img, b[2]), [0x123, b[3]]);
+        // Neighbouring FAT12 entries share a byte
+        assert_eq!(fat_entries(&mut img, b[3]), before);
+    }
+
+    #[test]
+    fn diverge_refuses_missing_copy() {
+        let mut img = image(FatType::Fat16);
+        let mutation = DivergeFat {
+            copy: 2,
+            ..DivergeFat::default()
+        };
+        assert!(mutation.apply(&mut img, 0).is_err());
+    }
+}
@@ -0,0 +1,506 @@
+//! Mutations damaging directory entries.
+//!
+//! Each one picks an entry or directory by its image path, or the
+//! first suitable one in directory order, and rewrites raw directory
+//! slots. The allocation table is left alone, so clusters an entry no
+//! longer reaches stay allocated.
+
+use super::{dir_slots, entry_slots, find_entry, parent_path, Mutation};
+use crate::{
+    disk::Disk,
+    error::{MkimgError, MkimgRes},
+    fat::{lfn_checksum, DirEntry, Volume, DELETED_MARKER},
+    image_path_key, FatType,
+};
+
+/// Makes the size of a file claim more data than its chain holds.
+#[derive(Debug, Clone, Default)]
+pub struct Oversize {
+    /// File to change.
+    pub file: Option<String>,
+    /// Size to claim. Defaults to one cluster more than the chain
+    /// holds.
+    pub size: Option<u32>,
+}
+
+impl Mutation for Oversize {
+    fn apply(&self, img: &mut dyn Disk, volume_offset: u64) -> MkimgRes<String> {
+        let mut volume = Volume::open(img, volume_offset)?;
+        let entries = volume.walk()?;
+        let (path, entry) = find_entry(&entries, self.file.as_deref(), "a file", |_, entry| {
+            !entry.attributes.directory
+        })?;
+        let bytes_per_cluster = volume.bpb.bytes_per_cluster();
+        let clusters = match entry.first_cluster {
+            0 => 0,
+            first => volume.chain(first)?.len() as u32,
+        };
+        let size = self
+            .size
+            .unwrap_or_else(|| clusters.saturating_add(1).saturating_mul(bytes_per_cluster));
+        volume.write_at(entry.offset + 0x1c, &size.to_le_bytes())?;
+        Ok(format!(
+            "Set the size of {path} to {size} bytes, its chain holds {}",
+            u64::from(clusters) * u64::from(bytes_per_cluster)
+        ))
+    }
+}
+
+/// Points the `.` or `..` entry of a directory at the wrong cluster.
+#[derive(Debug, Clone, Default)]
+pub struct DotLink {
+    /// Directory to change.
+    pub dir: Option<String>,
+    /// Change `..` instead of `.`.
+    pub parent: bool,
+    /// Cluster to point to. Defaults to the parent directory for `.`
+    /// and the directory itself for `..`.
+    pub to: Option<u32>,
+}
+
+impl Mutation for DotLink {
+    fn apply(&self, img: &mut dyn Disk, volume_offset: u64) -> MkimgRes<String> {
+        let mut volume = Volume::open(img, volume_offset)?;
+        let entries = volume.walk()?;
+        let name = match self.parent {
+            true => "..",
+            false => ".",
+        };
+        let (path, dir) = find_entry(&entries, self.dir.as_deref(), "a directory", |_, entry| {
+            entry.attributes.directory && !entry.is_dot()
+        })?;
+        let key = format!("{}/{name}", image_path_key(path));
+        let (_, dot) = entries
+            .iter()
+            .find(|(path, _)| image_path_key(path) == key)
+            .ok_or_else(|| MkimgError::validation(format!("'{path}' has no {name} entry")))?;
+        let to = match (self.to, self.parent) {
+            (Some(to), _) => to,
+            (None, true) => dir.first_cluster,
+            (None, false) => cluster_of(&volume, &entries, parent_path(path))?,
+        };
+        volume.set_first_cluster(dot.offset, to)?;
+        Ok(format!("Pointed {path}/{name} at cluster {to}"))
+    }
+}
+
+/// Points a subdirectory at one of its ancestors, so walking it
+/// never ends. Its own clusters are no longer reachable.
+#[derive(Debug, Clone)]
+pub struct DirLoop {
+    /// Directory to change.
+    pub dir: Option<String>,
+    /// Levels up to the ancestor, 1 being the directory holding it.
+    /// Only FAT32 root directories have a cluster to point to.
+    pub up: usize,
+}
+
+impl Default for DirLoop {
+    fn default() -> Self {
+        Self { dir: None, up: 1 }
+    }
+}
+
+impl Mutation for DirLoop {
+    fn apply(&self, img: &mut dyn Disk, volume_offset: u64) -> MkimgRes<String> {
+        if self.up == 0 {
+            return Err(MkimgError::validation("a directory cannot loop to itself"));
+        }
+        let mut volume = Volume::open(img, volume_offset)?;
+        let entries = volume.walk()?;
+        let has_root_cluster = volume.bpb.fat_type == FatType::Fat32;
+        let ancestor = |path: &str| {
+            let depth = path.split('/').count();
+            (self.up <= depth).then(|| {
+                path.split('/')
+                    .take(depth - self.up)
+                    .collect::<Vec<_>>()
+                    .join("/")
+            })
+        };
+        let (path, dir) = find_entry(
+            &entries,
+            self.dir.as_deref(),
+            &format!("a directory {} levels below one with a cluster", self.up),
+            |path, entry| {
+                entry.attributes.directory
+                    && !entry.is_dot()
+                    && ancestor(path).is_some_and(|path| !path.is_empty() || has_root_cluster)
+            },
+        )?;
+        let ancestor = ancestor(path).unwrap_or_default();
+        let to = cluster_of(&volume, &entries, &ancestor)?;
+        volume.set_first_cluster(dir.offset, to)?;
+        Ok(format!("Pointed {path} at its ancestor '/{ancestor}'"))
+    }
+}
+
+/// Puts a character no short name may hold into the short name of an
+/// entry. Its LFN entries are updated to match, so only the short
+/// name is wrong.
+#[derive(Debug, Clone)]
+pub struct BadShortName {
+    /// Entry to change.
+    pub entry: Option<String>,
+    /// Position in the 11 byte name, the extension starting at 8.
+    pub at: usize,
+    /// Byte to write.
+    pub byte: u8,
+}
+
+impl Default for BadShortName {
+    fn default() -> Self {
+        Self {
+            entry: None,
+            at: 0,
+            byte: b'*',
+        }
+    }
+}
+
+impl Mutation for BadShortName {
+    fn apply(&self, img: &mut dyn Disk, volume_offset: u64) -> MkimgRes<String> {
+        if self.at >= 11 {
+            return Err(MkimgError::validation(
+                "a short name is 11 bytes, counting from 0",
+            ));
+        }
+        let mut volume = Volume::open(img, volume_offset)?;
+        let entries = volume.walk()?;
+        let (path, entry) = find_entry(&entries, self.entry.as_deref(), "an entry", |_, entry| {
+            !entry.is_dot()
+        })?;
+        let mut slots = dir_slots(&mut volume, &entries, parent_path(path))?;
+        let range = entry_slots(&slots, entry.offset)?;
+        let (short, lfn) = slots[range].split_last_mut().unwrap();
+        short.bytes[self.at] = self.byte;
+        let mut name = [0u8; 11];
+        name.copy_from_slice(&short.bytes[..11]);
+        for slot in lfn.iter_mut().chain(Some(short)) {
+            if slot.is_lfn() {
+                slot.bytes[0x0d] = lfn_checksum(&name);
+            }
+            volume.write_at(slot.offset, &slot.bytes)?;
+        }
+        Ok(format!(
+            "Renamed the short entry of {path} to {:?}",
+            String::from_utf8_lossy(&name)
+        ))
+    }
+}
+
+/// Marks an entry and its LFN entries deleted while later entries of
+/// its directory stay live.
+#[derive(Debug, Clone, Default)]
+pub struct DeleteEntry {
+    /// Entry to delete. Defaults to the first one followed by another
+    /// in its directory.
+    pub entry: Option<String>,
+}
+
+impl Mutation for DeleteEntry {
+    fn apply(&self, img: &mut dyn Disk, volume_offset: u64) -> MkimgRes<String> {
+        let mut volume = Volume::open(img, volume_offset)?;
+        let entries = volume.walk()?;
+        // Entries are listed in directory order, with those of
+        // subdirectories in between
+        let followed = |path: &str, entry: &DirEntry| {
+            let parent = image_path_key(parent_path(path));
+            let idx = entries
+                .iter()
+                .position(|(_, other)| other.offset == entry.offset)
+                .unwrap_or(entries.len());
+            entries[idx + 1..]
+                .iter()
+                .any(|(other, _)| image_path_key(parent_path(other)) == parent)
+        };
+        let (path, entry) = find_entry(
+            &entries,
+            self.entry.as_deref(),
+            "an entry followed by another",
+            |path, entry| !entry.is_dot() && followed(path, entry),
+        )?;
+        let slots = dir_slots(&mut volume, &entries, parent_path(path))?;
+        for slot in &slots[entry_slots(&slots, entry.offset)?] {
+            volume.write_at(slot.offset, &[DELETED_MARKER])?;
+        }
+        Ok(format!("Marked {path} deleted"))
+    }
+}
+
+/// Fills the unused slots at the end of a directory, so no end of
+/// directory marker stops a walk before the end of its clusters.
+#[derive(Debug, Clone)]
+pub struct NoEndMarker {
+    /// Directory to change. Defaults to the root directory.
+    pub dir: Option<String>,
+    /// Byte every unused slot is filled with. The default marks them
+    /// deleted; others leave garbage entries.
+    pub byte: u8,
+}
+
+impl Default for NoEndMarker {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            byte: DELETED_MARKER,
+        }
+    }
+}
+
+impl Mutation for NoEndMarker {
+    fn apply(&self, img: &mut dyn Disk, volume_offset: u64) -> MkimgRes<String> {
+        let mut volume = Volume::open(img, volume_offset)?;
+        let entries = volume.walk()?;
+        let dir = self.dir.as_deref().unwrap_or("");
+        let slots = dir_slots(&mut volume, &entries, dir)?;
+        let end = slots
+            .iter()
+            .position(|slot| slot.bytes[0] == 0)
+            .ok_or_else(|| MkimgError::validation(format!("'/{dir}' has no unused slots")))?;
+        for slot in &slots[end..] {
+            volume.write_at(slot.offset, &[self.byte; 32])?;
+        }
+        Ok(format!(
+            "Filled {} unused slots of '/{dir}' with {:#04x}",
+            slots.len() - end,
+            self.byte
+        ))
+    }
+}
+
+// Cluster a directory entry uses to refer to the directory at `path`
+fn cluster_of<T>(volume: &Volume<T>, entries: &[(String, DirEntry)], path: &str) -> MkimgRes<u32> {
+    if image_path_key(path).is_empty() {
+        return Ok(match volume.bpb.fat_type {
+            FatType::Fat32 => volume.bpb.root_cluster,
+            _ => 0,
+        });
+    }
+    let (_, dir) = find_entry(entries, Some(path), "a directory", |_, entry| {
+        entry.attributes.directory
+    })?;
+    Ok(dir.first_cluster)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mutation::test_util::{apply, chain, entry, image, raw_slots, slot, volume, Img};
+
+    fn cluster_at(img: &mut Img, offset: u64) -> u32 {
+        let bytes = slot(img, offset);
+        u32::from(u16::from_le_bytes([bytes[0x14], bytes[0x15]])) << 16
+            | u32::from(u16::from_le_bytes([bytes[0x1a], bytes[0x1b]]))
+    }
+
+    fn size_at(img: &mut Img, offset: u64) -> u32 {
+        let bytes = slot(img, offset);
+        u32::from_le_bytes([bytes[0x1c], bytes[0x1d], bytes[0x1e], bytes[0x1f]])
+    }
+
+    fn slot_offsets(img: &mut Img, path: &str) -> Vec<u64> {
+        raw_slots(img, path)
+            .iter()
+            .map(|slot| slot.offset)
+            .collect()
+    }
+
+    #[test]
+    fn oversize_claims_one_more_cluster_by_default() {
+        let mut img = image(FatType::Fat16);
+        let a = entry(&mut img, "a.bin");
+        let clusters = chain(&mut img, "a.bin").len() as u32;
+        apply(&mut img, Oversize::default());
+        assert_eq!(size_at(&mut img, a.offset), (clusters + 1) * 512);
+    }
+
+    #[test]
+    fn oversize_named_file() {
+        let mut img = image(FatType::Fat16);
+        let a = entry(&mut img, "a.bin");
+        let b = entry(&mut img, "b.bin");
+        apply(
+            &mut img,
+            Oversize {
+                file: Some("b.bin".into()),
+                size: Some(0x10000),
+            },
+        );
+        assert_eq!(size_at(&mut img, b.offset), 0x10000);
+        assert_eq!(size_at(&mut img, a.offset), 10000);
+    }
+
+    #[test]
+    fn dotlink_points_dot_at_parent_by_default() {
+        let mut img = image(FatType::Fat16);
+        let dot = entry(&mut img, "d/.");
+        apply(&mut img, DotLink::default());
+        assert_eq!(cluster_at(&mut img, dot.offset), 0);
+    }
+
+    #[test]
+    fn dotlink_points_dot_at_fat32_root_cluster() {
+        let mut img = image(FatType::Fat32);
+        let dot = entry(&mut img, "d/.");
+        let root = volume(&mut img).bpb.root_cluster;
+        apply(&mut img, DotLink::default());
+        assert_eq!(cluster_at(&mut img, dot.offset), root);
+    }
+
+    #[test]
+    fn dotlink_named_dir_parent_entry() {
+        let mut img = image(FatType::Fat16);
+        let sub = entry(&mut img, "d/sub");
+        let dotdot = entry(&mut img, "d/sub/..");
+        apply(
+            &mut img,
+            DotLink {
+                dir: Some("d/sub".into()),
+                parent: true,
+                to: None,
+            },
+        );
+        assert_eq!(cluster_at(&mut img, dotdot.offset), sub.first_cluster);
+    }
+
+    #[test]
+    fn dirloop_skips_dirs_below_fat16_root_by_default() {
+        let mut img = image(FatType::Fat16);
+        let d = entry(&mut img, "d");
+        let sub = entry(&mut img, "d/sub");
+        apply(&mut img, DirLoop::default());
+        assert_eq!(cluster_at(&mut img, d.offset), d.first_cluster);
+        assert_eq!(cluster_at(&mut img, sub.offset), d.first_cluster);
+    }
+
+    #[test]
+    fn dirloop_named_dir_to_fat32_root() {
+        let mut img = image(FatType::Fat32);
+        let d = entry(&mut img, "d");
+        let root = volume(&mut img).bpb.root_cluster;
+        apply(
+            &mut img,
+            DirLoop {
+                dir: Some("d".into()),
+                up: 1,
+            },
+        );
+        assert_eq!(cluster_at(&mut img, d.offset), root);
+    }
+
+    #[test]
+    fn dirloop_refuses_fat16_root() {
+        let mut img = image(FatType::Fat16);
+        let mutation = DirLoop {
+            dir: Some("d".into()),
+            up: 1,
+        };
+        assert!(mutation.apply(&mut img, 0).is_err());
+    }
+
+    #[test]
+    fn badshortname_changes_first_byte_by_default() {
+        let mut img = image(FatType::Fat16);
+        let a = entry(&mut img, "a.bin");
+        apply(&mut img, BadShortName::default());
+        assert_eq!(&slot(&mut img, a.offset)[..11], b"*       BIN");
+    }
+
+    #[test]
+    fn badshortname_recomputes_lfn_checksum() {
+        let mut img = image(FatType::Fat16);
+        let offsets = slot_offsets(&mut img, "Long Name File.txt");
+        let (short, lfn) = offsets.split_last().unwrap();
+        assert_eq!(lfn.len(), 2);
+        let old = slot(&mut img, lfn[0])[0x0d];
+        apply(
+            &mut img,
+            BadShortName {
+                entry: Some("long name file.txt".into()),
+                at: 8,
+                byte: b'?',
+            },
+        );
+        let short = slot(&mut img, *short);
+        assert_eq!(short[8], b'?');
+        let mut name = [0u8; 11];
+        name.copy_from_slice(&short[..11]);
+        let checksum = lfn_checksum(&name);
+        assert_ne!(checksum, old);
+        for &offset in lfn {
+            assert_eq!(slot(&mut img, offset)[0x0d], checksum);
+        }
+    }
+
+    #[test]
+    fn delete_marks_first_entry_by_default() {
+        let mut img = image(FatType::Fat16);
+        let a = entry(&mut img, "a.bin");
+        let b = entry(&mut img, "b.bin");
+        apply(&mut img, DeleteEntry::default());
+        assert_eq!(slot(&mut img, a.offset)[0], DELETED_MARKER);
+        assert_eq!(slot(&mut img, b.offset)[0], b'B');
+    }
+
+    #[test]
+    fn delete_named_entry_with_lfn() {
+        let mut img = image(FatType::Fat16);
+        let offsets = slot_offsets(&mut img, "Long Name File.txt");
+        apply(
+            &mut img,
+            DeleteEntry {
+                entry: Some("Long Name File.txt".into()),
+            },
+        );
+        for offset in offsets {
+            assert_eq!(slot(&mut img, offset)[0], DELETED_MARKER);
+        }
+    }
+
+    #[test]
+    fn delete_refuses_last_entry_followed_by_its_children() {
+        // The walk lists d/sub/x.txt after d/sub, the last entry of d
+        let mut img = image(FatType::Fat16);
+        let mutation = DeleteEntry {
+            entry: Some("d/sub".into()),
+        };
+        assert!(mutation.apply(&mut img, 0).is_err());
+        apply(
+            &mut img,
+            DeleteEntry {
+                entry: Some("d/c.bin".into()),
+            },
+        );
+    }
+
+    #[test]
+    fn noend_marks_unused_root_slots_deleted_by_default() {
+        let mut img = image(FatType::Fat16);
+        let slots = volume(&mut img).root_dir().unwrap();
+        let end = slots.iter().position(|slot| slot.bytes[0] == 0).unwrap();
+        apply(&mut img, NoEndMarker::default());
+        for raw in &slots[end..] {
+            assert_eq!(slot(&mut img, raw.offset), [DELETED_MARKER; 32]);
+        }
+        assert_eq!(slot(&mut img, slots[end - 1].offset), slots[end - 1].bytes);
+    }
+
+    #[test]
+    fn noend_named_dir() {
+        let mut img = image(FatType::Fat16);
+        let d = entry(&mut img, "d");
+        let slots = volume(&mut img).dir(d.first_cluster).unwrap();
+        let end = slots.iter().position(|slot| slot.bytes[0] == 0).unwrap();
+        apply(
+            &mut img,
+            NoEndMarker {
+                dir: Some("d".into()),
+                byte: 0x41,
+            },
+        );
+        for raw in &slots[end..] {
+            assert_eq!(slot(&mut img, raw.offset), [0x41; 32]);
+        }
+    }
+}
@@ -0,0 +1,588 @@
+//! Mutations breaking the long file name (VFAT LFN) entries of a
+//! directory entry.
+//!
+//! Each one picks an entry by its image path, or the first one with a
+//! long name in directory order. Short entries and the allocation
+//! table keep their contents unless noted otherwise.
+
+use super::{dir_slots, entry_slots, find_entry, parent_path, Mutation};
+use crate::{
+    disk::Disk,
+    error::{MkimgError, MkimgRes},
+    fat::{
+        lfn_checksum, DirEntry, RawEntry, Volume, DELETED_MARKER, DIR_ENTRY_SIZE, LFN_ATTR,
+        LFN_CHAR_OFFSETS, LFN_LAST_FLAG,
+    },
+};
+use std::{
+    io::{Read, Seek},
+    ops::Range,
+};
+
+/// Mask of the sequence number in the first byte of an LFN entry.
+const LFN_SEQ_MASK: u8 = 0x1f;
+
+/// Longest name the sequence numbers can describe, in UTF-16 units.
+pub const MAX_LFN_UNITS: usize = LFN_SEQ_MASK as usize * LFN_CHAR_OFFSETS.len();
+
+/// Gives LFN entries a checksum that does not match their short name.
+#[derive(Debug, Clone, Default)]
+pub struct LfnChecksum {
+    /// Entry to change.
+    pub entry: Option<String>,
+    /// Sequence number of the only LFN entry to change. All of them
+    /// change if not set.
+    pub seq: Option<u8>,
+}
+
+impl Mutation for LfnChecksum {
+    fn apply(&self, img: &mut dyn Disk, volume_offset: u64) -> MkimgRes<String> {
+        let mut volume = Volume::open(img, volume_offset)?;
+        let LfnEntry {
+            path,
+            mut slots,
+            lfn,
+            ..
+        } = lfn_entry(&mut volume, self.entry.as_deref(), 1)?;
+        if let Some(seq) = self.seq {
+            seq_slot(&slots[lfn.clone()], seq)?;
+        }
+        for slot in &mut slots[lfn] {
+            if self
+                .seq
+                .is_none_or(|seq| slot.bytes[0] & LFN_SEQ_MASK == seq)
+            {
+                slot.bytes[0x0d] = slot.bytes[0x0d].wrapping_add(1);
+                volume.write_at(slot.offset, &slot.bytes)?;
+            }
+        }
+        Ok(format!("Broke the LFN checksum of {path}"))
+    }
+}
+
+/// Renumbers the LFN entries of an entry so one sequence number is
+/// missing.
+#[derive(Debug, Clone)]
+pub struct LfnGap {
+    /// Entry to change.
+    pub entry: Option<String>,
+    /// Sequence number to leave out. Entries from it on move up by one.
+    pub seq: u8,
+}
+
+impl Default for LfnGap {
+    fn default() -> Self {
+        Self {
+            entry: None,
+            seq: 1,
+        }
+    }
+}
+
+impl Mutation for LfnGap {
+    fn apply(&self, img: &mut dyn Disk, volume_offset: u64) -> MkimgRes<String> {
+        let mut volume = Volume::open(img, volume_offset)?;
+        let LfnEntry {
+            path,
+            mut slots,
+            lfn,
+            ..
+        } = lfn_entry(&mut volume, self.entry.as_deref(), 1)?;
+        seq_slot(&slots[lfn.clone()], self.seq)?;
+        if lfn.len() >= usize::from(LFN_SEQ_MASK) {
+            return Err(MkimgError::validation(format!(
+                "'{path}' has no sequence number left to move to"
+            )));
+        }
+        for slot in &mut slots[lfn] {
+            if slot.bytes[0] & LFN_SEQ_MASK >= self.seq {
+                slot.bytes[0] += 1;
+                volume.write_at(slot.offset, &slot.bytes)?;
+            }
+        }
+        Ok(format!(
+            "Left sequence number {} out of the LFN of {path}",
+            self.seq
+        ))
+    }
+}
+
+/// Swaps the sequence numbers of two neighbouring LFN entries, so
+/// they are stored out of order.
+#[derive(Debug, Clone)]
+pub struct LfnSwap {
+    /// Entry to change. Defaults to the first with at least two LFN
+    /// entries.
+    pub entry: Option<String>,
+    /// Sequence number swapped with the one following it.
+    pub seq: u8,
+}
+
+impl Default for LfnSwap {
+    fn default() -> Self {
+        Self {
+            entry: None,
+            seq: 1,
+        }
+    }
+}
+
+impl Mutation for LfnSwap {
+    fn apply(&self, img: &mut dyn Disk, volume_offset: u64) -> MkimgRes<String> {
+        let mut volume = Volume::open(img, volume_offset)?;
+        let LfnEntry {
+            path,
+            mut slots,
+            lfn,
+            ..
+        } = lfn_entry(&mut volume, self.entry.as_deref(), 2)?;
+        let lfn = &mut slots[lfn];
+        let first = seq_slot(lfn, self.seq)?;
+        let second = seq_slot(lfn, self.seq.saturating_add(1))?;
+        // The flag marking the last entry stays where it was
+        lfn[first].bytes[0] += 1;
+        lfn[second].bytes[0] -= 1;
+        for slot in [&lfn[first], &lfn[second]] {
+            volume.write_at(slot.offset, &slot.bytes)?;
+        }
+        Ok(format!(
+            "Swapped LFN sequence numbers {} and {} of {path}",
+            self.seq,
+            self.seq + 1
+        ))
+    }
+}
+
+/// Marks the short entry of an entry deleted, leaving its LFN
+/// entries with nothing to belong to. Its clusters stay allocated.
+#[derive(Debug, Clone, Default)]
+pub struct LfnOrphan {
+    /// Entry to change.
+    pub entry: Option<String>,
+}
+
+impl Mutation for LfnOrphan {
+    fn apply(&self, img: &mut dyn Disk, volume_offset: u64) -> MkimgRes<String> {
+        let mut volume = Volume::open(img, volume_offset)?;
+        let LfnEntry {
+            path, slots, lfn, ..
+        } = lfn_entry(&mut volume, self.entry.as_deref(), 1)?;
+        volume.write_at(slots[lfn.end].offset, &[DELETED_MARKER])?;
+        Ok(format!("Orphaned the LFN entries of {path}"))
+    }
+}
+
+/// Stores a long name longer than the 255 UTF-16 units FAT allows.
+/// Later entries of the directory move to make room.
+#[derive(Debug, Clone)]
+pub struct LfnTooLong {
+    /// Entry to change. Defaults to the first entry, with or without a
+    /// long name.
+    pub entry: Option<String>,
+    /// Length of the new name in UTF-16 units, at most
+    /// [`MAX_LFN_UNITS`]. The current name is padded with `x` before
+    /// its extension.
+    pub len: usize,
+}
+
+impl Default for LfnTooLong {
+    fn default() -> Self {
+        Self {
+            entry: None,
+            len: 256,
+        }
+    }
+}
+
+impl Mutation for LfnTooLong {
+    fn apply(&self, img: &mut dyn Disk, volume_offset: u64) -> MkimgRes<String> {
+        if !(1..=MAX_LFN_UNITS).contains(&self.len) {
+            return Err(MkimgError::validation(format!(
+                "LFN entries hold 1 to {MAX_LFN_UNITS} UTF-16 units"
+            )));
+        }
+        let mut volume = Volume::open(img, volume_offset)?;
+        let entries = volume.walk()?;
+        let (path, entry) = find_entry(&entries, self.entry.as_deref(), "an entry", |_, entry| {
+            !entry.is_dot()
+        })?;
+        let slots = dir_slots(&mut volume, &entries, parent_path(path))?;
+        let range = entry_slots(&slots, entry.offset)?;
+        let short = &slots[range.end - 1];
+
+        let mut units = padded_name(entry.name(), self.len);
+        units.truncate(self.len);
+        let mut name = [0u8; 11];
+        name.copy_from_slice(&short.bytes[..11]);
+        let mut contents: Vec<[u8; DIR_ENTRY_SIZE]> =
+            slots[..range.start].iter().map(|slot| slot.bytes).collect();
+        contents.extend(lfn_slots(&units, lfn_checksum(&name)));
+        contents.push(short.bytes);
+        let used = slots
+            .iter()
+            .position(|slot| slot.bytes[0] == 0)
+            .unwrap_or(slots.len());
+        contents.extend(slots[range.end..used].iter().map(|slot| slot.bytes));
+        if contents.len() > slots.len() {
+            return Err(MkimgError::validation(format!(
+                "the directory holding '{path}' has no room for {} more entries",
+                contents.len() - slots.len()
+            )));
+        }
+        // Slots no longer used end the directory
+        contents.resize(contents.len().max(used), [0; DIR_ENTRY_SIZE]);
+        for (slot, bytes) in slots.iter().zip(&contents) {
+            if slot.bytes != *bytes {
+                volume.write_at(slot.offset, bytes)?;
+            }
+        }
+        Ok(format!(
+            "Gave {path} a long name of {} UTF-16 units",
+            self.len
+        ))
+    }
+}
+
+/// Puts a UTF-16 surrogate without its other half into a long name.
+#[derive(Debug, Clone)]
+pub struct LfnSurrogate {
+    /// Entry to change.
+    pub entry: Option<String>,
+    /// Position of the replaced unit in the name.
+    pub at: usize,
+    /// Surrogate to write, high (0xd800-0xdbff) or low (0xdc00-0xdfff).
+    pub unit: u16,
+}
+
+impl Default for LfnSurrogate {
+    fn default() -> Self {
+        Self {
+            entry: None,
+            at: 0,
+            unit: 0xd800,
+        }
+    }
+}
+
+impl Mutation for LfnSurrogate {
+    fn apply(&self, img: &mut dyn Disk, volume_offset: u64) -> MkimgRes<String> {
+        let mut volume = Volume::open(img, volume_offset)?;
+        let LfnEntry {
+            path,
+            len,
+            mut slots,
+            lfn,
+        } = lfn_entry(&mut volume, self.entry.as_deref(), 1)?;
+        if self.at >= len {
+            return Err(MkimgError::validation(format!(
+                "the long name of '{path}' is {len} UTF-16 units"
+            )));
+        }
+        let lfn = &mut slots[lfn];
+        let per_slot = LFN_CHAR_OFFSETS.len();
+        let seq = u8::try_from(self.at / per_slot + 1).unwrap_or(u8::MAX);
+        let idx = seq_slot(lfn, seq)?;
+        let offset = LFN_CHAR_OFFSETS[self.at % per_slot];
+        let slot = &mut lfn[idx];
+        slot.bytes[offset..offset + 2].copy_from_slice(&self.unit.to_le_bytes());
+        volume.write_at(slot.offset, &slot.bytes)?;
+        Ok(format!(
+            "Replaced UTF-16 unit {} of the long name of {path} with {:#06x}",
+            self.at, self.unit
+        ))
+    }
+}
+
+// An entry with a long name and the slots of its directory
+struct LfnEntry {
+    path: String,
+    // Length of the long name in UTF-16 units
+    len: usize,
+    slots: Vec<RawEntry>,
+    // Slots of its LFN entries, followed by its short entry
+    lfn: Range<usize>,
+}
+
+// The entry at `path`, or the first with a long name stored in at
+// least `min_slots` LFN entries
+fn lfn_entry<T: Read + Seek>(
+    volume: &mut Volume<T>,
+    path: Option<&str>,
+    min_slots: usize,
+) -> MkimgRes<LfnEntry> {
+    let entries = volume.walk()?;
+    let lfn_slots = |entry: &DirEntry| {
+        entry.long_name.as_ref().map_or(0, |name| {
+            name.encode_utf16().count().div_ceil(LFN_CHAR_OFFSETS.len())
+        })
+    };
+    let what = match min_slots {
+        1 => "an entry with a long name".to_string(),
+        _ => format!("an entry with {min_slots} or more LFN entries"),
+    };
+    let (path, entry) = find_entry(&entries, path, &what, |_, entry| {
+        !entry.is_dot() && lfn_slots(entry) >= min_slots
+    })?;
+    let slots = dir_slots(volume, &entries, parent_path(path))?;
+    let range = entry_slots(&slots, entry.offset)?;
+    Ok(LfnEntry {
+        path: path.clone(),
+        len: entry.name().encode_utf16().count(),
+        slots,
+        lfn: range.start..range.end - 1,
+    })
+}
+
+// Index of the LFN entry with sequence number `seq`
+fn seq_slot(lfn: &[RawEntry], seq: u8) -> MkimgRes<usize> {
+    lfn.iter()
+        .position(|slot| slot.bytes[0] & LFN_SEQ_MASK == seq)
+        .ok_or_else(|| {
+            MkimgError::validation(format!(
+                "no LFN entry has sequence number {seq}, there are {}",
+                lfn.len()
+            ))
+        })
+}
+
+// `name` as UTF-16 grown to `len` units with `x` before its extension
+fn padded_name(name: &str, len: usize) -> Vec<u16> {
+    let (stem, ext) = match name.rfind('.') {
+        Some(dot) if dot > 0 => name.split_at(dot),
+        _ => (name, ""),
+    };
+    let mut units: Vec<u16> = stem.encode_utf16().collect();
+    let ext: Vec<u16> = ext.encode_utf16().collect();
+    let fill = len.saturating_sub(units.len() + ext.len());
+    units.extend(std::iter::repeat_n(u16::from(b'x'), fill));
+    units.extend(ext);
+    units
+}
+
+// LFN entries holding `units`, in on-disk order
+fn lfn_slots(units: &[u16], checksum: u8) -> Vec<[u8; DIR_ENTRY_SIZE]> {
+    let per_slot = LFN_CHAR_OFFSETS.len();
+    let count = units.len().div_ceil(per_slot);
+    // A name not filling its last entry is ended by a NUL and padded
+    // with 0xffff
+    let mut padded = units.to_vec();
+    if padded.len() < count * per_slot {
+        padded.push(0);
+        padded.resize(count * per_slot, 0xffff);
+    }
+    (1..=count)
+        .rev()
+        .map(|seq| {
+            let mut bytes = [0u8; DIR_ENTRY_SIZE];
+            bytes[0] = seq as u8;
+            if seq == count {
+                bytes[0] |= LFN_LAST_FLAG;
+            }
+            bytes[0x0b] = LFN_ATTR;
+            bytes[0x0d] = checksum;
+            let chunk = &padded[(seq - 1) * per_slot..seq * per_slot];
+            for (&offset, unit) in LFN_CHAR_OFFSETS.iter().zip(chunk) {
+                bytes[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+            }
+            bytes
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mutation::test_util::{apply, entry, image, raw_slots, slot, Img},
+        FatType,
+    };
+
+    const LONG: &str = "Long Name File.txt";
+
+    // Current bytes of `slots`, which may no longer belong to an entry
+    fn reread(img: &mut Img, slots: &[RawEntry]) -> Vec<[u8; DIR_ENTRY_SIZE]> {
+        slots.iter().map(|raw| slot(img, raw.offset)).collect()
+    }
+
+    fn first_bytes(slots: &[[u8; DIR_ENTRY_SIZE]]) -> Vec<u8> {
+        slots.iter().map(|bytes| bytes[0]).collect()
+    }
+
+    #[test]
+    fn checksum_breaks_first_long_name_by_default() {
+        let mut img = image(FatType::Fat16);
+        let before = raw_slots(&mut img, "a.bin");
+        apply(&mut img, LfnChecksum::default());
+        let after = reread(&mut img, &before);
+        assert_eq!(after[0][0x0d], before[0].bytes[0x0d].wrapping_add(1));
+        assert_eq!(after[1], before[1].bytes);
+    }
+
+    #[test]
+    fn checksum_named_entry_single_seq() {
+        let mut img = image(FatType::Fat16);
+        let before = raw_slots(&mut img, LONG);
+        apply(
+            &mut img,
+            LfnChecksum {
+                entry: Some(LONG.into()),
+                seq: Some(2),
+            },
+        );
+        let after = reread(&mut img, &before);
+        // Slots are stored from the last sequence number down
+        assert_eq!(after[0][0x0d], before[0].bytes[0x0d].wrapping_add(1));
+        assert_eq!(after[1], before[1].bytes);
+    }
+
+    #[test]
+    fn gap_leaves_out_seq_1_by_default() {
+        let mut img = image(FatType::Fat16);
+        let before = raw_slots(&mut img, "a.bin");
+        apply(&mut img, LfnGap::default());
+        assert_eq!(first_bytes(&reread(&mut img, &before)[..1]), [0x42]);
+    }
+
+    #[test]
+    fn gap_named_entry_seq_2() {
+        let mut img = image(FatType::Fat16);
+        let before = raw_slots(&mut img, LONG);
+        apply(
+            &mut img,
+            LfnGap {
+                entry: Some(LONG.into()),
+                seq: 2,
+            },
+        );
+        assert_eq!(first_bytes(&reread(&mut img, &before)[..2]), [0x43, 0x01]);
+    }
+
+    #[test]
+    fn swap_first_entry_with_two_lfn_slots_by_default() {
+        let mut img = image(FatType::Fat16);
+        let before = raw_slots(&mut img, LONG);
+        assert_eq!(
+            first_bytes(&[before[0].bytes, before[1].bytes]),
+            [0x42, 0x01]
+        );
+        apply(&mut img, LfnSwap::default());
+        assert_eq!(first_bytes(&reread(&mut img, &before)[..2]), [0x41, 0x02]);
+    }
+
+    #[test]
+    fn swap_named_entry_needs_following_seq() {
+        let mut img = image(FatType::Fat16);
+        let mutation = LfnSwap {
+            entry: Some(LONG.into()),
+            seq: 2,
+        };
+        assert!(mutation.apply(&mut img, 0).is_err());
+        let mutation = LfnSwap {
+            entry: Some("b.bin".into()),
+            seq: 1,
+        };
+        assert!(mutation.apply(&mut img, 0).is_err());
+    }
+
+    #[test]
+    fn orphan_deletes_short_slot_of_first_long_name_by_default() {
+        let mut img = image(FatType::Fat16);
+        let before = raw_slots(&mut img, "a.bin");
+        apply(&mut img, LfnOrphan::default());
+        let after = reread(&mut img, &before);
+        assert_eq!(after[0], before[0].bytes);
+        assert_eq!(after[1][0], DELETED_MARKER);
+        assert_eq!(after[1][1..], before[1].bytes[1..]);
+    }
+
+    #[test]
+    fn orphan_named_entry() {
+        let mut img = image(FatType::Fat16);
+        let before = raw_slots(&mut img, LONG);
+        apply(
+            &mut img,
+            LfnOrphan {
+                entry: Some(LONG.into()),
+            },
+        );
+        let after = reread(&mut img, &before);
+        assert_eq!(first_bytes(&after), [0x42, 0x01, DELETED_MARKER]);
+    }
+
+    #[test]
+    fn toolong_rewrites_first_entry_by_default() {
+        let mut img = image(FatType::Fat16);
+        let b = entry(&mut img, "b.bin");
+        apply(&mut img, LfnTooLong::default());
+        let slots = raw_slots(&mut img, &format!("a{}.bin", "x".repeat(251)));
+        let (short, lfn) = slots.split_last().unwrap();
+        // 256 units and a NUL in 13 unit slots
+        assert_eq!(lfn.len(), 20);
+        assert_eq!(lfn[0].bytes[0], LFN_LAST_FLAG | 20);
+        let mut name = [0u8; 11];
+        name.copy_from_slice(&short.bytes[..11]);
+        for slot in lfn {
+            assert_eq!(slot.bytes[0x0b], LFN_ATTR);
+            assert_eq!(slot.bytes[0x0d], lfn_checksum(&name));
+        }
+        // Later entries moved, keeping their contents
+        let moved = entry(&mut img, "b.bin");
+        assert_ne!(moved.offset, b.offset);
+        assert_eq!(moved.first_cluster, b.first_cluster);
+        assert_eq!(moved.size, b.size);
+    }
+
+    #[test]
+    fn toolong_named_entry_needs_room() {
+        let mut img = image(FatType::Fat16);
+        let mutation = LfnTooLong {
+            entry: Some("d/c.bin".into()),
+            len: 300,
+        };
+        assert!(mutation.apply(&mut img, 0).is_err());
+        apply(
+            &mut img,
+            LfnTooLong {
+                entry: Some("d/c.bin".into()),
+                len: 40,
+            },
+        );
+        let slots = raw_slots(&mut img, &format!("d/c{}.bin", "x".repeat(35)));
+        assert_eq!(slots.len(), 5);
+        assert_eq!(entry(&mut img, "d/sub/x.txt").size, 100);
+    }
+
+    #[test]
+    fn surrogate_replaces_first_unit_by_default() {
+        let mut img = image(FatType::Fat16);
+        let before = raw_slots(&mut img, "a.bin");
+        apply(&mut img, LfnSurrogate::default());
+        let after = reread(&mut img, &before);
+        let offset = LFN_CHAR_OFFSETS[0];
+        assert_eq!(after[0][offset..offset + 2], 0xd800u16.to_le_bytes());
+    }
+
+    #[test]
+    fn surrogate_named_entry_in_second_slot() {
+        let mut img = image(FatType::Fat16);
+        let before = raw_slots(&mut img, LONG);
+        apply(
+            &mut img,
+            LfnSurrogate {
+                entry: Some(LONG.into()),
+                at: 14,
+                unit: 0xdc00,
+            },
+        );
+        let after = reread(&mut img, &before);
+        let offset = LFN_CHAR_OFFSETS[1];
+        assert_eq!(after[0][offset..offset + 2], 0xdc00u16.to_le_bytes());
+        assert_eq!(after[1], before[1].bytes);
+        let mutation = LfnSurrogate {
+            entry: Some(LONG.into()),
+            at: LONG.len(),
+            unit: 0xdc00,
+        };
+        assert!(mutation.apply(&mut img, 0).is_err());
+    }
+}
@@ -0,0 +1,348 @@
+//! Image creation options.
+
+use crate::{
+    error::{MkimgError, MkimgRes},
+    fat::Timestamp,
+    size::DEFAULT_SLACK,
+};
+use fatfs::FatType;
+use std::str::FromStr;
+use uuid::Uuid;
+
+/// Total size of the created volume.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VolumeSize {
+    /// Size in bytes, rounded down to whole sectors.
+    Bytes(u64),
+    /// Size in 512 byte sectors.
+    Sectors(u32),
+}
+
+/// How the FAT volume is laid out on the disk image.
+#[derive(Debug, Clone, Default)]
+pub enum Layout {
+    /// A bare FAT volume with no partition table.
+    #[default]
+    Superfloppy,
+    /// A GPT disk with the FAT volume as its EFI System Partition.
+    Gpt(GptOptions),
+    /// An MBR disk with the FAT volume as its first primary partition.
+    Mbr(MbrOptions),
+}
+
+/// Options for GPT partitioned images.
+#[derive(Debug, Clone)]
+pub struct GptOptions {
+    /// Disk GUID. Randomly generated if `None`.
+    pub disk_guid: Option<Uuid>,
+    /// Unique GUID of the EFI System Partition. Randomly generated if
+    /// `None`.
+    pub partition_guid: Option<Uuid>,
+    /// Alignment of the partition start, in bytes. Must be a non-zero
+    /// multiple of 512.
+    pub alignment: u64,
+}
+
+impl Default for GptOptions {
+    fn default() -> Self {
+        Self {
+            disk_guid: None,
+            partition_guid: None,
+            alignment: DEFAULT_ALIGNMENT,
+        }
+    }
+}
+
+/// Options for MBR partitioned images.
+#[derive(Debug, Clone)]
+pub struct MbrOptions {
+    /// Partition type byte. If `None` it is derived from the FAT type:
+    /// 0x01 for FAT12, 0x06 for FAT16, and 0x0B or 0x0C (LBA) for
+    /// FAT32. Use [`MBR_ESP_TYPE`] for an EFI System Partition.
+    pub partition_type: Option<u8>,
+    /// Mark the partition active (bootable).
+    pub active: bool,
+    /// Disk signature. Randomly generated if `None`.
+    pub disk_signature: Option<u32>,
+    /// Alignment of the partition start, in bytes. Must be a non-zero
+    /// multiple of 512.
+    pub alignment: u64,
+}
+
+impl Default for MbrOptions {
+    fn default() -> Self {
+        Self {
+            partition_type: None,
+            active: false,
+            disk_signature: None,
+            alignment: DEFAULT_ALIGNMENT,
+        }
+    }
+}
+
+/// MBR partition type of an EFI System Partition.
+pub const MBR_ESP_TYPE: u8 = 0xEF;
+
+/// Default partition alignment (1MiB).
+pub const DEFAULT_ALIGNMENT: u64 = 1024 * 1024;
+
+/// Options controlling the geometry of a created image.
+///
+/// Every field left as `None` is chosen automatically, either from
+/// the size of the mapped files or using the same defaults as
+/// `fatfs::format_volume`.
+#[derive(Debug, Clone)]
+pub struct CreateOptions {
+    /// Total volume size. If `None` the volume is sized to fit its
+    /// contents plus `slack`.
+    pub size: Option<VolumeSize>,
+    /// Free space, in bytes, to leave when sizing automatically.
+    pub slack: u64,
+    /// FAT variant. If `None` the smallest variant that fits is used.
+    pub fat_type: Option<FatType>,
+    /// Cluster size in bytes. Must be a power of two between 512 and
+    /// 65536.
+    pub bytes_per_cluster: Option<u32>,
+    /// Reserved sectors before the first FAT. Must be at least 1 for
+    /// FAT12/FAT16 and 8 for FAT32.
+    pub reserved_sectors: Option<u16>,
+    /// Number of FAT copies, 1 or 2.
+    pub fats: Option<u8>,
+    /// Root directory entries on FAT12/FAT16 volumes. Must be a
+    /// multiple of 16. Ignored for FAT32.
+    pub root_entries: Option<u16>,
+    /// Partitioning of the disk image.
+    pub layout: Layout,
+    /// Timestamp given to every file and directory. Setting it makes
+    /// the image reproducible: partition table IDs left unset are
+    /// derived from the volume contents instead of being random. See
+    /// [`Timestamp::source_date_epoch`].
+    pub timestamp: Option<Timestamp>,
+    /// Volume serial number. If `None` it is derived from `timestamp`,
+    /// or the current time, the way DOS `FORMAT` does.
+    pub volume_id: Option<u32>,
+    /// Copy host modification and access times to mapped files and
+    /// directories, overriding `timestamp`. The creation time is the
+    /// host birth time where available, the modification time
+    /// otherwise. Host times are stored as UTC.
+    pub preserve_times: bool,
+    /// Derive FAT attributes from the host: files without write
+    /// permission become read-only, and entries whose name starts with
+    /// a dot become hidden.
+    pub preserve_attributes: bool,
+    /// Misreport the volume size in the boot sector and FSInfo
+    /// sector. `create_deceptive_img` uses the defaults if `None`.
+    pub deception: Option<DeceptionOptions>,
+}
+
+impl Default for CreateOptions {
+    fn default() -> Self {
+        Self {
+            size: None,
+            slack: DEFAULT_SLACK,
+            fat_type: None,
+            bytes_per_cluster: None,
+            reserved_sectors: None,
+            fats: None,
+            root_entries: None,
+            layout: Layout::Superfloppy,
+            timestamp: None,
+            volume_id: None,
+            preserve_times: false,
+            preserve_attributes: false,
+            deception: None,
+        }
+    }
+}
+
+/// How a deceptive image misreports one value.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Lie {
+    /// Leave the real value.
+    Keep,
+    /// Multiply the real value by a non-negative factor.
+    Scale(f64),
+    /// Replace the real value.
+    Set(u32),
+}
+
+impl Lie {
+    /// The value told instead of `value`, saturating at `u32::MAX`.
+    pub fn apply(self, value: u32) -> u32 {
+        match self {
+            Lie::Keep => value,
+            // Float to int casts saturate
+            Lie::Scale(factor) => (f64::from(value) * factor) as u32,
+            Lie::Set(fake) => fake,
+        }
+    }
+}
+
+/// Parses a factor such as `1.5x`, an absolute value in decimal or
+/// with a `0x` prefix in hexadecimal, or `keep`.
+impl FromStr for Lie {
+    type Err = MkimgError;
+
+    fn from_str(s: &str) -> MkimgRes<Self> {
+        if s.eq_ignore_ascii_case("keep") {
+            return Ok(Lie::Keep);
+        }
+        let parsed = match s.strip_suffix(['x', 'X']) {
+            Some(factor) => factor
+                .parse::<f64>()
+                .ok()
+                .filter(|factor| factor.is_finite() && *factor >= 0.0)
+                .map(Lie::Scale),
+            None => parse_number(s).ok().map(Lie::Set),
+        };
+        parsed.ok_or_else(|| MkimgError::validation(format!("invalid value '{s}'")))
+    }
+}
+
+/// Parses an integer given in decimal or, with a `0x` prefix, in
+/// hexadecimal.
+///
+/// # Errors
+///
+/// Returns error if `s` is not a valid integer or does not fit in `T`
+pub fn parse_number<T: TryFrom<u64>>(s: &str) -> MkimgRes<T> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+    .and_then(|n| T::try_from(n).ok())
+    .ok_or_else(|| MkimgError::validation(format!("invalid value '{s}'")))
+}
+
+/// Which size fields of a volume lie, and by how much.
+///
+/// The defaults make the volume claim 1.5 times its real size and 3
+/// times its real free space.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DeceptionOptions {
+    /// Total sector count in the boot sector. FAT12/FAT16 volumes keep
+    /// a count that fits in the 16-bit field there, and use the 32-bit
+    /// field otherwise.
+    pub total_sectors: Lie,
+    /// Free cluster count in the FSInfo sector. An unknown count
+    /// (0xFFFFFFFF) is only replaced by [`Lie::Set`]. FAT32 only.
+    pub free_clusters: Lie,
+    /// Next free cluster hint in the FSInfo sector. FAT32 only.
+    pub next_free: Lie,
+}
+
+impl Default for DeceptionOptions {
+    fn default() -> Self {
+        Self {
+            total_sectors: Lie::Scale(1.5),
+            free_clusters: Lie::Scale(3.0),
+            next_free: Lie::Keep,
+        }
+    }
+}
+
+/// Options for modifying an existing image with [`edit`](fn@crate::edit).
+#[derive(Debug, Clone, Default)]
+pub struct EditOptions {
+    /// Timestamp given to added, replaced and newly created entries.
+    /// If `None` they get the current time.
+    pub timestamp: Option<Timestamp>,
+    /// Copy host modification and access times to added entries, as
+    /// [`CreateOptions::preserve_times`] does.
+    pub preserve_times: bool,
+    /// Derive FAT attributes of added entries from the host, as
+    /// [`CreateOptions::preserve_attributes`] does.
+    pub preserve_attributes: bool,
+}
+
+/// Name of the file holding exclude patterns in a scanned directory.
+pub const IGNORE_FILE: &str = ".mkimgignore";
+
+/// How `create_mappings` treats symbolic links.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Map the file or directory the link points to.
+    #[default]
+    Follow,
+    /// Leave links out of the image.
+    Skip,
+    /// Fail on the first link found.
+    Error,
+}
+
+/// Options controlling which host files `create_mappings` picks up.
+///
+/// Patterns are globs matched against `/` separated paths relative to
+/// the scanned directory. A pattern without a `/` matches a name at
+/// any depth (e.g. `*.swp` or `.git`), a leading `/` anchors it to the
+/// scanned directory, and a trailing `/` is ignored.
+#[derive(Debug, Clone)]
+pub struct ScanOptions {
+    /// If not empty, only entries matching one of these patterns, or
+    /// inside a matching directory, are mapped. Directories are kept
+    /// if they contain a mapped entry.
+    pub include: Vec<String>,
+    /// Entries matching any of these patterns are not mapped. An
+    /// excluded directory is skipped with all of its contents.
+    pub exclude: Vec<String>,
+    /// Read further exclude patterns, one per line, from the
+    /// [`IGNORE_FILE`] in the scanned directory if there is one. Blank
+    /// lines and lines starting with `#` are ignored, and the file
+    /// itself is never mapped.
+    pub ignore_file: bool,
+    /// Skip entries more than this many levels below the scanned
+    /// directory. Unlimited if `None`.
+    pub max_depth: Option<usize>,
+    /// What to do with symbolic links.
+    pub symlinks: SymlinkPolicy,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        Self {
+            include: Vec::new(),
+            exclude: Vec::new(),
+            ignore_file: true,
+            max_depth: None,
+            symlinks: SymlinkPolicy::Follow,
+        }
+    }
+}
+
+/// Default largest file whose contents are captured by `examine`.
+pub const DEFAULT_CONTENT_LIMIT: u64 = 200_000;
+
+/// Options controlling how much of an image `examine` reads.
+#[derive(Debug, Clone)]
+pub struct ExamineOptions {
+    /// Deepest directory level to descend into, with the root
+    /// directory at level 0. Unlimited if `None`.
+    pub max_depth: Option<usize>,
+    /// Capture the contents of files no larger than this many bytes.
+    /// No contents are captured if `None`.
+    pub content_limit: Option<u64>,
+    /// Count the free clusters of each volume, which reads its whole
+    /// FAT.
+    pub free_space: bool,
+}
+
+impl ExamineOptions {
+    /// The full tree without any file contents or free space.
+    pub fn listing() -> Self {
+        Self {
+            max_depth: None,
+            content_limit: None,
+            free_space: false,
+        }
+    }
+}
+
+impl Default for ExamineOptions {
+    fn default() -> Self {
+        Self {
+            max_depth: None,
+            content_limit: Some(DEFAULT_CONTENT_LIMIT),
+            free_space: true,
+        }
+    }
+}
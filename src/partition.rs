@@ -0,0 +1,808 @@
+//! Partition table reading and writing.
+//!
+//! When creating an image the FAT volume is always written first; the
+//! partition table is then laid out around it according to the
+//! requested [`Layout`]. When examining one, the partition table is
+//! detected from the image itself.
+
+use crate::{
+    error::{MkimgError, MkimgRes},
+    fat,
+    options::{GptOptions, Layout, MbrOptions},
+    size::{Geometry, BYTES_PER_SECTOR},
+};
+use fatfs::FatType;
+use serde::Serialize;
+use std::{
+    collections::HashSet,
+    io::{Read, Seek, SeekFrom, Write},
+};
+use uuid::Uuid;
+
+/// EFI System Partition type GUID.
+pub const ESP_TYPE_GUID: Uuid = uuid::uuid!("C12A7328-F81F-11D2-BA4B-00A0C93EC93B");
+
+/// MBR partition type used for the GPT protective partition.
+const GPT_PROTECTIVE_TYPE: u8 = 0xEE;
+
+/// Entries in the GPT partition entry array.
+const GPT_ENTRIES: u32 = 128;
+
+/// Size of a single GPT partition entry.
+const GPT_ENTRY_SIZE: u32 = 128;
+
+/// Size of the GPT header.
+const GPT_HEADER_SIZE: u32 = 92;
+
+/// Sectors taken by the GPT partition entry array.
+const GPT_ENTRY_SECTORS: u64 = (GPT_ENTRIES * GPT_ENTRY_SIZE / BYTES_PER_SECTOR) as u64;
+
+/// Largest GPT partition entry read from an image.
+const GPT_MAX_ENTRY_SIZE: u32 = 4096;
+
+/// Largest GPT partition entry array read from an image, in bytes.
+const GPT_MAX_ENTRIES_BYTES: u32 = 1 << 20;
+
+/// MBR partition types derived from the FAT type.
+const MBR_FAT12_TYPE: u8 = 0x01;
+const MBR_FAT16_TYPE: u8 = 0x06;
+const MBR_FAT32_CHS_TYPE: u8 = 0x0B;
+const MBR_FAT32_LBA_TYPE: u8 = 0x0C;
+
+/// CHS geometry used for MBR partition entries.
+const CHS_HEADS: u64 = 255;
+const CHS_SECTORS_PER_TRACK: u64 = 63;
+const CHS_MAX_CYLINDER: u64 = 1023;
+
+/// Name given to the EFI System Partition.
+const ESP_NAME: &str = "EFI System Partition";
+
+/// MBR partition types of extended partitions holding logical ones.
+const MBR_EXTENDED_TYPES: [u8; 3] = [0x05, 0x0f, 0x85];
+
+/// Partitioning scheme found on an existing image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PartitionScheme {
+    /// A bare FAT volume with no partition table.
+    Superfloppy,
+    Mbr {
+        disk_signature: u32,
+    },
+    Gpt {
+        disk_guid: Uuid,
+    },
+}
+
+/// A partition found on an existing image.
+#[derive(Debug, Clone, Serialize)]
+pub struct Partition {
+    /// Partition number, starting at 1. MBR logical partitions are
+    /// numbered from 5.
+    pub number: u32,
+    pub start_lba: u64,
+    pub sectors: u64,
+    pub kind: PartitionKind,
+}
+
+impl Partition {
+    /// Byte offset of the partition. [`detect`] only returns GPT
+    /// partitions within the disk and MBR ones have 32 bit LBAs, so
+    /// this cannot overflow.
+    pub(crate) fn offset(&self) -> u64 {
+        self.start_lba * u64::from(BYTES_PER_SECTOR)
+    }
+}
+
+/// Partition table specific details of a [`Partition`].
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PartitionKind {
+    Mbr {
+        partition_type: u8,
+        active: bool,
+    },
+    Gpt {
+        type_guid: Uuid,
+        partition_guid: Uuid,
+        name: String,
+    },
+}
+
+/// Where the FAT volume sits in the disk image.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Placement {
+    /// First sector of the FAT volume.
+    pub volume_start: u64,
+    /// Size of the FAT volume in sectors.
+    pub volume_sectors: u64,
+    /// Total size of the disk image in sectors.
+    pub disk_sectors: u64,
+}
+
+impl Placement {
+    pub fn volume_offset(&self) -> u64 {
+        self.volume_start * u64::from(BYTES_PER_SECTOR)
+    }
+
+    pub fn volume_end(&self) -> u64 {
+        (self.volume_start + self.volume_sectors) * u64::from(BYTES_PER_SECTOR)
+    }
+
+    pub fn disk_bytes(&self) -> u64 {
+        self.disk_sectors * u64::from(BYTES_PER_SECTOR)
+    }
+}
+
+/// Computes where a volume of `volume_sectors` goes for `layout`.
+pub(crate) fn place(layout: &Layout, volume_sectors: u32) -> MkimgRes<Placement> {
+    let volume_sectors = u64::from(volume_sectors);
+    match layout {
+        Layout::Superfloppy => Ok(Placement {
+            volume_start: 0,
+            volume_sectors,
+            disk_sectors: volume_sectors,
+        }),
+        Layout::Gpt(gpt) => {
+            let alignment = alignment_sectors(gpt.alignment)?;
+            // Protective MBR, primary header and entry array
+            let first_usable = 2 + GPT_ENTRY_SECTORS;
+            let volume_start = first_usable.next_multiple_of(alignment);
+            Ok(Placement {
+                volume_start,
+                volume_sectors,
+                // Backup entry array and header
+                disk_sectors: volume_start + volume_sectors + GPT_ENTRY_SECTORS + 1,
+            })
+        }
+        Layout::Mbr(mbr) => {
+            let alignment = alignment_sectors(mbr.alignment)?;
+            // The MBR itself occupies the first sector
+            let volume_start = 1u64.next_multiple_of(alignment);
+            if volume_start + volume_sectors > u64::from(u32::MAX) {
+                return Err(MkimgError::validation(
+                    "MBR partitions must end within the first 2TiB",
+                ));
+            }
+            Ok(Placement {
+                volume_start,
+                volume_sectors,
+                disk_sectors: volume_start + volume_sectors,
+            })
+        }
+    }
+}
+
+fn alignment_sectors(alignment: u64) -> MkimgRes<u64> {
+    let sector = u64::from(BYTES_PER_SECTOR);
+    if alignment == 0 || !alignment.is_multiple_of(sector) {
+        return Err(MkimgError::validation(format!(
+            "partition alignment must be a non-zero multiple of {sector}"
+        )));
+    }
+    Ok(alignment / sector)
+}
+
+/// Fills in the partition table IDs left unset in `layout` with values
+/// derived from the contents of the already written volume, so that
+/// identical volumes get identical IDs.
+pub(crate) fn derive_ids<T: Read + Seek>(
+    disk: &mut T,
+    layout: &Layout,
+    placement: &Placement,
+) -> MkimgRes<Layout> {
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = vec![0u8; 1 << 20];
+    disk.seek(SeekFrom::Start(placement.volume_offset()))?;
+    let mut remaining = placement.volume_end() - placement.volume_offset();
+    while remaining > 0 {
+        let len = remaining.min(buf.len() as u64) as usize;
+        disk.read_exact(&mut buf[..len])?;
+        hasher.update(&buf[..len]);
+        remaining -= len as u64;
+    }
+    let digest = hasher.finalize();
+    let derive = |purpose: &str| {
+        Uuid::new_v5(
+            &Uuid::NAMESPACE_OID,
+            format!("mkimg:{purpose}:{digest:08x}").as_bytes(),
+        )
+    };
+    Ok(match layout {
+        Layout::Superfloppy => Layout::Superfloppy,
+        Layout::Gpt(gpt) => Layout::Gpt(GptOptions {
+            disk_guid: gpt.disk_guid.or_else(|| Some(derive("disk"))),
+            partition_guid: gpt.partition_guid.or_else(|| Some(derive("partition"))),
+            ..gpt.clone()
+        }),
+        Layout::Mbr(mbr) => Layout::Mbr(MbrOptions {
+            disk_signature: mbr
+                .disk_signature
+                .or_else(|| Some(derive("signature").as_fields().0)),
+            ..mbr.clone()
+        }),
+    })
+}
+
+/// Writes the partition table for `layout` around an already written
+/// volume.
+pub(crate) fn write_table<T: Write + Seek>(
+    disk: &mut T,
+    layout: &Layout,
+    geometry: &Geometry,
+    placement: &Placement,
+) -> MkimgRes {
+    match layout {
+        Layout::Superfloppy => Ok(()),
+        Layout::Gpt(gpt) => write_gpt(disk, gpt, placement),
+        Layout::Mbr(mbr) => write_mbr(disk, mbr, geometry, placement),
+    }
+}
+
+fn write_mbr<T: Write + Seek>(
+    disk: &mut T,
+    options: &MbrOptions,
+    geometry: &Geometry,
+    placement: &Placement,
+) -> MkimgRes {
+    let volume_end = placement.volume_start + placement.volume_sectors;
+    let partition_type = options.partition_type.unwrap_or(match geometry.fat_type {
+        FatType::Fat12 => MBR_FAT12_TYPE,
+        FatType::Fat16 => MBR_FAT16_TYPE,
+        FatType::Fat32 if volume_end <= chs_limit() => MBR_FAT32_CHS_TYPE,
+        FatType::Fat32 => MBR_FAT32_LBA_TYPE,
+    });
+    let disk_signature = options
+        .disk_signature
+        .unwrap_or_else(|| Uuid::new_v4().as_fields().0);
+
+    let mut mbr = [0u8; BYTES_PER_SECTOR as usize];
+    mbr[0x1b8..0x1bc].copy_from_slice(&disk_signature.to_le_bytes());
+    let entry = &mut mbr[0x1be..0x1ce];
+    entry[0x00] = if options.active { 0x80 } else { 0x00 };
+    entry[0x01..0x04].copy_from_slice(&chs(placement.volume_start));
+    entry[0x04] = partition_type;
+    entry[0x05..0x08].copy_from_slice(&chs(volume_end - 1));
+    // Both values are bounded to 32 bits by `place`
+    entry[0x08..0x0c].copy_from_slice(&(placement.volume_start as u32).to_le_bytes());
+    entry[0x0c..0x10].copy_from_slice(&(placement.volume_sectors as u32).to_le_bytes());
+    mbr[0x1fe..0x200].copy_from_slice(&[0x55, 0xaa]);
+    write_at(disk, 0, &mbr)?;
+    disk.flush()?;
+    Ok(())
+}
+
+/// First sector that cannot be addressed with CHS.
+fn chs_limit() -> u64 {
+    (CHS_MAX_CYLINDER + 1) * CHS_HEADS * CHS_SECTORS_PER_TRACK
+}
+
+/// Encodes `lba` as an MBR CHS triple, saturating at the largest
+/// addressable value.
+fn chs(lba: u64) -> [u8; 3] {
+    if lba >= chs_limit() {
+        return [0xfe, 0xff, 0xff];
+    }
+    let cylinder = lba / (CHS_HEADS * CHS_SECTORS_PER_TRACK);
+    let head = (lba / CHS_SECTORS_PER_TRACK) % CHS_HEADS;
+    let sector = lba % CHS_SECTORS_PER_TRACK + 1;
+    [
+        head as u8,
+        (sector as u8) | ((cylinder >> 2) as u8 & 0xc0),
+        cylinder as u8,
+    ]
+}
+
+fn write_gpt<T: Write + Seek>(disk: &mut T, gpt: &GptOptions, placement: &Placement) -> MkimgRes {
+    let disk_guid = gpt.disk_guid.unwrap_or_else(Uuid::new_v4);
+    let partition_guid = gpt.partition_guid.unwrap_or_else(Uuid::new_v4);
+    let last_lba = placement.disk_sectors - 1;
+    let first_usable = 2 + GPT_ENTRY_SECTORS;
+    let last_usable = last_lba - 1 - GPT_ENTRY_SECTORS;
+
+    let mut entries = vec![0u8; (GPT_ENTRY_SECTORS * u64::from(BYTES_PER_SECTOR)) as usize];
+    {
+        let entry = &mut entries[..GPT_ENTRY_SIZE as usize];
+        entry[0x00..0x10].copy_from_slice(&ESP_TYPE_GUID.to_bytes_le());
+        entry[0x10..0x20].copy_from_slice(&partition_guid.to_bytes_le());
+        entry[0x20..0x28].copy_from_slice(&placement.volume_start.to_le_bytes());
+        let volume_last = placement.volume_start + placement.volume_sectors - 1;
+        entry[0x28..0x30].copy_from_slice(&volume_last.to_le_bytes());
+        for (idx, unit) in ESP_NAME.encode_utf16().enumerate() {
+            entry[0x38 + idx * 2..0x3a + idx * 2].copy_from_slice(&unit.to_le_bytes());
+        }
+    }
+    let entries_crc = crc32fast::hash(&entries);
+
+    let header = |current_lba: u64, backup_lba: u64, entries_lba: u64| {
+        let mut header = [0u8; BYTES_PER_SECTOR as usize];
+        header[0x00..0x08].copy_from_slice(b"EFI PART");
+        header[0x08..0x0c].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+        header[0x0c..0x10].copy_from_slice(&GPT_HEADER_SIZE.to_le_bytes());
+        header[0x18..0x20].copy_from_slice(&current_lba.to_le_bytes());
+        header[0x20..0x28].copy_from_slice(&backup_lba.to_le_bytes());
+        header[0x28..0x30].copy_from_slice(&first_usable.to_le_bytes());
+        header[0x30..0x38].copy_from_slice(&last_usable.to_le_bytes());
+        header[0x38..0x48].copy_from_slice(&disk_guid.to_bytes_le());
+        header[0x48..0x50].copy_from_slice(&entries_lba.to_le_bytes());
+        header[0x50..0x54].copy_from_slice(&GPT_ENTRIES.to_le_bytes());
+        header[0x54..0x58].copy_from_slice(&GPT_ENTRY_SIZE.to_le_bytes());
+        header[0x58..0x5c].copy_from_slice(&entries_crc.to_le_bytes());
+        let header_crc = crc32fast::hash(&header[..GPT_HEADER_SIZE as usize]);
+        header[0x10..0x14].copy_from_slice(&header_crc.to_le_bytes());
+        header
+    };
+
+    let protective_sectors = u32::try_from(last_lba).unwrap_or(u32::MAX);
+    let mbr = protective_mbr(protective_sectors);
+    write_at(disk, 0, &mbr)?;
+    write_at(disk, 1, &header(1, last_lba, 2))?;
+    write_at(disk, 2, &entries)?;
+    let backup_entries_lba = last_lba - GPT_ENTRY_SECTORS;
+    write_at(disk, backup_entries_lba, &entries)?;
+    write_at(disk, last_lba, &header(last_lba, 1, backup_entries_lba))?;
+    disk.flush()?;
+    Ok(())
+}
+
+/// Builds an MBR whose only partition covers the whole disk and marks
+/// it as GPT protected.
+fn protective_mbr(sectors: u32) -> [u8; BYTES_PER_SECTOR as usize] {
+    let mut mbr = [0u8; BYTES_PER_SECTOR as usize];
+    let entry = &mut mbr[0x1be..0x1ce];
+    // CHS start 0/0/2, end maxed out as the spec requires
+    entry[0x01..0x04].copy_from_slice(&[0x00, 0x02, 0x00]);
+    entry[0x04] = GPT_PROTECTIVE_TYPE;
+    entry[0x05..0x08].copy_from_slice(&[0xff, 0xff, 0xff]);
+    entry[0x08..0x0c].copy_from_slice(&1u32.to_le_bytes());
+    entry[0x0c..0x10].copy_from_slice(&sectors.to_le_bytes());
+    mbr[0x1fe..0x200].copy_from_slice(&[0x55, 0xaa]);
+    mbr
+}
+
+/// Detects the partitioning of an existing image and lists its
+/// partitions.
+///
+/// An image whose first sector is a FAT boot sector is a superfloppy.
+/// Otherwise it must start with an MBR; a GPT protective partition in
+/// it means the GPT is read instead. Only a GPT whose header and entry
+/// array checksums match is accepted, falling back to the backup copy
+/// at the end of the disk.
+pub(crate) fn detect<T: Read + Seek>(disk: &mut T) -> MkimgRes<(PartitionScheme, Vec<Partition>)> {
+    let mut mbr = [0u8; BYTES_PER_SECTOR as usize];
+    read_at(disk, 0, &mut mbr)
+        .map_err(|_| MkimgError::validation("image is smaller than one sector"))?;
+    if fat::is_boot_sector(&mbr) {
+        return Ok((PartitionScheme::Superfloppy, Vec::new()));
+    }
+    if mbr[0x1fe..0x200] != [0x55, 0xaa] {
+        return Err(MkimgError::validation(
+            "no FAT volume or partition table found",
+        ));
+    }
+    if mbr_entries(&mbr)
+        .iter()
+        .any(|entry| entry.partition_type == GPT_PROTECTIVE_TYPE)
+    {
+        return read_gpt(disk);
+    }
+    let disk_signature = u32::from_le_bytes([mbr[0x1b8], mbr[0x1b9], mbr[0x1ba], mbr[0x1bb]]);
+    Ok((
+        PartitionScheme::Mbr { disk_signature },
+        read_mbr(disk, &mbr)?,
+    ))
+}
+
+/// A raw MBR partition entry.
+struct MbrEntry {
+    active: bool,
+    partition_type: u8,
+    start_lba: u32,
+    sectors: u32,
+}
+
+fn mbr_entries(sector: &[u8]) -> Vec<MbrEntry> {
+    sector[0x1be..0x1fe]
+        .chunks_exact(16)
+        .map(|entry| MbrEntry {
+            active: entry[0x00] & 0x80 != 0,
+            partition_type: entry[0x04],
+            start_lba: u32::from_le_bytes([entry[0x08], entry[0x09], entry[0x0a], entry[0x0b]]),
+            sectors: u32::from_le_bytes([entry[0x0c], entry[0x0d], entry[0x0e], entry[0x0f]]),
+        })
+        .collect()
+}
+
+fn read_mbr<T: Read + Seek>(disk: &mut T, mbr: &[u8]) -> MkimgRes<Vec<Partition>> {
+    let mut partitions = Vec::new();
+    let mut extended = None;
+    for (idx, entry) in mbr_entries(mbr).into_iter().enumerate() {
+        if entry.partition_type == 0 || entry.sectors == 0 {
+            continue;
+        }
+        if MBR_EXTENDED_TYPES.contains(&entry.partition_type) {
+            extended.get_or_insert(u64::from(entry.start_lba));
+            continue;
+        }
+        partitions.push(Partition {
+            number: idx as u32 + 1,
+            start_lba: u64::from(entry.start_lba),
+            sectors: u64::from(entry.sectors),
+            kind: PartitionKind::Mbr {
+                partition_type: entry.partition_type,
+                active: entry.active,
+            },
+        });
+    }
+
+    // Logical partitions form a chain of extended boot records, each
+    // describing one partition relative to itself and linking to the
+    // next relative to the start of the extended partition
+    let Some(extended_start) = extended else {
+        return Ok(partitions);
+    };
+    let mut ebr_lba = extended_start;
+    let mut seen = HashSet::new();
+    let mut number = 5;
+    while seen.insert(ebr_lba) {
+        let mut ebr = [0u8; BYTES_PER_SECTOR as usize];
+        read_at(disk, ebr_lba, &mut ebr)?;
+        if ebr[0x1fe..0x200] != [0x55, 0xaa] {
+            break;
+        }
+        let entries = mbr_entries(&ebr);
+        let logical = &entries[0];
+        if logical.partition_type != 0 && logical.sectors != 0 {
+            partitions.push(Partition {
+                number,
+                start_lba: ebr_lba + u64::from(logical.start_lba),
+                sectors: u64::from(logical.sectors),
+                kind: PartitionKind::Mbr {
+                    partition_type: logical.partition_type,
+                    active: logical.active,
+                },
+            });
+            number += 1;
+        }
+        let next = &entries[1];
+        if next.partition_type == 0 || next.start_lba == 0 {
+            break;
+        }
+        ebr_lba = extended_start + u64::from(next.start_lba);
+    }
+    Ok(partitions)
+}
+
+fn read_gpt<T: Read + Seek>(disk: &mut T) -> MkimgRes<(PartitionScheme, Vec<Partition>)> {
+    let last_lba = (disk.seek(SeekFrom::End(0))? / u64::from(BYTES_PER_SECTOR)).saturating_sub(1);
+    // A header describing an entry array too large to read or
+    // partitions outside the disk does not stop the other one from
+    // being tried
+    let mut error = None;
+    for header_lba in [1, last_lba] {
+        match read_gpt_at(disk, header_lba, last_lba) {
+            Ok(Some(gpt)) => return Ok(gpt),
+            Ok(None) => (),
+            Err(e) => {
+                error.get_or_insert(e);
+            }
+        }
+    }
+    Err(error.unwrap_or_else(|| {
+        MkimgError::validation("neither the primary nor the backup GPT is valid")
+    }))
+}
+
+/// Reads the GPT whose header is at `header_lba` on a disk whose last
+/// sector is `disk_last_lba`, returning `None` if it fails validation
+/// and an error if its entry array is too large to read or a partition
+/// does not lie within the disk.
+fn read_gpt_at<T: Read + Seek>(
+    disk: &mut T,
+    header_lba: u64,
+    disk_last_lba: u64,
+) -> MkimgRes<Option<(PartitionScheme, Vec<Partition>)>> {
+    let mut header = [0u8; BYTES_PER_SECTOR as usize];
+    if header_lba == 0 || read_at(disk, header_lba, &mut header).is_err() {
+        return Ok(None);
+    }
+    let u32_at = |offset: usize| {
+        u32::from_le_bytes([
+            header[offset],
+            header[offset + 1],
+            header[offset + 2],
+            header[offset + 3],
+        ])
+    };
+    let header_size = u32_at(0x0c) as usize;
+    if &header[0x00..0x08] != b"EFI PART"
+        || !(GPT_HEADER_SIZE as usize..=512).contains(&header_size)
+    {
+        return Ok(None);
+    }
+    let mut crc_header = header;
+    crc_header[0x10..0x14].fill(0);
+    if crc32fast::hash(&crc_header[..header_size]) != u32_at(0x10) {
+        return Ok(None);
+    }
+    let entries_lba = u64::from_le_bytes(header[0x48..0x50].try_into().unwrap());
+    let entry_count = u32_at(0x50);
+    let entry_size = u32_at(0x54);
+    if !(GPT_ENTRY_SIZE..=GPT_MAX_ENTRY_SIZE).contains(&entry_size) {
+        return Err(MkimgError::validation(format!(
+            "GPT at LBA {header_lba} has {entry_size} byte entries, \
+             {GPT_ENTRY_SIZE} to {GPT_MAX_ENTRY_SIZE} are supported"
+        )));
+    }
+    let entries_bytes = entry_count
+        .checked_mul(entry_size)
+        .filter(|&bytes| bytes <= GPT_MAX_ENTRIES_BYTES)
+        .ok_or_else(|| {
+            MkimgError::validation(format!(
+                "GPT at LBA {header_lba} has {entry_count} entries of {entry_size} bytes, \
+                 more than the {GPT_MAX_ENTRIES_BYTES} bytes supported"
+            ))
+        })?;
+    let entry_size = entry_size as usize;
+    let mut entries = vec![0u8; entries_bytes as usize];
+    if read_at(disk, entries_lba, &mut entries).is_err()
+        || crc32fast::hash(&entries) != u32_at(0x58)
+    {
+        return Ok(None);
+    }
+
+    let disk_guid = Uuid::from_bytes_le(header[0x38..0x48].try_into().unwrap());
+    let mut partitions = Vec::new();
+    for (idx, entry) in entries.chunks_exact(entry_size).enumerate() {
+        let type_guid = Uuid::from_bytes_le(entry[0x00..0x10].try_into().unwrap());
+        if type_guid.is_nil() {
+            continue;
+        }
+        let number = idx as u32 + 1;
+        let first_lba = u64::from_le_bytes(entry[0x20..0x28].try_into().unwrap());
+        let last_lba = u64::from_le_bytes(entry[0x28..0x30].try_into().unwrap());
+        let sectors = last_lba
+            .checked_sub(first_lba)
+            .and_then(|sectors| sectors.checked_add(1))
+            .filter(|_| last_lba <= disk_last_lba)
+            .ok_or_else(|| {
+                MkimgError::validation(format!(
+                    "GPT at LBA {header_lba} has partition {number} at LBAs {first_lba} \
+                     to {last_lba}, which is not a range within LBAs 0 to {disk_last_lba}"
+                ))
+            })?;
+        let name: Vec<u16> = entry[0x38..0x80]
+            .chunks_exact(2)
+            .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+            .take_while(|&unit| unit != 0)
+            .collect();
+        partitions.push(Partition {
+            number,
+            start_lba: first_lba,
+            sectors,
+            kind: PartitionKind::Gpt {
+                type_guid,
+                partition_guid: Uuid::from_bytes_le(entry[0x10..0x20].try_into().unwrap()),
+                name: String::from_utf16_lossy(&name),
+            },
+        });
+    }
+    Ok(Some((PartitionScheme::Gpt { disk_guid }, partitions)))
+}
+
+fn read_at<T: Read + Seek>(disk: &mut T, lba: u64, buf: &mut [u8]) -> MkimgRes {
+    disk.seek(SeekFrom::Start(lba_offset(lba)?))?;
+    disk.read_exact(buf)?;
+    Ok(())
+}
+
+fn write_at<T: Write + Seek>(disk: &mut T, lba: u64, data: &[u8]) -> MkimgRes {
+    disk.seek(SeekFrom::Start(lba_offset(lba)?))?;
+    disk.write_all(data)?;
+    Ok(())
+}
+
+/// Byte offset of `lba`, which is read from the image and may be too
+/// large to address.
+fn lba_offset(lba: u64) -> MkimgRes<u64> {
+    lba.checked_mul(u64::from(BYTES_PER_SECTOR))
+        .ok_or_else(|| MkimgError::validation(format!("LBA {lba} is too large to address")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const VOLUME_SECTORS: u32 = 2048;
+
+    fn geometry() -> Geometry {
+        Geometry {
+            fat_type: FatType::Fat16,
+            bytes_per_cluster: 512,
+            total_sectors: VOLUME_SECTORS,
+            reserved_sectors: 1,
+            fats: 2,
+            root_entries: 512,
+        }
+    }
+
+    fn disk(layout: &Layout) -> (Cursor<Vec<u8>>, Placement) {
+        let placement = place(layout, VOLUME_SECTORS).unwrap();
+        let mut disk = Cursor::new(vec![0u8; placement.disk_bytes() as usize]);
+        write_table(&mut disk, layout, &geometry(), &placement).unwrap();
+        (disk, placement)
+    }
+
+    fn gpt_disk() -> (Cursor<Vec<u8>>, Placement) {
+        disk(&Layout::Gpt(GptOptions {
+            disk_guid: Some(Uuid::from_u128(1)),
+            partition_guid: Some(Uuid::from_u128(2)),
+            ..GptOptions::default()
+        }))
+    }
+
+    fn last_lba(disk: &Cursor<Vec<u8>>) -> u64 {
+        disk.get_ref().len() as u64 / u64::from(BYTES_PER_SECTOR) - 1
+    }
+
+    fn sector(disk: &mut Cursor<Vec<u8>>, lba: u64) -> &mut [u8] {
+        let offset = (lba * u64::from(BYTES_PER_SECTOR)) as usize;
+        &mut disk.get_mut()[offset..offset + BYTES_PER_SECTOR as usize]
+    }
+
+    // Edits the GPT header at `lba` and updates its checksum
+    fn edit_header(disk: &mut Cursor<Vec<u8>>, lba: u64, edit: impl FnOnce(&mut [u8])) {
+        let header = sector(disk, lba);
+        edit(header);
+        header[0x10..0x14].fill(0);
+        let crc = crc32fast::hash(&header[..GPT_HEADER_SIZE as usize]);
+        header[0x10..0x14].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    // Edits the first entry of the primary GPT and updates both
+    // checksums
+    fn edit_entry(disk: &mut Cursor<Vec<u8>>, edit: impl FnOnce(&mut [u8])) {
+        let offset = 2 * BYTES_PER_SECTOR as usize;
+        let entries = &mut disk.get_mut()[offset..][..(GPT_ENTRIES * GPT_ENTRY_SIZE) as usize];
+        edit(&mut entries[..GPT_ENTRY_SIZE as usize]);
+        let crc = crc32fast::hash(entries);
+        edit_header(disk, 1, |header| {
+            header[0x58..0x5c].copy_from_slice(&crc.to_le_bytes())
+        });
+    }
+
+    #[test]
+    fn gpt_round_trip() {
+        let (mut disk, placement) = gpt_disk();
+        let (scheme, partitions) = detect(&mut disk).unwrap();
+        assert_eq!(
+            scheme,
+            PartitionScheme::Gpt {
+                disk_guid: Uuid::from_u128(1)
+            }
+        );
+        assert_eq!(partitions.len(), 1);
+        let partition = &partitions[0];
+        assert_eq!(partition.number, 1);
+        assert_eq!(partition.start_lba, placement.volume_start);
+        assert_eq!(partition.sectors, u64::from(VOLUME_SECTORS));
+        assert_eq!(partition.offset(), placement.volume_offset());
+        let PartitionKind::Gpt {
+            type_guid,
+            partition_guid,
+            name,
+        } = &partition.kind
+        else {
+            panic!("not a GPT partition");
+        };
+        assert_eq!(*type_guid, ESP_TYPE_GUID);
+        assert_eq!(*partition_guid, Uuid::from_u128(2));
+        assert_eq!(name, ESP_NAME);
+    }
+
+    #[test]
+    fn mbr_round_trip() {
+        let (mut disk, placement) = disk(&Layout::Mbr(MbrOptions {
+            active: true,
+            disk_signature: Some(0x1234_5678),
+            ..MbrOptions::default()
+        }));
+        let (scheme, partitions) = detect(&mut disk).unwrap();
+        assert_eq!(
+            scheme,
+            PartitionScheme::Mbr {
+                disk_signature: 0x1234_5678
+            }
+        );
+        assert_eq!(partitions.len(), 1);
+        assert_eq!(partitions[0].start_lba, placement.volume_start);
+        assert_eq!(partitions[0].sectors, u64::from(VOLUME_SECTORS));
+        assert!(matches!(
+            partitions[0].kind,
+            PartitionKind::Mbr {
+                partition_type: MBR_FAT16_TYPE,
+                active: true
+            }
+        ));
+    }
+
+    #[test]
+    fn bad_crc_falls_back_to_backup() {
+        let (mut disk, _) = gpt_disk();
+        let last = last_lba(&disk);
+        sector(&mut disk, 1)[0x38] ^= 0xff;
+        assert!(read_gpt_at(&mut disk, 1, last).unwrap().is_none());
+        let (_, partitions) = detect(&mut disk).unwrap();
+        assert_eq!(partitions.len(), 1);
+
+        sector(&mut disk, last)[0x38] ^= 0xff;
+        assert!(detect(&mut disk).is_err());
+    }
+
+    #[test]
+    fn bad_entries_crc_is_invalid() {
+        let (mut disk, _) = gpt_disk();
+        let last = last_lba(&disk);
+        sector(&mut disk, 2)[0x38] ^= 0xff;
+        assert!(read_gpt_at(&mut disk, 1, last).unwrap().is_none());
+    }
+
+    #[test]
+    fn oversized_entry_array_is_an_error() {
+        let (mut disk, _) = gpt_disk();
+        let last = last_lba(&disk);
+        edit_header(&mut disk, 1, |header| {
+            header[0x50..0x54].copy_from_slice(&u32::MAX.to_le_bytes())
+        });
+        assert!(read_gpt_at(&mut disk, 1, last).is_err());
+        // The backup is still used
+        assert!(detect(&mut disk).is_ok());
+
+        edit_header(&mut disk, last, |header| {
+            header[0x54..0x58].copy_from_slice(&(GPT_MAX_ENTRY_SIZE * 2).to_le_bytes())
+        });
+        assert!(detect(&mut disk).is_err());
+    }
+
+    #[test]
+    fn overflowing_entries_lba_is_invalid() {
+        let (mut disk, _) = gpt_disk();
+        let last = last_lba(&disk);
+        edit_header(&mut disk, 1, |header| {
+            header[0x48..0x50].copy_from_slice(&u64::MAX.to_le_bytes())
+        });
+        assert!(read_gpt_at(&mut disk, 1, last).unwrap().is_none());
+        assert!(read_gpt_at(&mut disk, u64::MAX, last).unwrap().is_none());
+        assert!(lba_offset(u64::MAX).is_err());
+    }
+
+    #[test]
+    fn partitions_outside_the_disk_are_errors() {
+        let bounds: [(u64, u64); 3] = [(0, u64::MAX), (u64::MAX, u64::MAX), (100, 99)];
+        for (first, last) in bounds {
+            let (mut disk, _) = gpt_disk();
+            let disk_last = last_lba(&disk);
+            edit_entry(&mut disk, |entry| {
+                entry[0x20..0x28].copy_from_slice(&first.to_le_bytes());
+                entry[0x28..0x30].copy_from_slice(&last.to_le_bytes());
+            });
+            assert!(
+                read_gpt_at(&mut disk, 1, disk_last).is_err(),
+                "{first}..={last}"
+            );
+        }
+    }
+
+    #[test]
+    fn partition_may_end_on_the_last_lba() {
+        let (mut disk, _) = gpt_disk();
+        let last = last_lba(&disk);
+        edit_entry(&mut disk, |entry| {
+            entry[0x20..0x28].copy_from_slice(&0u64.to_le_bytes());
+            entry[0x28..0x30].copy_from_slice(&last.to_le_bytes());
+        });
+        let (_, partitions) = read_gpt_at(&mut disk, 1, last).unwrap().unwrap();
+        assert_eq!(partitions[0].sectors, last + 1);
+    }
+}
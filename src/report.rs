@@ -0,0 +1,529 @@
+//! Structured description of an image's contents.
+
+use crate::{
+    error::{MkimgError, MkimgRes},
+    fat::{self, Attributes, DirEntry, Timestamp, Volume},
+    options::ExamineOptions,
+    partition::{self, Partition, PartitionKind, PartitionScheme},
+};
+use fatfs::FatType;
+use serde::{Serialize, Serializer};
+use std::{
+    collections::HashSet,
+    fmt,
+    io::{Read, Seek, SeekFrom, Write},
+};
+
+/// Everything [`examine`](crate::examine) found in a disk image.
+#[derive(Debug, Clone, Serialize)]
+pub struct DiskReport {
+    /// Partition table found on the image.
+    pub scheme: PartitionScheme,
+    /// All partitions, whether or not they hold a FAT volume. Empty for
+    /// superfloppies.
+    pub partitions: Vec<Partition>,
+    /// Every FAT volume found, in partition order.
+    pub volumes: Vec<ImageReport>,
+}
+
+/// The contents of a single FAT volume.
+#[derive(Debug, Clone, Serialize)]
+pub struct ImageReport {
+    /// Partition holding the volume; `None` for superfloppies.
+    pub partition: Option<Partition>,
+    /// Boot sector and allocation information.
+    pub volume: VolumeInfo,
+    /// Entries of the root directory, excluding `.` and `..`.
+    pub entries: Vec<EntryReport>,
+}
+
+/// FAT volume parameters read from the boot sector.
+#[derive(Debug, Clone, Serialize)]
+pub struct VolumeInfo {
+    #[serde(serialize_with = "serialize_fat_type")]
+    pub fat_type: FatType,
+    pub oem_name: String,
+    pub volume_label: String,
+    pub volume_id: u32,
+    pub media: u8,
+    pub bytes_per_sector: u16,
+    pub bytes_per_cluster: u32,
+    pub reserved_sectors: u16,
+    pub fats: u8,
+    pub sectors_per_fat: u32,
+    /// Root directory entries; 0 on FAT32.
+    pub root_entries: u16,
+    pub total_sectors: u32,
+    /// Sectors preceding the volume on a partitioned disk.
+    pub hidden_sectors: u32,
+    pub total_clusters: u32,
+    /// Clusters marked free in the first FAT. Only counted if
+    /// [`ExamineOptions::free_space`] is set.
+    pub free_clusters: Option<u32>,
+    /// `free_clusters` in bytes.
+    pub free_bytes: Option<u64>,
+}
+
+/// A file or directory in the image.
+#[derive(Debug, Clone, Serialize)]
+pub struct EntryReport {
+    /// Path from the image root, `/` separated.
+    pub path: String,
+    /// Long name if present, short name otherwise.
+    pub name: String,
+    /// 8.3 name as stored in the directory entry.
+    pub short_name: String,
+    pub long_name: Option<String>,
+    /// Size in bytes; 0 for directories.
+    pub size: u32,
+    pub attributes: Attributes,
+    pub created: Option<Timestamp>,
+    pub modified: Option<Timestamp>,
+    /// Last access date. The time of day is not stored.
+    pub accessed: Option<Timestamp>,
+    /// Clusters holding the entry's data, in chain order.
+    pub clusters: Vec<u32>,
+    /// File contents, if no larger than
+    /// [`ExamineOptions::content_limit`]. Not serialized.
+    #[serde(skip)]
+    pub content: Option<Vec<u8>>,
+    /// Directory contents, excluding `.` and `..`. Empty beyond
+    /// [`ExamineOptions::max_depth`].
+    pub children: Vec<EntryReport>,
+}
+
+impl EntryReport {
+    pub fn is_dir(&self) -> bool {
+        self.attributes.directory
+    }
+}
+
+impl ImageReport {
+    /// Looks up an entry by its `/` separated path. Components are
+    /// compared case-insensitively, as FAT does.
+    pub fn find(&self, path: &str) -> Option<&EntryReport> {
+        let mut entries = &self.entries;
+        let mut found = None;
+        for part in path.split('/').filter(|part| !part.is_empty()) {
+            let entry = entries.iter().find(|entry| {
+                entry.name.eq_ignore_ascii_case(part) || entry.short_name.eq_ignore_ascii_case(part)
+            })?;
+            entries = &entry.children;
+            found = Some(entry);
+        }
+        found
+    }
+
+    /// Reads the contents of `entry`, a file on this volume, from the
+    /// image `disk` the report was built from.
+    ///
+    /// # Errors
+    ///
+    /// Returns error if the image cannot be read or the file's cluster
+    /// chain is shorter than its size
+    pub fn read_file<T: Read + Seek>(&self, disk: T, entry: &EntryReport) -> MkimgRes<Vec<u8>> {
+        let mut data = Vec::new();
+        self.copy_file(disk, entry, &mut data)?;
+        Ok(data)
+    }
+
+    /// Streams the contents of `entry`, a file on this volume, from the
+    /// image `disk` to `out` one cluster at a time.
+    ///
+    /// # Returns
+    ///
+    /// The number of bytes copied, which is the size of the file
+    ///
+    /// # Errors
+    ///
+    /// Returns error if the image cannot be read, `out` cannot be
+    /// written, or the file's cluster chain is shorter than its size
+    pub fn copy_file<T: Read + Seek, W: Write + ?Sized>(
+        &self,
+        disk: T,
+        entry: &EntryReport,
+        out: &mut W,
+    ) -> MkimgRes<u64> {
+        let base = self.partition.as_ref().map_or(0, Partition::offset);
+        let size = u64::from(entry.size);
+        let copied = Volume::open(disk, base)?.copy_chain(&entry.clusters, size, out)?;
+        if copied != size {
+            return Err(MkimgError::validation(format!(
+                "{}: cluster chain ends before the end of the file",
+                entry.path
+            )));
+        }
+        Ok(copied)
+    }
+
+    /// Renders the directory tree in the style of `tree(1)`.
+    pub fn tree(&self) -> Tree<'_> {
+        Tree(self)
+    }
+
+    /// Iterates over every entry in the image, parents before their
+    /// children.
+    pub fn iter(&self) -> impl Iterator<Item = &EntryReport> {
+        let mut stack: Vec<&EntryReport> = self.entries.iter().rev().collect();
+        std::iter::from_fn(move || {
+            let entry = stack.pop()?;
+            stack.extend(entry.children.iter().rev());
+            Some(entry)
+        })
+    }
+}
+
+/// How an image differs from its file mappings, as found by
+/// [`verify`](crate::verify). Paths are `/` separated image paths.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct VerifyReport {
+    /// Mapped entries not found in the image.
+    pub missing: Vec<String>,
+    /// Image entries no mapping covers. Contents of extra directories
+    /// are not listed.
+    pub extra: Vec<String>,
+    /// Entries found in the image that do not match their mapping.
+    pub differing: Vec<Differing>,
+}
+
+/// An image entry that does not match its mapping.
+#[derive(Debug, Clone, Serialize)]
+pub struct Differing {
+    pub path: String,
+    pub difference: Difference,
+}
+
+impl VerifyReport {
+    /// True if the image holds exactly what was mapped.
+    pub fn is_match(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty() && self.differing.is_empty()
+    }
+}
+
+/// How an image entry differs from its mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Difference {
+    /// A file where a directory is expected, or the other way round.
+    Kind,
+    /// The file size differs.
+    Size { expected: u64, found: u64 },
+    /// The file has the expected size but different contents.
+    Contents,
+    /// The file's data ends early: its cluster chain is too short or
+    /// runs past the end of the image.
+    Truncated,
+}
+
+/// Detects the layout of `disk` and reads every FAT volume on it.
+pub(crate) fn build<T: Read + Seek>(mut disk: T, options: &ExamineOptions) -> MkimgRes<DiskReport> {
+    let (scheme, partitions) = partition::detect(&mut disk)?;
+    let mut volumes = Vec::new();
+    if scheme == PartitionScheme::Superfloppy {
+        volumes.push(build_volume(&mut disk, None, options)?);
+    }
+    for partition in &partitions {
+        let mut sector = [0u8; 512];
+        disk.seek(SeekFrom::Start(partition.offset()))?;
+        if disk.read_exact(&mut sector).is_ok() && fat::is_boot_sector(&sector) {
+            volumes.push(build_volume(&mut disk, Some(partition.clone()), options)?);
+        }
+    }
+    Ok(DiskReport {
+        scheme,
+        partitions,
+        volumes,
+    })
+}
+
+/// Reads the FAT volume at the start of `partition` into a report.
+fn build_volume<T: Read + Seek>(
+    disk: T,
+    partition: Option<Partition>,
+    options: &ExamineOptions,
+) -> MkimgRes<ImageReport> {
+    let base = partition.as_ref().map_or(0, Partition::offset);
+    let mut volume = Volume::open(disk, base)?;
+    let bpb = volume.bpb.clone();
+    let free_clusters = match options.free_space {
+        true => Some(volume.free_clusters()?),
+        false => None,
+    };
+    let info = VolumeInfo {
+        fat_type: bpb.fat_type,
+        oem_name: String::from_utf8_lossy(&bpb.oem_name)
+            .trim_end()
+            .to_string(),
+        volume_label: String::from_utf8_lossy(&bpb.volume_label)
+            .trim_end()
+            .to_string(),
+        volume_id: bpb.volume_id,
+        media: bpb.media,
+        bytes_per_sector: bpb.bytes_per_sector,
+        bytes_per_cluster: bpb.bytes_per_cluster(),
+        reserved_sectors: bpb.reserved_sectors,
+        fats: bpb.fats,
+        sectors_per_fat: bpb.sectors_per_fat,
+        root_entries: bpb.root_entries,
+        total_sectors: bpb.total_sectors,
+        hidden_sectors: bpb.hidden_sectors,
+        total_clusters: bpb.total_clusters(),
+        free_clusters,
+        free_bytes: free_clusters.map(|free| u64::from(free) * u64::from(bpb.bytes_per_cluster())),
+    };
+    let root = fat::parse_dir(&volume.root_dir()?);
+    let mut visited = HashSet::new();
+    let entries = build_entries(&mut volume, root, "", 0, options, &mut visited)?;
+    Ok(ImageReport {
+        partition,
+        volume: info,
+        entries,
+    })
+}
+
+fn build_entries<T: Read + Seek>(
+    volume: &mut Volume<T>,
+    dir: Vec<DirEntry>,
+    parent: &str,
+    depth: usize,
+    options: &ExamineOptions,
+    visited: &mut HashSet<u32>,
+) -> MkimgRes<Vec<EntryReport>> {
+    let mut entries = Vec::new();
+    for entry in dir.into_iter().filter(|entry| !entry.is_dot()) {
+        let path = if parent.is_empty() {
+            entry.name().to_string()
+        } else {
+            format!("{parent}/{}", entry.name())
+        };
+        let clusters = volume.chain(entry.first_cluster)?;
+        let is_dir = entry.attributes.directory;
+        let content = match options.content_limit {
+            Some(limit) if !is_dir && u64::from(entry.size) <= limit => {
+                Some(volume.read_chain(&clusters, u64::from(entry.size))?)
+            }
+            _ => None,
+        };
+        // Directories that loop back on an ancestor are listed but
+        // not descended into again
+        let descend = is_dir
+            && options.max_depth.is_none_or(|max| depth < max)
+            && visited.insert(entry.first_cluster);
+        let children = if descend {
+            let dir = fat::parse_dir(&volume.dir(entry.first_cluster)?);
+            build_entries(volume, dir, &path, depth + 1, options, visited)?
+        } else {
+            Vec::new()
+        };
+        entries.push(EntryReport {
+            path,
+            name: entry.name().to_string(),
+            short_name: entry.short_name,
+            long_name: entry.long_name,
+            size: if is_dir { 0 } else { entry.size },
+            attributes: entry.attributes,
+            created: entry.created,
+            modified: entry.modified,
+            accessed: entry.accessed,
+            clusters,
+            content,
+            children,
+        });
+    }
+    Ok(entries)
+}
+
+impl fmt::Display for DiskReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.scheme {
+            PartitionScheme::Superfloppy => {}
+            PartitionScheme::Mbr { disk_signature } => {
+                writeln!(f, "MBR disk (signature {disk_signature:08X})")?
+            }
+            PartitionScheme::Gpt { disk_guid } => writeln!(f, "GPT disk (GUID {disk_guid})")?,
+        }
+        for partition in &self.partitions {
+            writeln!(f, "  {}", PartitionLabel(partition))?;
+        }
+        for volume in &self.volumes {
+            write!(f, "{volume}")?;
+        }
+        Ok(())
+    }
+}
+
+/// One line description of a partition.
+struct PartitionLabel<'a>(&'a Partition);
+
+impl fmt::Display for PartitionLabel<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let partition = self.0;
+        write!(
+            f,
+            "Partition {}: {} sectors at LBA {}",
+            partition.number, partition.sectors, partition.start_lba
+        )?;
+        match &partition.kind {
+            PartitionKind::Mbr {
+                partition_type,
+                active,
+            } => {
+                write!(f, ", type 0x{partition_type:02X}")?;
+                if *active {
+                    write!(f, ", active")?;
+                }
+                Ok(())
+            }
+            PartitionKind::Gpt {
+                type_guid, name, ..
+            } => write!(f, ", type {type_guid} {name:?}"),
+        }
+    }
+}
+
+impl fmt::Display for ImageReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let volume = &self.volume;
+        if let Some(partition) = &self.partition {
+            write!(f, "Partition {}: ", partition.number)?;
+        }
+        writeln!(
+            f,
+            "{} volume {:?} (id {:08X})",
+            fat_type_name(volume.fat_type),
+            volume.volume_label,
+            volume.volume_id
+        )?;
+        writeln!(
+            f,
+            "  {} sectors of {} bytes, {} bytes per cluster",
+            volume.total_sectors, volume.bytes_per_sector, volume.bytes_per_cluster
+        )?;
+        writeln!(
+            f,
+            "  {} reserved sectors, {} FATs of {} sectors, {} root entries, {} hidden sectors",
+            volume.reserved_sectors,
+            volume.fats,
+            volume.sectors_per_fat,
+            volume.root_entries,
+            volume.hidden_sectors
+        )?;
+        match (volume.free_clusters, volume.free_bytes) {
+            (Some(free_clusters), Some(free_bytes)) => writeln!(
+                f,
+                "  {} of {} clusters free ({} bytes)",
+                free_clusters, volume.total_clusters, free_bytes
+            )?,
+            _ => writeln!(f, "  {} clusters", volume.total_clusters)?,
+        }
+        for entry in &self.entries {
+            fmt_entry(f, entry, 0)?;
+        }
+        Ok(())
+    }
+}
+
+fn fat_type_name(fat_type: FatType) -> &'static str {
+    match fat_type {
+        FatType::Fat12 => "FAT12",
+        FatType::Fat16 => "FAT16",
+        FatType::Fat32 => "FAT32",
+    }
+}
+
+fn serialize_fat_type<S: Serializer>(fat_type: &FatType, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(fat_type_name(*fat_type))
+}
+
+fn fmt_entry(f: &mut fmt::Formatter<'_>, entry: &EntryReport, depth: usize) -> fmt::Result {
+    let indent = "  ".repeat(depth);
+    let tag = if entry.is_dir() { "(DIR)" } else { "(FILE)" };
+    writeln!(f, "{indent}{} {} bytes {tag}", entry.name, entry.size)?;
+    if let Some(contents) = &entry.content {
+        if contents.iter().all(|&b| {
+            b.is_ascii() && !b.is_ascii_control() || b == b'\n' || b == b'\r' || b == b'\t'
+        }) {
+            writeln!(
+                f,
+                "{indent}  Content: {:?}",
+                String::from_utf8_lossy(contents)
+            )?;
+        } else {
+            writeln!(
+                f,
+                "{indent}  Content: {} bytes of binary data",
+                contents.len()
+            )?;
+        }
+    }
+    for child in &entry.children {
+        fmt_entry(f, child, depth + 1)?;
+    }
+    Ok(())
+}
+
+/// Tree view of an [`ImageReport`], see [`ImageReport::tree`].
+pub struct Tree<'a>(&'a ImageReport);
+
+impl fmt::Display for Tree<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let volume = &self.0.volume;
+        if let Some(partition) = &self.0.partition {
+            write!(f, "Partition {}: ", partition.number)?;
+        }
+        write!(
+            f,
+            "/ ({} {:?}",
+            fat_type_name(volume.fat_type),
+            volume.volume_label
+        )?;
+        if let Some(free_bytes) = volume.free_bytes {
+            write!(f, ", {free_bytes} bytes free")?;
+        }
+        writeln!(f, ")")?;
+        fmt_tree(f, &self.0.entries, "")
+    }
+}
+
+fn fmt_tree(f: &mut fmt::Formatter<'_>, entries: &[EntryReport], prefix: &str) -> fmt::Result {
+    for (idx, entry) in entries.iter().enumerate() {
+        let last = idx + 1 == entries.len();
+        let branch = if last { "└── " } else { "├── " };
+        if entry.is_dir() {
+            writeln!(f, "{prefix}{branch}{}/", entry.name)?;
+            let prefix = format!("{prefix}{}", if last { "    " } else { "│   " });
+            fmt_tree(f, &entry.children, &prefix)?;
+        } else {
+            writeln!(f, "{prefix}{branch}{} ({} bytes)", entry.name, entry.size)?;
+        }
+    }
+    Ok(())
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for path in &self.missing {
+            writeln!(f, "missing: {path}")?;
+        }
+        for path in &self.extra {
+            writeln!(f, "extra: {path}")?;
+        }
+        for Differing { path, difference } in &self.differing {
+            writeln!(f, "differs: {path} ({difference})")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Difference::Kind => write!(f, "file and directory swapped"),
+            Difference::Size { expected, found } => {
+                write!(f, "{found} bytes instead of {expected}")
+            }
+            Difference::Contents => write!(f, "contents differ"),
+            Difference::Truncated => write!(f, "data cut short"),
+        }
+    }
+}
@@ -0,0 +1,688 @@
+//! Volume size calculation.
+//!
+//! `fatfs` only formats whatever space it is handed, so the image has
+//! to be sized before formatting. The functions here mirror the
+//! geometry rules `fatfs::format_volume` applies (reserved sectors,
+//! root directory region, FAT size formula, cluster count limits per
+//! FAT type) so that the volume they describe is guaranteed to hold
+//! every mapped file.
+
+use crate::{
+    error::{path_to_str_with_context, MkimgError, MkimgRes},
+    options::{CreateOptions, VolumeSize},
+    FileMapping,
+};
+use fatfs::FatType;
+use std::collections::BTreeMap;
+
+/// Sector size used for every image.
+pub const BYTES_PER_SECTOR: u32 = 512;
+
+/// Free space added on top of the computed minimum when the caller
+/// does not ask for anything else.
+pub const DEFAULT_SLACK: u64 = 64 * 1024;
+
+/// Size of a single FAT directory entry.
+const DIR_ENTRY_SIZE: u64 = 32;
+
+/// UTF-16 code units stored in one long file name entry.
+const LFN_CHARS_PER_ENTRY: u64 = 13;
+
+/// Root directory entries `fatfs` reserves on FAT12/FAT16 volumes.
+const DEFAULT_ROOT_ENTRIES: u16 = 512;
+
+/// Number of FAT copies `fatfs` writes by default.
+const DEFAULT_FATS: u8 = 2;
+
+/// Largest cluster size `fatfs` will select on its own.
+const MAX_AUTO_BYTES_PER_CLUSTER: u32 = 32 * 1024;
+
+/// Largest cluster size a BPB can describe with 512 byte sectors.
+const MAX_BYTES_PER_CLUSTER: u32 = 128 * BYTES_PER_SECTOR;
+
+const FAT12_MIN_CLUSTERS: u32 = 16;
+const FAT16_MIN_CLUSTERS: u32 = 4085;
+const FAT32_MIN_CLUSTERS: u32 = 65525;
+const FAT32_MAX_CLUSTERS: u32 = 0x0FFF_FFF4;
+
+/// Layout of a FAT volume large enough for a set of file mappings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Geometry {
+    /// FAT variant the volume will be formatted with.
+    pub fat_type: FatType,
+    /// Cluster size in bytes.
+    pub bytes_per_cluster: u32,
+    /// Total volume size in sectors.
+    pub total_sectors: u32,
+    /// Reserved sectors before the first FAT.
+    pub reserved_sectors: u16,
+    /// Number of FAT copies.
+    pub fats: u8,
+    /// Root directory entries (FAT12/FAT16 only).
+    pub root_entries: u16,
+}
+
+impl Geometry {
+    /// Total volume size in bytes.
+    pub fn total_bytes(&self) -> u64 {
+        u64::from(self.total_sectors) * u64::from(BYTES_PER_SECTOR)
+    }
+
+    /// Reserved sectors beyond the fixed amount `fatfs` lays out
+    /// itself. `fatfs` formats the volume after these sectors and the
+    /// boot sector is moved to the front afterwards.
+    pub(crate) fn extra_reserved_sectors(&self) -> u32 {
+        u32::from(self.reserved_sectors - default_reserved_sectors(self.fat_type))
+    }
+}
+
+/// Computes the geometry of a volume holding all `file_mappings`.
+///
+/// Options left unset in `options` are chosen automatically: the FAT
+/// type is estimated from the volume size the same way `fatfs` does,
+/// falling back to larger types when the content does not fit, and
+/// the cluster size starts at the `fatfs` default and is increased as
+/// needed. When no explicit size is requested, the smallest volume
+/// holding the content plus `options.slack` is used.
+///
+/// # Arguments
+///
+/// * `file_mappings` - Files that will be written to the image
+/// * `options` - Requested volume parameters
+///
+/// # Errors
+///
+/// Returns error if a source file cannot be inspected, an option is
+/// invalid, or the content does not fit the requested volume
+pub fn compute_geometry(
+    file_mappings: &[FileMapping],
+    options: &CreateOptions,
+) -> MkimgRes<Geometry> {
+    validate(options)?;
+    let content = Content::scan(file_mappings)?;
+    let fixed_sectors = options.size.map(|size| match size {
+        VolumeSize::Bytes(bytes) => bytes / u64::from(BYTES_PER_SECTOR),
+        VolumeSize::Sectors(sectors) => u64::from(sectors),
+    });
+    let estimated_bytes = fixed_sectors
+        .map(|sectors| sectors * u64::from(BYTES_PER_SECTOR))
+        .unwrap_or_else(|| content.bytes() + options.slack);
+
+    let fat_types = match options.fat_type {
+        Some(fat_type) => vec![fat_type],
+        None => [FatType::Fat12, FatType::Fat16, FatType::Fat32]
+            .into_iter()
+            .skip_while(|&fat_type| fat_type != estimate_fat_type(estimated_bytes))
+            .collect(),
+    };
+    for fat_type in fat_types {
+        let params = Params::new(fat_type, options)?;
+        if fat_type != FatType::Fat32 {
+            let root_entries = content.root_entries();
+            if root_entries > u64::from(params.root_entries) {
+                if options.fat_type.is_none() {
+                    continue;
+                }
+                return Err(MkimgError::validation(format!(
+                    "root directory needs {root_entries} entries but only {} are available",
+                    params.root_entries
+                )));
+            }
+        }
+        let cluster_sizes = match options.bytes_per_cluster {
+            Some(bytes_per_cluster) => vec![bytes_per_cluster],
+            None => auto_cluster_sizes(fat_type, estimated_bytes),
+        };
+        for bytes_per_cluster in cluster_sizes {
+            let geometry = match fixed_sectors {
+                Some(sectors) => fit_fixed(&content, &params, bytes_per_cluster, sectors),
+                None => fit_auto(&content, &params, bytes_per_cluster, options.slack),
+            };
+            if let Some(geometry) = geometry {
+                return Ok(geometry);
+            }
+        }
+    }
+    Err(MkimgError::validation(
+        "content does not fit in a volume with the requested options",
+    ))
+}
+
+/// Parses a byte count with an optional K, M or G (binary) suffix,
+/// e.g. `1440K` or `64M`.
+///
+/// # Errors
+///
+/// Returns error if `s` is not a valid size or overflows
+pub fn parse_size(s: &str) -> MkimgRes<u64> {
+    let (digits, multiplier) = match s.char_indices().last() {
+        Some((idx, 'k' | 'K')) => (&s[..idx], 1 << 10),
+        Some((idx, 'm' | 'M')) => (&s[..idx], 1 << 20),
+        Some((idx, 'g' | 'G')) => (&s[..idx], 1 << 30),
+        _ => (s, 1),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| MkimgError::validation(format!("invalid size '{s}'")))
+}
+
+fn validate(options: &CreateOptions) -> MkimgRes {
+    if let Some(bytes_per_cluster) = options.bytes_per_cluster {
+        if !bytes_per_cluster.is_power_of_two()
+            || !(BYTES_PER_SECTOR..=MAX_BYTES_PER_CLUSTER).contains(&bytes_per_cluster)
+        {
+            return Err(MkimgError::validation(format!(
+                "cluster size must be a power of two between {BYTES_PER_SECTOR} and {MAX_BYTES_PER_CLUSTER}"
+            )));
+        }
+    }
+    if let Some(fats) = options.fats {
+        if !(1..=2).contains(&fats) {
+            return Err(MkimgError::validation("number of FATs must be 1 or 2"));
+        }
+    }
+    if let Some(root_entries) = options.root_entries {
+        if root_entries == 0 || root_entries % 16 != 0 {
+            return Err(MkimgError::validation(
+                "root entry count must be a non-zero multiple of 16",
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Per FAT type volume parameters, with defaults filled in.
+struct Params {
+    fat_type: FatType,
+    reserved_sectors: u16,
+    fats: u8,
+    root_entries: u16,
+}
+
+impl Params {
+    fn new(fat_type: FatType, options: &CreateOptions) -> MkimgRes<Self> {
+        let min_reserved = default_reserved_sectors(fat_type);
+        let reserved_sectors = options.reserved_sectors.unwrap_or(min_reserved);
+        if reserved_sectors < min_reserved {
+            return Err(MkimgError::validation(format!(
+                "{fat_type:?} needs at least {min_reserved} reserved sectors"
+            )));
+        }
+        Ok(Self {
+            fat_type,
+            reserved_sectors,
+            fats: options.fats.unwrap_or(DEFAULT_FATS),
+            root_entries: options.root_entries.unwrap_or(DEFAULT_ROOT_ENTRIES),
+        })
+    }
+
+    fn extra_reserved_sectors(&self) -> u64 {
+        u64::from(self.reserved_sectors - default_reserved_sectors(self.fat_type))
+    }
+
+    fn geometry(&self, bytes_per_cluster: u32, total_sectors: u32) -> Geometry {
+        Geometry {
+            fat_type: self.fat_type,
+            bytes_per_cluster,
+            total_sectors,
+            reserved_sectors: self.reserved_sectors,
+            fats: self.fats,
+            root_entries: self.root_entries,
+        }
+    }
+
+    /// Data clusters in a volume of `total_sectors`, or `None` if
+    /// `fatfs` would not format it as `self.fat_type`.
+    fn clusters(&self, total_sectors: u64, sectors_per_cluster: u32) -> Option<u64> {
+        // fatfs only sees the volume after the extra reserved sectors
+        let fatfs_sectors = total_sectors.checked_sub(self.extra_reserved_sectors())?;
+        let fatfs_sectors = u32::try_from(fatfs_sectors).ok()?;
+        if resolve_fat_type(fatfs_sectors, sectors_per_cluster, self)? != self.fat_type {
+            return None;
+        }
+        Some(data_clusters(
+            fatfs_sectors,
+            sectors_per_cluster,
+            self.fat_type,
+            self,
+        ))
+    }
+}
+
+/// Fits the content into a volume of exactly `total_sectors`.
+fn fit_fixed(
+    content: &Content,
+    params: &Params,
+    bytes_per_cluster: u32,
+    total_sectors: u64,
+) -> Option<Geometry> {
+    let sectors_per_cluster = bytes_per_cluster / BYTES_PER_SECTOR;
+    let clusters = params.clusters(total_sectors, sectors_per_cluster)?;
+    if clusters < content.clusters(params.fat_type, bytes_per_cluster) {
+        return None;
+    }
+    Some(params.geometry(bytes_per_cluster, total_sectors as u32))
+}
+
+/// Finds the smallest volume holding the content plus `slack` bytes.
+fn fit_auto(
+    content: &Content,
+    params: &Params,
+    bytes_per_cluster: u32,
+    slack: u64,
+) -> Option<Geometry> {
+    let (min_clusters, max_clusters) = cluster_limits(params.fat_type);
+    let needed = content.clusters(params.fat_type, bytes_per_cluster)
+        + slack.div_ceil(u64::from(bytes_per_cluster));
+    if needed > u64::from(max_clusters) {
+        return None;
+    }
+    let clusters = needed.max(u64::from(min_clusters));
+
+    let sectors_per_cluster = bytes_per_cluster / BYTES_PER_SECTOR;
+    let overhead = u64::from(params.reserved_sectors) + root_dir_sectors(params.fat_type, params);
+    let mut total_sectors = overhead + clusters * u64::from(sectors_per_cluster);
+    loop {
+        u32::try_from(total_sectors).ok()?;
+        let fatfs_sectors = (total_sectors - params.extra_reserved_sectors()) as u32;
+        let actual = data_clusters(fatfs_sectors, sectors_per_cluster, params.fat_type, params);
+        if actual >= clusters {
+            return params
+                .clusters(total_sectors, sectors_per_cluster)
+                .map(|_| params.geometry(bytes_per_cluster, total_sectors as u32));
+        }
+        total_sectors += (clusters - actual) * u64::from(sectors_per_cluster);
+    }
+}
+
+/// Reserved sectors `fatfs` always lays out for `fat_type`.
+fn default_reserved_sectors(fat_type: FatType) -> u16 {
+    match fat_type {
+        FatType::Fat32 => 8,
+        FatType::Fat12 | FatType::Fat16 => 1,
+    }
+}
+
+/// Smallest and largest cluster count allowed for `fat_type`.
+fn cluster_limits(fat_type: FatType) -> (u32, u32) {
+    match fat_type {
+        FatType::Fat12 => (FAT12_MIN_CLUSTERS, FAT16_MIN_CLUSTERS - 1),
+        FatType::Fat16 => (FAT16_MIN_CLUSTERS, FAT32_MIN_CLUSTERS - 1),
+        FatType::Fat32 => (FAT32_MIN_CLUSTERS, FAT32_MAX_CLUSTERS),
+    }
+}
+
+fn root_dir_sectors(fat_type: FatType, params: &Params) -> u64 {
+    match fat_type {
+        FatType::Fat32 => 0,
+        FatType::Fat12 | FatType::Fat16 => {
+            (u64::from(params.root_entries) * DIR_ENTRY_SIZE).div_ceil(u64::from(BYTES_PER_SECTOR))
+        }
+    }
+}
+
+/// Mirrors the FAT type selection in `fatfs::format_volume`, which
+/// tries FAT32 first and settles on the first type whose cluster
+/// count is in range.
+fn resolve_fat_type(
+    fatfs_sectors: u32,
+    sectors_per_cluster: u32,
+    params: &Params,
+) -> Option<FatType> {
+    [FatType::Fat32, FatType::Fat16, FatType::Fat12]
+        .into_iter()
+        .find(|&fat_type| {
+            let (min_clusters, max_clusters) = cluster_limits(fat_type);
+            let clusters = data_clusters(fatfs_sectors, sectors_per_cluster, fat_type, params);
+            (u64::from(min_clusters)..=u64::from(max_clusters)).contains(&clusters)
+        })
+}
+
+/// Data clusters `fatfs` ends up with when formatting
+/// `fatfs_sectors` as `fat_type`, computed with the same FAT size
+/// formula it uses.
+fn data_clusters(
+    fatfs_sectors: u32,
+    sectors_per_cluster: u32,
+    fat_type: FatType,
+    params: &Params,
+) -> u64 {
+    let overhead =
+        u64::from(default_reserved_sectors(fat_type)) + root_dir_sectors(fat_type, params);
+    if u64::from(fatfs_sectors) <= overhead + 8 {
+        return 0;
+    }
+    let bits_per_fat_entry = match fat_type {
+        FatType::Fat12 => 12,
+        FatType::Fat16 => 16,
+        FatType::Fat32 => 32,
+    };
+    let fats = u64::from(params.fats);
+    let t0 = u64::from(fatfs_sectors) - overhead;
+    let t1 = t0 + 2 * u64::from(sectors_per_cluster);
+    let bits_per_cluster = u64::from(sectors_per_cluster * BYTES_PER_SECTOR * 8);
+    let t2 = bits_per_cluster / bits_per_fat_entry + fats;
+    let sectors_per_fat = t1.div_ceil(t2);
+    t0.saturating_sub(fats * sectors_per_fat) / u64::from(sectors_per_cluster)
+}
+
+/// FAT type `fatfs` would assume for a volume of `total_bytes`.
+fn estimate_fat_type(total_bytes: u64) -> FatType {
+    const MB: u64 = 1024 * 1024;
+    if total_bytes < 4 * MB {
+        FatType::Fat12
+    } else if total_bytes < 512 * MB {
+        FatType::Fat16
+    } else {
+        FatType::Fat32
+    }
+}
+
+/// Cluster sizes to try, starting with the one `fatfs` would pick for
+/// a volume of roughly `total_bytes`, then larger ones, then smaller
+/// ones.
+fn auto_cluster_sizes(fat_type: FatType, total_bytes: u64) -> Vec<u32> {
+    const KB: u64 = 1024;
+    const MB: u64 = 1024 * KB;
+    const GB: u64 = 1024 * MB;
+    let default = match fat_type {
+        FatType::Fat12 => total_bytes.next_power_of_two() / MB * 512,
+        FatType::Fat16 if total_bytes <= 16 * MB => KB,
+        FatType::Fat16 if total_bytes <= 128 * MB => 2 * KB,
+        FatType::Fat16 => total_bytes.next_power_of_two() / (64 * MB) * KB,
+        FatType::Fat32 if total_bytes <= 260 * MB => 512,
+        FatType::Fat32 if total_bytes <= 8 * GB => 4 * KB,
+        FatType::Fat32 => total_bytes.next_power_of_two() / (2 * GB) * KB,
+    };
+    let default = default.clamp(
+        u64::from(BYTES_PER_SECTOR),
+        u64::from(MAX_AUTO_BYTES_PER_CLUSTER),
+    ) as u32;
+    let all = (BYTES_PER_SECTOR.trailing_zeros()..=MAX_AUTO_BYTES_PER_CLUSTER.trailing_zeros())
+        .map(|shift| 1 << shift);
+    all.clone()
+        .filter(|&size| size >= default)
+        .chain(all.filter(|&size| size < default).rev())
+        .collect()
+}
+
+/// Directory tree and file sizes implied by a set of mappings.
+struct Content {
+    /// Entries per directory, keyed by directory path in the image.
+    /// The root directory has the empty key.
+    dirs: BTreeMap<String, Vec<String>>,
+    /// File sizes in bytes, keyed by path in the image.
+    files: BTreeMap<String, u64>,
+}
+
+impl Content {
+    fn scan(file_mappings: &[FileMapping]) -> MkimgRes<Self> {
+        let mut dirs: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        let mut files = BTreeMap::new();
+        dirs.insert(String::new(), Vec::new());
+        for FileMapping { src, int, .. } in file_mappings {
+            let parts: Vec<_> = path_to_str_with_context(int)?
+                .split('/')
+                .filter(|part| !part.is_empty())
+                .collect();
+            // Directories are created with all of their parents, files
+            // below their parents
+            let (parents, name) = match src.is_dir() {
+                true => (&parts[..], None),
+                false => match parts.split_last() {
+                    Some((name, parents)) => (parents, Some(name)),
+                    None => continue,
+                },
+            };
+            let mut dir = String::new();
+            for part in parents {
+                let child = join(&dir, part);
+                if !dirs.contains_key(&child) {
+                    dirs.entry(dir).or_default().push(part.to_string());
+                    dirs.insert(child.clone(), Vec::new());
+                }
+                dir = child;
+            }
+            if let Some(name) = name {
+                let path = join(&dir, name);
+                if !files.contains_key(&path) {
+                    dirs.entry(dir).or_default().push(name.to_string());
+                }
+                files.insert(path, src.size()?);
+            }
+        }
+        Ok(Self { dirs, files })
+    }
+
+    /// Total size of file contents in bytes.
+    fn bytes(&self) -> u64 {
+        self.files.values().sum()
+    }
+
+    /// Directory entries needed by the root directory.
+    fn root_entries(&self) -> u64 {
+        self.dirs.get("").map_or(0, |names| dir_entries(names))
+    }
+
+    /// Data clusters needed for all files and directories. The root
+    /// directory is only counted for FAT32, where it lives in the
+    /// data region.
+    fn clusters(&self, fat_type: FatType, bytes_per_cluster: u32) -> u64 {
+        let bytes_per_cluster = u64::from(bytes_per_cluster);
+        let files: u64 = self
+            .files
+            .values()
+            .map(|len| len.div_ceil(bytes_per_cluster))
+            .sum();
+        let dirs: u64 = self
+            .dirs
+            .iter()
+            .filter(|(path, _)| !path.is_empty() || fat_type == FatType::Fat32)
+            .map(|(path, names)| {
+                // Subdirectories also hold "." and ".." entries
+                let dots = if path.is_empty() { 0 } else { 2 };
+                let bytes = (dir_entries(names) + dots) * DIR_ENTRY_SIZE;
+                bytes.div_ceil(bytes_per_cluster).max(1)
+            })
+            .sum();
+        files + dirs
+    }
+}
+
+fn join(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_string()
+    } else {
+        format!("{dir}/{name}")
+    }
+}
+
+/// Directory entries used by `names`. `fatfs` writes a long file name
+/// sequence in front of every short entry, even when the name would
+/// fit in 8.3 form.
+fn dir_entries(names: &[String]) -> u64 {
+    names
+        .iter()
+        .map(|name| {
+            let utf16_len = name.encode_utf16().count() as u64;
+            1 + utf16_len.div_ceil(LFN_CHARS_PER_ENTRY)
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Source;
+    use fatfs::{FileSystem, FormatVolumeOptions, FsOptions};
+    use std::io::{self, Cursor, Read};
+
+    const CLUSTER: u64 = 512;
+
+    fn mappings(files: &[(&str, u64)]) -> Vec<FileMapping> {
+        files
+            .iter()
+            .map(|&(path, len)| {
+                let src = Source::Fill {
+                    len,
+                    pattern: Vec::new(),
+                };
+                FileMapping::new(src, path)
+            })
+            .collect()
+    }
+
+    // Formats `geometry` with fatfs, writes `files` to it and returns
+    // the free bytes left
+    fn format_and_fill(geometry: &Geometry, files: &[(&str, u64)]) -> u64 {
+        assert_eq!(
+            geometry.reserved_sectors,
+            default_reserved_sectors(geometry.fat_type)
+        );
+        let mut disk = Cursor::new(vec![0u8; geometry.total_bytes() as usize]);
+        fatfs::format_volume(
+            &mut disk,
+            FormatVolumeOptions::new()
+                .fat_type(geometry.fat_type)
+                .bytes_per_cluster(geometry.bytes_per_cluster)
+                .total_sectors(geometry.total_sectors)
+                .fats(geometry.fats)
+                .max_root_dir_entries(geometry.root_entries),
+        )
+        .unwrap();
+        let fs = FileSystem::new(&mut disk, FsOptions::new()).unwrap();
+        assert_eq!(fs.fat_type(), geometry.fat_type);
+        for &(path, len) in files {
+            let mut dir = fs.root_dir();
+            let (parents, name) = path.rsplit_once('/').unwrap_or(("", path));
+            for part in parents.split('/').filter(|part| !part.is_empty()) {
+                dir = dir.create_dir(part).unwrap();
+            }
+            let mut file = dir.create_file(name).unwrap();
+            io::copy(&mut io::repeat(0).take(len), &mut file).unwrap();
+        }
+        let stats = fs.stats().unwrap();
+        u64::from(stats.free_clusters()) * u64::from(stats.cluster_size())
+    }
+
+    fn fit(files: &[(&str, u64)], options: &CreateOptions) -> MkimgRes<Geometry> {
+        compute_geometry(&mappings(files), options)
+    }
+
+    fn options(fat_type: Option<FatType>, slack: u64) -> CreateOptions {
+        CreateOptions {
+            fat_type,
+            bytes_per_cluster: Some(CLUSTER as u32),
+            slack,
+            ..CreateOptions::default()
+        }
+    }
+
+    #[test]
+    fn parse_size_suffixes() {
+        let cases: [(&str, u64); 8] = [
+            ("0", 0),
+            ("512", 512),
+            ("1k", 1 << 10),
+            ("1440K", 1440 << 10),
+            ("64m", 64 << 20),
+            ("64M", 64 << 20),
+            ("2g", 2 << 30),
+            ("2G", 2 << 30),
+        ];
+        for (text, bytes) in cases {
+            assert_eq!(parse_size(text).unwrap(), bytes, "{text}");
+        }
+    }
+
+    #[test]
+    fn parse_size_rejects_invalid_sizes() {
+        for text in ["", "K", "1T", "-1", "1.5M", " 1K", "18446744073709551615K"] {
+            assert!(parse_size(text).is_err(), "{text}");
+        }
+    }
+
+    #[test]
+    fn auto_geometry_holds_content_and_slack() {
+        let cases: [&[(&str, u64)]; 4] = [
+            &[],
+            &[("a.bin", 1)],
+            &[("a.bin", 100_000), ("d/b.bin", 5000), ("d/e/c.bin", 0)],
+            &[("Long Name File Number One.txt", 3 << 20)],
+        ];
+        for files in cases {
+            let geometry = fit(files, &CreateOptions::default()).unwrap();
+            assert!(
+                format_and_fill(&geometry, files) >= DEFAULT_SLACK,
+                "{files:?}: {geometry:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn auto_geometry_is_tight() {
+        let files = [("a.bin", 100 * CLUSTER)];
+        let geometry = fit(&files, &options(Some(FatType::Fat16), 0)).unwrap();
+        // Padding up to the FAT16 minimum is all that is left free
+        let free = format_and_fill(&geometry, &files);
+        assert!(free < (u64::from(FAT16_MIN_CLUSTERS) - 100 + 8) * CLUSTER);
+    }
+
+    #[test]
+    fn default_slack() {
+        assert_eq!(CreateOptions::default().slack, DEFAULT_SLACK);
+        // Enough content that the volume is not padded to the FAT16
+        // minimum either way
+        let files = [("a.bin", 5000 * CLUSTER)];
+        let without = fit(&files, &options(Some(FatType::Fat16), 0)).unwrap();
+        let with = fit(&files, &options(Some(FatType::Fat16), DEFAULT_SLACK)).unwrap();
+        assert!(with.total_bytes() >= without.total_bytes() + DEFAULT_SLACK);
+        assert!(format_and_fill(&with, &files) >= DEFAULT_SLACK);
+    }
+
+    #[test]
+    fn fat_type_cluster_count_thresholds() {
+        let fat12_max = u64::from(FAT16_MIN_CLUSTERS) - 1;
+        let fat16_max = u64::from(FAT32_MIN_CLUSTERS) - 1;
+        // Files in the root directory of a FAT12/FAT16 volume need no
+        // data clusters beyond their own
+        let cases = [
+            (Some(FatType::Fat12), fat12_max, Some(FatType::Fat12)),
+            (Some(FatType::Fat12), fat12_max + 1, None),
+            (None, fat12_max + 1, Some(FatType::Fat16)),
+            (Some(FatType::Fat16), fat16_max, Some(FatType::Fat16)),
+            (Some(FatType::Fat16), fat16_max + 1, None),
+            (None, fat16_max + 1, Some(FatType::Fat32)),
+        ];
+        for (requested, clusters, expected) in cases {
+            let files = [("a.bin", clusters * CLUSTER)];
+            let geometry = fit(&files, &options(requested, 0));
+            match expected {
+                Some(fat_type) => {
+                    let geometry = geometry.unwrap();
+                    assert_eq!(geometry.fat_type, fat_type, "{requested:?} {clusters}");
+                    format_and_fill(&geometry, &files);
+                }
+                None => assert!(geometry.is_err(), "{requested:?} {clusters}"),
+            }
+        }
+    }
+
+    #[test]
+    fn fixed_size_geometry() {
+        let size = Some(VolumeSize::Bytes(1440 << 10));
+        let options = CreateOptions {
+            size,
+            ..CreateOptions::default()
+        };
+        let files = [("a.bin", 1 << 20)];
+        let geometry = fit(&files, &options).unwrap();
+        assert_eq!(geometry.fat_type, FatType::Fat12);
+        assert_eq!(geometry.total_sectors, 2880);
+        format_and_fill(&geometry, &files);
+
+        assert!(fit(&[("a.bin", 2 << 20)], &options).is_err());
+    }
+}
@@ -0,0 +1,180 @@
+//! Contents of mapped image files.
+
+use crate::error::{MkimgError, MkimgRes};
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+/// Contents of a mapped image entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    /// File or directory in the external filesystem. A directory is
+    /// created empty; its contents need mappings of their own.
+    Path(PathBuf),
+    /// File contents held in memory. An empty vector gives a
+    /// zero-length file.
+    Inline(Vec<u8>),
+    /// `len` bytes repeating `pattern`, or zeros if `pattern` is
+    /// empty.
+    Fill { len: u64, pattern: Vec<u8> },
+    /// `len` pseudo-random bytes. The same `seed` always produces the
+    /// same bytes.
+    Random { len: u64, seed: u64 },
+    /// An empty directory.
+    Directory,
+}
+
+impl Source {
+    /// External path of the source, if it has one.
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Source::Path(path) => Some(path),
+            _ => None,
+        }
+    }
+
+    /// Whether the source is a directory.
+    pub fn is_dir(&self) -> bool {
+        match self {
+            Source::Path(path) => path.is_dir(),
+            Source::Directory => true,
+            Source::Inline(_) | Source::Fill { .. } | Source::Random { .. } => false,
+        }
+    }
+
+    /// Size of the file contents in bytes, 0 for directories.
+    ///
+    /// # Errors
+    ///
+    /// Returns error if an external file cannot be inspected
+    pub fn size(&self) -> MkimgRes<u64> {
+        match self {
+            Source::Path(path) if path.is_dir() => Ok(0),
+            Source::Path(path) => Ok(std::fs::metadata(path)
+                .map_err(|e| MkimgError::path_operation("read metadata", path, e))?
+                .len()),
+            Source::Inline(data) => Ok(data.len() as u64),
+            Source::Fill { len, .. } | Source::Random { len, .. } => Ok(*len),
+            Source::Directory => Ok(0),
+        }
+    }
+
+    /// Opens the file contents for streaming. Generated contents are
+    /// produced as they are read rather than held in memory.
+    pub(crate) fn open(&self) -> MkimgRes<Box<dyn Read + '_>> {
+        match self {
+            Source::Path(path) => {
+                Ok(Box::new(File::open(path).map_err(|e| {
+                    MkimgError::path_operation("open", path, e)
+                })?))
+            }
+            Source::Inline(data) => Ok(Box::new(data.as_slice())),
+            Source::Fill { len, pattern } if pattern.is_empty() => {
+                Ok(Box::new(io::repeat(0).take(*len)))
+            }
+            Source::Fill { len, pattern } => {
+                Ok(Box::new(PatternReader { pattern, offset: 0 }.take(*len)))
+            }
+            Source::Random { len, seed } => Ok(Box::new(
+                RandomReader {
+                    rng: SplitMix64(*seed),
+                    block: [0; 8],
+                    offset: 8,
+                }
+                .take(*len),
+            )),
+            Source::Directory => Ok(Box::new(io::empty())),
+        }
+    }
+}
+
+/// Endless repetitions of a non-empty pattern.
+struct PatternReader<'a> {
+    pattern: &'a [u8],
+    offset: usize,
+}
+
+impl Read for PatternReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        for byte in buf.iter_mut() {
+            *byte = self.pattern[self.offset];
+            self.offset = (self.offset + 1) % self.pattern.len();
+        }
+        Ok(buf.len())
+    }
+}
+
+/// Endless pseudo-random bytes: the generator's outputs in little
+/// endian order.
+struct RandomReader {
+    rng: SplitMix64,
+    block: [u8; 8],
+    offset: usize,
+}
+
+impl Read for RandomReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        for byte in buf.iter_mut() {
+            if self.offset == self.block.len() {
+                self.block = self.rng.next().to_le_bytes();
+                self.offset = 0;
+            }
+            *byte = self.block[self.offset];
+            self.offset += 1;
+        }
+        Ok(buf.len())
+    }
+}
+
+/// SplitMix64, a small and fast generator whose output is fully
+/// determined by its seed. Good enough for filler data, not for
+/// anything that needs to be unpredictable.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+impl From<PathBuf> for Source {
+    fn from(path: PathBuf) -> Self {
+        Source::Path(path)
+    }
+}
+
+impl From<&PathBuf> for Source {
+    fn from(path: &PathBuf) -> Self {
+        Source::Path(path.clone())
+    }
+}
+
+impl From<&Path> for Source {
+    fn from(path: &Path) -> Self {
+        Source::Path(path.to_path_buf())
+    }
+}
+
+impl From<Vec<u8>> for Source {
+    fn from(data: Vec<u8>) -> Self {
+        Source::Inline(data)
+    }
+}
+
+impl From<&[u8]> for Source {
+    fn from(data: &[u8]) -> Self {
+        Source::Inline(data.to_vec())
+    }
+}
+
+impl<const N: usize> From<&[u8; N]> for Source {
+    fn from(data: &[u8; N]) -> Self {
+        Source::Inline(data.to_vec())
+    }
+}